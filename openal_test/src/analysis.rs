@@ -0,0 +1,410 @@
+// Measuring sound, so synthesis can be checked without listening.
+//
+// Spectrum takes a Blackman-Harris windowed FFT of a block. The window's side lobes are
+// over 90dB down, so a tone's energy stays within a few bins of it, and what's left
+// elsewhere (noise, aliases) can be measured too. Magnitudes are scaled so a sine of
+// amplitude 1 peaks at 1.
+//
+// pitch() uses YIN (de Cheveigné & Kawahara 2002: YIN, a fundamental frequency
+// estimator for speech and music), which is far less prone than plain autocorrelation to
+// picking an octave out.
+
+use std::f64::consts::PI;
+
+use crate::sink::{Sink, SinkError};
+
+/// In place radix-2 FFT; the length must be a power of two.
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n, "fft of {} and {} samples", n, im.len());
+
+    // Bit reversed order.
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f64).sin_cos();
+                let (w_re, w_im) = (w_re as f32, w_im as f32);
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|x| (*x as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt() as f32
+}
+
+pub fn peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0f32, |m, x| m.max(x.abs()))
+}
+
+/// Decibels relative to 1; silence is -infinity.
+pub fn db(level: f32) -> f32 {
+    20.0 * level.log10()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spectrum {
+    pub sample_rate: u32,
+    pub magnitudes: Vec<f32>, // 0Hz up to just below Nyquist.
+    // Bins either side of a sine that its energy spreads over.
+    lobe: usize,
+}
+
+impl Spectrum {
+    /// The spectrum of a block of mono samples, zero padded to a power of two.
+    pub fn new(samples: &[f32], sample_rate: u32) -> Spectrum {
+        let len = samples.len().max(1);
+        let size = len.next_power_of_two().max(2);
+        let mut re = vec![0.0; size];
+        let mut im = vec![0.0; size];
+        let mut window_sum = 0.0;
+        for (i, x) in samples.iter().enumerate() {
+            // 4-term Blackman-Harris.
+            let t = 2.0 * PI * i as f64 / len as f64;
+            let w = 0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos();
+            re[i] = x * w as f32;
+            window_sum += w;
+        }
+        fft(&mut re, &mut im);
+        let scale = 2.0 / window_sum.max(1e-9) as f32;
+        let magnitudes = (0..size / 2).map(|i| (re[i] * re[i] + im[i] * im[i]).sqrt() * scale).collect();
+        // The main lobe is 4 bins each side, before padding.
+        let lobe = (4 * size + len - 1) / len + 1;
+        Spectrum { sample_rate, magnitudes, lobe }
+    }
+
+    pub fn bin_width(&self) -> f32 {
+        self.sample_rate as f32 / (self.magnitudes.len() * 2) as f32
+    }
+
+    fn bin(&self, frequency: f32) -> usize {
+        ((frequency / self.bin_width()).round().max(0.0) as usize).min(self.magnitudes.len() - 1)
+    }
+
+    /// The strongest frequency and its amplitude, interpolated between bins.
+    pub fn peak(&self) -> (f32, f32) {
+        let mut i = 0;
+        for (j, m) in self.magnitudes.iter().enumerate() {
+            if *m > self.magnitudes[i] {
+                i = j;
+            }
+        }
+        self.refine(i)
+    }
+
+    // Fit a parabola through a peak bin and its neighbours, in dB.
+    fn refine(&self, i: usize) -> (f32, f32) {
+        let m = &self.magnitudes;
+        if i == 0 || i + 1 >= m.len() || m[i] <= 0.0 {
+            return (i as f32 * self.bin_width(), m[i]);
+        }
+        let (a, b, c) = (db(m[i - 1].max(1e-20)), db(m[i]), db(m[i + 1].max(1e-20)));
+        let offset = 0.5 * (a - c) / (a - 2.0 * b + c);
+        let level = b - 0.25 * (a - c) * offset;
+        ((i as f32 + offset) * self.bin_width(), 10f32.powf(level / 20.0))
+    }
+
+    /// The amplitude of the strongest component within a few bins of `frequency`.
+    pub fn magnitude_at(&self, frequency: f32) -> f32 {
+        let centre = self.bin(frequency);
+        let lo = centre.saturating_sub(self.lobe);
+        let hi = (centre + self.lobe).min(self.magnitudes.len() - 1);
+        let i = (lo..=hi).fold(lo, |best, j| if self.magnitudes[j] > self.magnitudes[best] { j } else { best });
+        self.refine(i).1
+    }
+
+    // Summed power of the bins from `lo` to `hi`, inclusive.
+    fn power_bins(&self, lo: usize, hi: usize) -> f64 {
+        let hi = hi.min(self.magnitudes.len() - 1);
+        self.magnitudes.get(lo..=hi).map_or(0.0, |m| m.iter().map(|x| (*x as f64).powi(2)).sum())
+    }
+
+    /// Power between two frequencies, relative to the other bands; useful as a ratio.
+    pub fn power(&self, from: f32, to: f32) -> f64 {
+        self.power_bins(self.bin(from), self.bin(to))
+    }
+
+    // Whether harmonics of `fundamental` fall in separate bins; false for NaN too.
+    fn resolves(&self, fundamental: f32) -> bool {
+        fundamental >= self.bin_width() && fundamental.is_finite()
+    }
+
+    /// Power of the `harmonic`th multiple of `fundamental`, 1 being the fundamental.
+    fn harmonic_power(&self, fundamental: f32, harmonic: usize) -> f64 {
+        let centre = self.bin(fundamental * harmonic as f32);
+        self.power_bins(centre.saturating_sub(self.lobe), centre + self.lobe)
+    }
+
+    /// Total harmonic distortion: the power of the harmonics up to Nyquist, relative to
+    /// the fundamental, as an amplitude ratio. A pure sine is 0, a square wave 0.48.
+    /// A fundamental below one bin (zero, or a failed pitch estimate) gives 0.
+    pub fn thd(&self, fundamental: f32) -> f32 {
+        if !self.resolves(fundamental) {
+            return 0.0;
+        }
+        let nyquist = self.sample_rate as f32 / 2.0;
+        let first = self.harmonic_power(fundamental, 1);
+        let harmonics: f64 = (2..).take_while(|h| fundamental * *h as f32 + self.bin_width() * self.lobe as f32 <= nyquist)
+            .map(|h| self.harmonic_power(fundamental, h))
+            .sum();
+        (harmonics / first.max(1e-30)).sqrt() as f32
+    }
+
+    /// Power that isn't at a harmonic of `fundamental` (aliases, noise, hum) relative to
+    /// all the power, as an amplitude ratio. Bins below the fundamental are left out.
+    /// A fundamental below one bin gives 0.
+    pub fn inharmonic(&self, fundamental: f32) -> f32 {
+        if !self.resolves(fundamental) {
+            return 0.0;
+        }
+        let start = self.bin(fundamental).saturating_sub(self.lobe);
+        let total = self.power_bins(start, self.magnitudes.len());
+        let mut harmonic = 0.0;
+        let mut h = 1;
+        while self.bin(fundamental * h as f32) < self.magnitudes.len() - 1 {
+            harmonic += self.harmonic_power(fundamental, h);
+            h += 1;
+        }
+        ((total - harmonic).max(0.0) / total.max(1e-30)).sqrt() as f32
+    }
+}
+
+// The lowest pitch looked for, which sets how much YIN reads.
+const MIN_PITCH: f32 = 40.0;
+const YIN_THRESHOLD: f32 = 0.1;
+
+/// The fundamental frequency of mono samples, or None if they're not clearly pitched
+/// (noise, silence, or too short). Needs two cycles of the lowest pitch, 40Hz.
+pub fn pitch(samples: &[f32], sample_rate: u32) -> Option<f32> {
+    let max_lag = ((sample_rate as f32 / MIN_PITCH) as usize).min(samples.len() / 2);
+    if max_lag < 3 {
+        return None;
+    }
+    let window = max_lag;
+
+    // The cumulative mean normalised difference for each lag.
+    let mut d = vec![1.0f32; max_lag + 1];
+    let mut sum = 0.0;
+    for lag in 1..=max_lag {
+        let diff: f64 = (0..window).map(|j| (samples[j] as f64 - samples[j + lag] as f64).powi(2)).sum();
+        sum += diff;
+        d[lag] = if sum > 0.0 { (diff * lag as f64 / sum) as f32 } else { 1.0 };
+    }
+
+    // The first dip under the threshold, followed down to its bottom.
+    let mut lag = (2..max_lag).find(|l| d[*l] < YIN_THRESHOLD)?;
+    while lag + 1 < max_lag && d[lag + 1] < d[lag] {
+        lag += 1;
+    }
+    let (a, b, c) = (d[lag - 1], d[lag], d[lag + 1]);
+    let denominator = a - 2.0 * b + c;
+    let offset = if denominator.abs() > 1e-12 { 0.5 * (a - c) / denominator } else { 0.0 };
+    Some(sample_rate as f32 / (lag as f32 + offset))
+}
+
+/// RMS and peak levels of everything written, per channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Meter {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub frames: usize,
+    squares: Vec<f64>,
+    peaks: Vec<f32>,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32, channels: usize) -> Meter {
+        Meter { sample_rate, channels, frames: 0, squares: vec![0.0; channels], peaks: vec![0.0; channels] }
+    }
+
+    pub fn rms(&self, channel: usize) -> f32 {
+        (self.squares[channel] / self.frames.max(1) as f64).sqrt() as f32
+    }
+
+    pub fn peak(&self, channel: usize) -> f32 {
+        self.peaks[channel]
+    }
+
+    pub fn reset(&mut self) {
+        *self = Meter::new(self.sample_rate, self.channels);
+    }
+}
+
+impl Sink for Meter {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), SinkError> {
+        for frame in samples.chunks(self.channels) {
+            for (channel, x) in frame.iter().enumerate() {
+                self.squares[channel] += (*x as f64).powi(2);
+                self.peaks[channel] = self.peaks[channel].max(x.abs());
+            }
+        }
+        self.frames += samples.len() / self.channels;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscillator::{Oscillator, Waveform};
+    use crate::stream::Generator;
+
+    fn tone(waveform: Waveform, frequency: f32, samples: usize) -> Vec<f32> {
+        Oscillator::new(waveform, frequency, 44100).take(samples).collect()
+    }
+
+    #[test]
+    fn test_fft() {
+        // Against a plain DFT.
+        let input: Vec<f32> = (0..16).map(|i| ((i * 7) % 5) as f32 - 2.0).collect();
+        let (mut re, mut im) = (input.clone(), vec![0.0; 16]);
+        fft(&mut re, &mut im);
+        for k in 0..16 {
+            let (mut dft_re, mut dft_im) = (0.0, 0.0);
+            for (i, x) in input.iter().enumerate() {
+                let angle = -2.0 * PI * (k * i) as f64 / 16.0;
+                dft_re += *x as f64 * angle.cos();
+                dft_im += *x as f64 * angle.sin();
+            }
+            assert!((re[k] as f64 - dft_re).abs() < 1e-4 && (im[k] as f64 - dft_im).abs() < 1e-4);
+        }
+
+        let spectrum = Spectrum::new(&tone(Waveform::Sine, 1000.0, 8192), 44100);
+        let (frequency, level) = spectrum.peak();
+        assert!((frequency - 1000.0).abs() < 0.5, "{}", frequency);
+        assert!((level - 1.0).abs() < 0.01, "{}", level);
+        assert!(spectrum.magnitude_at(3000.0) < 1e-4);
+    }
+
+    #[test]
+    fn test_meters() {
+        let sine = tone(Waveform::Sine, 441.0, 44100);
+        assert!((rms(&sine) - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        assert!((peak(&sine) - 1.0).abs() < 1e-3);
+        assert_eq!(db(1.0), 0.0);
+        assert!((db(0.5) + 6.02).abs() < 0.01);
+
+        // A half level sine on the left, silence on the right.
+        let mut osc = Oscillator::sine(441.0, 44100);
+        osc.set_amplitude(0.5);
+        let mut meter = Meter::new(44100, 2);
+        for _ in 0..100 {
+            let block: Vec<f32> = (0..441).flat_map(|_| vec![osc.next_sample(), 0.0]).collect();
+            meter.write(&block).unwrap();
+        }
+        assert_eq!(meter.frames, 44100);
+        assert!((meter.rms(0) - 0.3536).abs() < 1e-3);
+        assert!((meter.peak(0) - 0.5).abs() < 1e-3);
+        assert_eq!((meter.rms(1), meter.peak(1)), (0.0, 0.0));
+        meter.reset();
+        assert_eq!(meter.frames, 0);
+    }
+
+    #[test]
+    fn test_pitch() {
+        for (waveform, frequency) in [(Waveform::Sine, 440.0), (Waveform::Saw, 110.0),
+                                      (Waveform::Square { pulse_width: 0.5 }, 1000.0),
+                                      (Waveform::Triangle, 55.0)].iter() {
+            let found = pitch(&tone(*waveform, *frequency, 8192), 44100).unwrap();
+            assert!((found - frequency).abs() < frequency * 0.002, "{:?}: {}", waveform, found);
+        }
+        assert_eq!(pitch(&tone(Waveform::WhiteNoise, 0.0, 8192), 44100), None);
+        assert_eq!(pitch(&[0.0; 8192], 44100), None);
+        assert_eq!(pitch(&[0.0; 4], 44100), None);
+    }
+
+    #[test]
+    fn test_main_tone() {
+        // The 440Hz note main plays really is at 440Hz, and still is a square wave.
+        let square = Waveform::Square { pulse_width: 0.5 };
+        let mut note = crate::tone(square, 440.0, 0.5);
+        let mut samples = vec![0.0; 22050];
+        note.generate(&mut samples);
+        let samples = &samples[4410..];
+
+        assert!((pitch(samples, 44100).unwrap() - 440.0).abs() < 0.5);
+        let spectrum = Spectrum::new(samples, 44100);
+        let (frequency, level) = spectrum.peak();
+        assert!((frequency - 440.0).abs() < 0.5, "{}", frequency);
+        // A square's fundamental is 4/pi of its amplitude; the note's at 0.5, sustained
+        // at 0.8.
+        assert!((level - 0.5 * 0.8 * 4.0 / std::f32::consts::PI).abs() < 0.01, "{}", level);
+        assert!(spectrum.magnitude_at(880.0) < 1e-3);
+        assert!((spectrum.magnitude_at(1320.0) / level - 1.0 / 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_thd() {
+        let spectrum = |waveform| Spectrum::new(&tone(waveform, 100.0, 16384), 44100);
+        assert!(spectrum(Waveform::Sine).thd(100.0) < 1e-3);
+        // sqrt(pi^2 / 8 - 1) and sqrt(pi^4 / 96 - 1), less what's above Nyquist.
+        let square = spectrum(Waveform::Square { pulse_width: 0.5 }).thd(100.0);
+        assert!((square - 0.483).abs() < 0.01, "{}", square);
+        let triangle = spectrum(Waveform::Triangle).thd(100.0);
+        assert!((triangle - 0.121).abs() < 0.005, "{}", triangle);
+
+        // Clipping adds distortion.
+        let clipped: Vec<f32> = tone(Waveform::Sine, 100.0, 16384).iter().map(|x| (x * 3.0).tanh()).collect();
+        assert!(Spectrum::new(&clipped, 44100).thd(100.0) > 0.1);
+
+        // No fundamental to measure against.
+        let square = spectrum(Waveform::Square { pulse_width: 0.5 });
+        for fundamental in [0.0, -100.0, 1.0, std::f32::NAN, std::f32::INFINITY].iter() {
+            assert_eq!(square.thd(*fundamental), 0.0);
+            assert_eq!(square.inharmonic(*fundamental), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_aliasing() {
+        // A naive square's harmonics above Nyquist fold back between the real ones. PolyBLEP
+        // keeps them 30dB down at 1kHz, and more lower down.
+        let naive: Vec<f32> = tone(Waveform::Sine, 1000.0, 16384).iter()
+            .map(|x| if *x >= 0.0 { 1.0 } else { -1.0 })
+            .collect();
+        let naive = Spectrum::new(&naive, 44100).inharmonic(1000.0);
+
+        for waveform in [Waveform::Square { pulse_width: 0.5 }, Waveform::Saw].iter() {
+            let spectrum = Spectrum::new(&tone(*waveform, 1000.0, 16384), 44100);
+            let aliasing = spectrum.inharmonic(1000.0);
+            assert!(db(aliasing) < -30.0 && aliasing < naive / 4.0, "{:?}: {}dB, naive {}dB",
+                    waveform, db(aliasing), db(naive));
+        }
+        assert!(Spectrum::new(&tone(Waveform::Sine, 1000.0, 16384), 44100).inharmonic(1000.0) < 1e-3);
+    }
+}
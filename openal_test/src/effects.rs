@@ -0,0 +1,671 @@
+// Effects: filters, delay, reverb and distortion.
+//
+// Every effect processes interleaved blocks in place, mono or stereo, keeping separate
+// state for each channel. Effects are chained in order, on a voice in the mixer, on the
+// mixer's master bus, or on any generator with Effected. Their parameters can be changed
+// between blocks; reach them through Chain::get_mut.
+//
+// The filters are biquads, from Robert Bristow-Johnson's Audio EQ Cookbook. The reverb
+// is Jezar's Freeverb: eight damped comb filters in parallel, then four all-passes in
+// series, for each side.
+
+use std::any::Any;
+use std::f32::consts::PI;
+
+use crate::stream::Generator;
+
+pub trait Effect: Send {
+    /// Process interleaved samples in place.
+    fn process(&mut self, samples: &mut [f32], channels: usize);
+
+    /// Forget everything, eg. empty delay lines.
+    fn reset(&mut self) {}
+
+    /// How many frames it can go on sounding after its input stops.
+    fn tail(&self) -> usize {
+        0
+    }
+
+    // So a Chain can hand back the concrete effect.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FilterKind {
+    LowPass,
+    HighPass,
+    // Unity gain at the centre frequency.
+    BandPass,
+    Notch,
+    Peak { gain_db: f32 },
+    LowShelf { gain_db: f32 },
+    HighShelf { gain_db: f32 },
+}
+
+/// A second order IIR filter.
+#[derive(Debug, Clone)]
+pub struct Biquad {
+    sample_rate: u32,
+    kind: FilterKind,
+    frequency: f32,
+    q: f32,
+    b: [f32; 3], // normalised by a0.
+    a: [f32; 2], // a1, a2, normalised by a0.
+    state: [[f32; 2]; 2], // transposed direct form II, per channel.
+}
+
+impl Biquad {
+    /// `q` of 0.707 is maximally flat for the pass and shelving filters.
+    pub fn new(sample_rate: u32, kind: FilterKind, frequency: f32, q: f32) -> Biquad {
+        let mut filter = Biquad { sample_rate, kind, frequency, q, b: [1.0, 0.0, 0.0], a: [0.0, 0.0], state: [[0.0; 2]; 2] };
+        filter.update();
+        filter
+    }
+
+    pub fn kind(&self) -> FilterKind {
+        self.kind
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn q(&self) -> f32 {
+        self.q
+    }
+
+    pub fn set_kind(&mut self, kind: FilterKind) {
+        self.kind = kind;
+        self.update();
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+        self.update();
+    }
+
+    pub fn set_q(&mut self, q: f32) {
+        self.q = q;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let nyquist = self.sample_rate as f32 * 0.5;
+        let w0 = 2.0 * PI * self.frequency.max(1.0).min(nyquist * 0.99) / self.sample_rate as f32;
+        let (sin, cos) = (w0.sin(), w0.cos());
+        let alpha = sin / (2.0 * self.q.max(1e-3));
+
+        let (b, a) = match self.kind {
+            FilterKind::LowPass => ([(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+            FilterKind::HighPass => ([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+            FilterKind::BandPass => ([alpha, 0.0, -alpha], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+            FilterKind::Notch => ([1.0, -2.0 * cos, 1.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+            FilterKind::Peak { gain_db } => {
+                let a = 10f32.powf(gain_db / 40.0);
+                ([1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a], [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a])
+            }
+            FilterKind::LowShelf { gain_db } => {
+                let a = 10f32.powf(gain_db / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+                ([a * ((a + 1.0) - (a - 1.0) * cos + k),
+                  2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                  a * ((a + 1.0) - (a - 1.0) * cos - k)],
+                 [(a + 1.0) + (a - 1.0) * cos + k,
+                  -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                  (a + 1.0) + (a - 1.0) * cos - k])
+            }
+            FilterKind::HighShelf { gain_db } => {
+                let a = 10f32.powf(gain_db / 40.0);
+                let k = 2.0 * a.sqrt() * alpha;
+                ([a * ((a + 1.0) + (a - 1.0) * cos + k),
+                  -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                  a * ((a + 1.0) + (a - 1.0) * cos - k)],
+                 [(a + 1.0) - (a - 1.0) * cos + k,
+                  2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                  (a + 1.0) - (a - 1.0) * cos - k])
+            }
+        };
+        self.b = [b[0] / a[0], b[1] / a[0], b[2] / a[0]];
+        self.a = [a[1] / a[0], a[2] / a[0]];
+    }
+
+    /// The gain at `frequency`.
+    pub fn response(&self, frequency: f32) -> f32 {
+        // |H(e^jw)|, with the numerator and denominator as complex numbers.
+        let w = 2.0 * PI * frequency / self.sample_rate as f32;
+        let (c1, s1, c2, s2) = (w.cos(), w.sin(), (2.0 * w).cos(), (2.0 * w).sin());
+        let num = (self.b[0] + self.b[1] * c1 + self.b[2] * c2, -self.b[1] * s1 - self.b[2] * s2);
+        let den = (1.0 + self.a[0] * c1 + self.a[1] * c2, -self.a[0] * s1 - self.a[1] * s2);
+        ((num.0 * num.0 + num.1 * num.1) / (den.0 * den.0 + den.1 * den.1)).sqrt()
+    }
+
+    fn tick(&mut self, channel: usize, x: f32) -> f32 {
+        let s = &mut self.state[channel];
+        let y = self.b[0] * x + s[0];
+        s[0] = self.b[1] * x - self.a[0] * y + s[1];
+        s[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    /// Process with the frequency changing every frame, eg. following an envelope.
+    pub fn process_with_frequency(&mut self, samples: &mut [f32], channels: usize, frequency: &[f32]) {
+        for (frame, f) in samples.chunks_mut(channels).zip(frequency) {
+            if *f != self.frequency {
+                self.set_frequency(*f);
+            }
+            for (channel, x) in frame.iter_mut().enumerate() {
+                *x = self.tick(channel, *x);
+            }
+        }
+    }
+}
+
+impl Effect for Biquad {
+    fn process(&mut self, samples: &mut [f32], channels: usize) {
+        for frame in samples.chunks_mut(channels) {
+            for (channel, x) in frame.iter_mut().enumerate() {
+                *x = self.tick(channel, *x);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = [[0.0; 2]; 2];
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Frames for -60dB, going round a loop of `length` frames losing `feedback` each time.
+fn decay_frames(length: usize, feedback: f32) -> usize {
+    if feedback <= 0.0 {
+        length
+    } else {
+        length + (length as f32 * (0.001f32).ln() / feedback.min(0.999).ln()) as usize
+    }
+}
+
+/// An echo: the input delayed, fed back into itself.
+#[derive(Debug, Clone)]
+pub struct Delay {
+    sample_rate: u32,
+    lines: [Vec<f32>; 2],
+    position: usize,
+    time: usize, // frames.
+    pub feedback: f32,
+    pub mix: f32, // 0 dry .. 1 wet.
+}
+
+impl Delay {
+    /// The time can be changed up to `max_time` seconds.
+    pub fn new(sample_rate: u32, max_time: f32, time: f32, feedback: f32, mix: f32) -> Delay {
+        let length = ((max_time * sample_rate as f32) as usize).max(1);
+        let mut delay = Delay { sample_rate, lines: [vec![0.0; length], vec![0.0; length]], position: 0, time: 1, feedback, mix };
+        delay.set_time(time);
+        delay
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time as f32 / self.sample_rate as f32
+    }
+
+    pub fn set_time(&mut self, seconds: f32) {
+        self.time = ((seconds * self.sample_rate as f32).round() as usize).max(1).min(self.lines[0].len());
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, samples: &mut [f32], channels: usize) {
+        let length = self.lines[0].len();
+        for frame in samples.chunks_mut(channels) {
+            let read = (self.position + length - self.time) % length;
+            for (channel, x) in frame.iter_mut().enumerate() {
+                let line = &mut self.lines[channel];
+                let delayed = line[read];
+                line[self.position] = *x + delayed * self.feedback;
+                *x = *x * (1.0 - self.mix) + delayed * self.mix;
+            }
+            self.position = (self.position + 1) % length;
+        }
+    }
+
+    fn reset(&mut self) {
+        for line in self.lines.iter_mut() {
+            for x in line.iter_mut() {
+                *x = 0.0;
+            }
+        }
+    }
+
+    fn tail(&self) -> usize {
+        decay_frames(self.time, self.feedback)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Freeverb's tunings, in samples at 44.1kHz. The right side's are STEREO_SPREAD longer.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const INPUT_GAIN: f32 = 0.015;
+
+#[derive(Debug, Clone)]
+struct Comb {
+    buffer: Vec<f32>,
+    position: usize,
+    filtered: f32, // the one pole low-pass in the feedback path.
+}
+
+impl Comb {
+    fn tick(&mut self, x: f32, feedback: f32, damping: f32) -> f32 {
+        let out = self.buffer[self.position];
+        self.filtered = out * (1.0 - damping) + self.filtered * damping;
+        self.buffer[self.position] = x + self.filtered * feedback;
+        self.position = (self.position + 1) % self.buffer.len();
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AllPass {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl AllPass {
+    fn tick(&mut self, x: f32) -> f32 {
+        let delayed = self.buffer[self.position];
+        self.buffer[self.position] = x + delayed * 0.5;
+        self.position = (self.position + 1) % self.buffer.len();
+        delayed - x
+    }
+}
+
+/// Freeverb. Stereo in or out; the input's summed to mono either way.
+#[derive(Debug, Clone)]
+pub struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<AllPass>; 2],
+    pub room_size: f32, // 0..1, how long it rings.
+    pub damping: f32,   // 0..1, how quickly the highs die away.
+    pub wet: f32,
+    pub dry: f32,
+    pub width: f32,     // 0 mono .. 1 full stereo.
+}
+
+impl Reverb {
+    pub fn new(sample_rate: u32) -> Reverb {
+        let scale = |samples: usize| (samples * sample_rate as usize / 44100).max(1);
+        let side = |spread: usize| {
+            let combs = COMB_TUNING.iter()
+                .map(|t| Comb { buffer: vec![0.0; scale(t + spread)], position: 0, filtered: 0.0 })
+                .collect();
+            let allpasses = ALLPASS_TUNING.iter()
+                .map(|t| AllPass { buffer: vec![0.0; scale(t + spread)], position: 0 })
+                .collect();
+            (combs, allpasses)
+        };
+        let (left, right) = (side(0), side(STEREO_SPREAD));
+        Reverb {
+            combs: [left.0, right.0],
+            allpasses: [left.1, right.1],
+            room_size: 0.5,
+            damping: 0.5,
+            wet: 0.3,
+            dry: 1.0,
+            width: 1.0,
+        }
+    }
+
+    fn feedback(&self) -> f32 {
+        self.room_size.max(0.0).min(1.0) * 0.28 + 0.7
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, samples: &mut [f32], channels: usize) {
+        let (feedback, damping) = (self.feedback(), self.damping.max(0.0).min(1.0) * 0.4);
+        let wet1 = self.wet * (self.width / 2.0 + 0.5);
+        let wet2 = self.wet * ((1.0 - self.width) / 2.0);
+
+        for frame in samples.chunks_mut(channels) {
+            let input = frame.iter().sum::<f32>() / channels as f32 * INPUT_GAIN;
+            let mut out = [0.0; 2];
+            for side in 0..2 {
+                out[side] = self.combs[side].iter_mut().map(|c| c.tick(input, feedback, damping)).sum();
+                for allpass in self.allpasses[side].iter_mut() {
+                    out[side] = allpass.tick(out[side]);
+                }
+            }
+            if channels == 1 {
+                frame[0] = frame[0] * self.dry + (out[0] + out[1]) * 0.5 * self.wet;
+            } else {
+                frame[0] = frame[0] * self.dry + out[0] * wet1 + out[1] * wet2;
+                frame[1] = frame[1] * self.dry + out[1] * wet1 + out[0] * wet2;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for side in 0..2 {
+            for comb in self.combs[side].iter_mut() {
+                comb.buffer.iter_mut().for_each(|x| *x = 0.0);
+                comb.filtered = 0.0;
+            }
+            for allpass in self.allpasses[side].iter_mut() {
+                allpass.buffer.iter_mut().for_each(|x| *x = 0.0);
+            }
+        }
+    }
+
+    fn tail(&self) -> usize {
+        let longest = self.combs[1].iter().map(|c| c.buffer.len()).max().unwrap_or(0);
+        decay_frames(longest, self.feedback())
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Shape {
+    // Smooth saturation.
+    Tanh,
+    HardClip,
+    // Reflects anything beyond ±1 back inwards, for a harsher, buzzier sound.
+    Fold,
+    Custom(fn(f32) -> f32),
+}
+
+/// Distortion: each sample, multiplied by `drive`, through a transfer curve.
+#[derive(Debug, Clone)]
+pub struct Waveshaper {
+    pub shape: Shape,
+    pub drive: f32,
+    pub level: f32, // output gain.
+    pub mix: f32,   // 0 dry .. 1 wet.
+}
+
+impl Waveshaper {
+    pub fn new(shape: Shape, drive: f32) -> Waveshaper {
+        Waveshaper { shape, drive, level: 1.0, mix: 1.0 }
+    }
+
+    pub fn shape(&self, x: f32) -> f32 {
+        match self.shape {
+            Shape::Tanh => x.tanh(),
+            Shape::HardClip => x.max(-1.0).min(1.0),
+            Shape::Fold => {
+                // A triangle wave of x, period 4, matching x over -1..1.
+                let t = (x + 1.0).rem_euclid(4.0);
+                if t < 2.0 { t - 1.0 } else { 3.0 - t }
+            }
+            Shape::Custom(f) => f(x),
+        }
+    }
+}
+
+impl Effect for Waveshaper {
+    fn process(&mut self, samples: &mut [f32], _channels: usize) {
+        for x in samples.iter_mut() {
+            let wet = self.shape(*x * self.drive) * self.level;
+            *x = *x * (1.0 - self.mix) + wet * self.mix;
+        }
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Effects run one after another.
+#[derive(Default)]
+pub struct Chain {
+    effects: Vec<Box<dyn Effect>>,
+}
+
+impl Chain {
+    pub fn new() -> Chain {
+        Chain { effects: vec![] }
+    }
+
+    /// Add an effect to the end, returning its index.
+    pub fn push<E: Effect + 'static>(&mut self, effect: E) -> usize {
+        self.effects.push(Box::new(effect));
+        self.effects.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Box<dyn Effect> {
+        self.effects.remove(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    /// The effect at `index`, if it's a T; to change its parameters.
+    pub fn get_mut<T: Effect + 'static>(&mut self, index: usize) -> Option<&mut T> {
+        self.effects.get_mut(index)?.as_any_mut().downcast_mut::<T>()
+    }
+}
+
+impl Effect for Chain {
+    fn process(&mut self, samples: &mut [f32], channels: usize) {
+        for effect in self.effects.iter_mut() {
+            effect.process(samples, channels);
+        }
+    }
+
+    fn reset(&mut self) {
+        for effect in self.effects.iter_mut() {
+            effect.reset();
+        }
+    }
+
+    fn tail(&self) -> usize {
+        self.effects.iter().map(|e| e.tail()).sum()
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A generator through a chain of effects. When the generator finishes, the effects'
+/// tails are played out before this does.
+pub struct Effected<G: Generator> {
+    pub generator: G,
+    pub effects: Chain,
+    tail: Option<usize>, // frames of tail left, once the generator's finished.
+}
+
+impl<G: Generator> Effected<G> {
+    pub fn new(generator: G, effects: Chain) -> Effected<G> {
+        Effected { generator, effects, tail: None }
+    }
+}
+
+impl<G: Generator> Generator for Effected<G> {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        let channels = self.generator.channels();
+        let frames = out.len() / channels;
+        let n = if self.tail.is_some() { 0 } else { self.generator.generate(out) };
+        for x in out[n * channels..].iter_mut() {
+            *x = 0.0;
+        }
+        self.effects.process(out, channels);
+        if n == frames {
+            return frames;
+        }
+
+        let (start, tail) = match self.tail {
+            Some(tail) => (0, tail),
+            None => (n, self.effects.tail()),
+        };
+        let end = (start + tail).min(frames);
+        self.tail = Some(tail - (end - start));
+        end
+    }
+
+    fn channels(&self) -> usize {
+        self.generator.channels()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscillator::Oscillator;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    // RMS of a sine at `frequency` through `effect`, after it's settled.
+    fn sine_rms<E: Effect>(effect: &mut E, frequency: f32) -> f32 {
+        let mut samples = vec![0.0; 8000];
+        Oscillator::sine(frequency, 8000).fill(&mut samples);
+        effect.process(&mut samples, 1);
+        (samples[4000..].iter().map(|x| x * x).sum::<f32>() / 4000.0).sqrt()
+    }
+
+    #[test]
+    fn test_filters() {
+        let db = |gain: f32| 20.0 * gain.log10();
+        let filter = |kind| Biquad::new(8000, kind, 1000.0, FRAC_1_SQRT_2);
+
+        let low = filter(FilterKind::LowPass);
+        assert!((db(low.response(1000.0)) + 3.01).abs() < 0.05);
+        assert!(low.response(50.0) > 0.999 && low.response(3000.0) < 0.1);
+        let high = filter(FilterKind::HighPass);
+        assert!((db(high.response(1000.0)) + 3.01).abs() < 0.05);
+        assert!(high.response(3500.0) > 0.99 && high.response(100.0) < 0.02);
+        let band = filter(FilterKind::BandPass);
+        assert!((band.response(1000.0) - 1.0).abs() < 1e-3 && band.response(100.0) < 0.2);
+        assert!(filter(FilterKind::Notch).response(1000.0) < 1e-3);
+
+        let peak = filter(FilterKind::Peak { gain_db: 6.0 });
+        assert!((db(peak.response(1000.0)) - 6.0).abs() < 0.01 && peak.response(30.0) < 1.01);
+        let shelf = filter(FilterKind::LowShelf { gain_db: -12.0 });
+        assert!((db(shelf.response(20.0)) + 12.0).abs() < 0.1 && shelf.response(3500.0) > 0.98);
+        let shelf = filter(FilterKind::HighShelf { gain_db: 6.0 });
+        assert!((db(shelf.response(3900.0)) - 6.0).abs() < 0.1 && shelf.response(20.0) < 1.01);
+
+        // The response matches what actually comes out.
+        let mut low = filter(FilterKind::LowPass);
+        for f in [200.0, 1000.0, 2000.0].iter() {
+            low.reset();
+            let rms = sine_rms(&mut low, *f) * 2f32.sqrt();
+            assert!((rms - low.response(*f)).abs() < 0.01, "{}Hz: {} vs {}", f, rms, low.response(*f));
+        }
+
+        // Changed while running.
+        low.set_frequency(200.0);
+        assert!(sine_rms(&mut low, 1000.0) < 0.05);
+        low.set_kind(FilterKind::HighPass);
+        assert!(sine_rms(&mut low, 1000.0) > 0.7);
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut delay = Delay::new(1000, 0.01, 0.003, 0.5, 0.5);
+        let mut samples = vec![0.0; 12];
+        samples[0] = 1.0;
+        delay.process(&mut samples, 1);
+        assert_eq!(samples, vec![0.5, 0.0, 0.0, 0.5, 0.0, 0.0, 0.25, 0.0, 0.0, 0.125, 0.0, 0.0]);
+
+        // Stereo channels are kept apart.
+        delay.reset();
+        delay.set_time(0.001);
+        let mut samples = vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        delay.process(&mut samples, 2);
+        assert_eq!(samples, vec![0.5, 0.0, 0.5, 0.0, 0.25, 0.0]);
+        assert_eq!(delay.time(), 0.001);
+        assert_eq!(delay.tail(), 1 + 9);
+    }
+
+    #[test]
+    fn test_reverb() {
+        let mut reverb = Reverb::new(8000);
+        reverb.dry = 0.0;
+        let mut samples = vec![0.0; 8000 * 2];
+        samples[0] = 1.0;
+        reverb.process(&mut samples, 2);
+        // Nothing until the shortest comb and all-passes have gone round.
+        assert!(samples[..2 * 200].iter().all(|x| *x == 0.0));
+        let energy = |s: &[f32]| s.iter().map(|x| x * x).sum::<f32>();
+        let (early, late) = (energy(&samples[..4000]), energy(&samples[12000..]));
+        assert!(early > 0.0 && late < early * 0.1, "{} {}", early, late);
+        // The sides differ.
+        assert!(samples.chunks(2).any(|lr| (lr[0] - lr[1]).abs() > 1e-4));
+
+        let short = reverb.tail();
+        reverb.room_size = 1.0;
+        assert!(reverb.tail() > short);
+        reverb.reset();
+        let mut silence = vec![0.0; 100];
+        reverb.process(&mut silence, 1);
+        assert!(silence.iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn test_waveshaper() {
+        let mut samples = vec![-3.0, -1.5, 0.0, 0.5, 1.5, 3.0];
+        Waveshaper::new(Shape::HardClip, 1.0).process(&mut samples, 1);
+        assert_eq!(samples, vec![-1.0, -1.0, 0.0, 0.5, 1.0, 1.0]);
+
+        let mut samples = vec![-3.0, -1.5, 0.0, 0.5, 1.5, 3.0];
+        Waveshaper::new(Shape::Fold, 1.0).process(&mut samples, 1);
+        assert_eq!(samples, vec![1.0, -0.5, 0.0, 0.5, 0.5, -1.0]);
+
+        let mut shaper = Waveshaper::new(Shape::Tanh, 4.0);
+        shaper.mix = 0.5;
+        let mut samples = vec![0.5];
+        shaper.process(&mut samples, 1);
+        assert!((samples[0] - (0.25 + 2f32.tanh() * 0.5)).abs() < 1e-6);
+
+        let mut shaper = Waveshaper::new(Shape::Custom(|x| x * x), 2.0);
+        let mut samples = vec![0.5, -1.0];
+        shaper.process(&mut samples, 1);
+        assert_eq!(samples, vec![1.0, 4.0]);
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut chain = Chain::new();
+        let filter = chain.push(Biquad::new(8000, FilterKind::LowPass, 3000.0, FRAC_1_SQRT_2));
+        let drive = chain.push(Waveshaper::new(Shape::HardClip, 1.0));
+        assert!(chain.get_mut::<Delay>(filter).is_none());
+        chain.get_mut::<Waveshaper>(drive).unwrap().drive = 0.5;
+        chain.get_mut::<Biquad>(filter).unwrap().set_frequency(100.0);
+        assert!(sine_rms(&mut chain, 1000.0) < 0.01);
+
+        // An echo's tail is played out after the tone.
+        let mut echo = Chain::new();
+        echo.push(Delay::new(1000, 0.1, 0.05, 0.0, 0.5));
+        let mut remaining = 100;
+        let tone = move |out: &mut [f32]| {
+            let n = out.len().min(remaining);
+            remaining -= n;
+            n
+        };
+        let mut effected = Effected::new(tone, echo);
+        let mut out = vec![0.0; 64];
+        let mut frames = 0;
+        loop {
+            let n = effected.generate(&mut out);
+            frames += n;
+            if n < out.len() {
+                break;
+            }
+        }
+        assert_eq!(frames, 150);
+    }
+}
@@ -0,0 +1,344 @@
+// Envelopes: values that change over the course of a note.
+//
+// An envelope is a list of segments, each moving from wherever the last one left off to
+// its target over its duration, on a linear or exponential curve. One segment can be the
+// sustain point: on reaching its target the envelope holds there until note off, then
+// carries on with the segments after it. An ADSR is just attack and decay segments,
+// sustaining at the end of the decay, then a release.
+//
+// Note on and off can be given as an offset into the next block, so they happen on
+// exactly the right sample however big the blocks are. Either can happen at any time:
+// segments start from the current value, so nothing jumps.
+//
+// Envelope::fill gives one value per sample. Multiplying by it shapes the amplitude
+// (see Enveloped), and it can just as well drive a frequency, or a cutoff.
+
+use crate::stream::Generator;
+
+// How far through its fall an exponential curve is, at the end: 1 - e^-5, 99.3%.
+// The curve is scaled so it does get there.
+const STEEPNESS: f32 = 5.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Curve {
+    Linear,
+    // Fast at first, then slowing towards the target, like a capacitor charging.
+    Exponential,
+}
+
+impl Curve {
+    // 0 to 1, as t goes from 0 to 1.
+    fn shape(self, t: f32) -> f32 {
+        match self {
+            Curve::Linear => t,
+            Curve::Exponential => (1.0 - (-STEEPNESS * t).exp()) / (1.0 - (-STEEPNESS).exp()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Segment {
+    pub duration: f32, // seconds.
+    pub target: f32,
+    pub curve: Curve,
+}
+
+impl Segment {
+    pub fn linear(duration: f32, target: f32) -> Segment {
+        Segment { duration, target, curve: Curve::Linear }
+    }
+
+    pub fn exponential(duration: f32, target: f32) -> Segment {
+        Segment { duration, target, curve: Curve::Exponential }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Stage {
+    Idle, // waiting for the first note on.
+    Segment { index: usize, frame: usize, from: f32 },
+    Sustain,
+    Done,
+}
+
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    sample_rate: u32,
+    start: f32,
+    segments: Vec<Segment>,
+    sustain: Option<usize>, // segment to hold at the end of.
+    value: f32,
+    stage: Stage,
+    events: Vec<(usize, bool)>, // (offset into the next block, note on), in order.
+}
+
+impl Envelope {
+    /// Starting at `start`, holding at the end of segment `sustain`, if given.
+    pub fn new(sample_rate: u32, start: f32, segments: Vec<Segment>, sustain: Option<usize>) -> Envelope {
+        assert!(sustain.map_or(true, |s| s < segments.len()));
+        Envelope { sample_rate, start, segments, sustain, value: start, stage: Stage::Idle, events: vec![] }
+    }
+
+    /// 0 to 1 over `attack` seconds, linearly, then down to `sustain` over `decay`, and
+    /// after note off, down to 0 over `release`; both exponentially.
+    pub fn adsr(sample_rate: u32, attack: f32, decay: f32, sustain: f32, release: f32) -> Envelope {
+        let segments = vec![
+            Segment::linear(attack, 1.0),
+            Segment::exponential(decay, sustain),
+            Segment::exponential(release, 0.0),
+        ];
+        Envelope::new(sample_rate, 0.0, segments, Some(1))
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// Started, and not yet finished.
+    pub fn is_active(&self) -> bool {
+        match self.stage {
+            Stage::Segment { .. } | Stage::Sustain => true,
+            Stage::Idle | Stage::Done => false,
+        }
+    }
+
+    /// Run through its last segment.
+    pub fn is_done(&self) -> bool {
+        self.stage == Stage::Done
+    }
+
+    pub fn note_on(&mut self) {
+        self.note_on_at(0);
+    }
+
+    pub fn note_off(&mut self) {
+        self.note_off_at(0);
+    }
+
+    /// Note on, `offset` samples into the next block (or a later one).
+    pub fn note_on_at(&mut self, offset: usize) {
+        self.schedule(offset, true);
+    }
+
+    pub fn note_off_at(&mut self, offset: usize) {
+        self.schedule(offset, false);
+    }
+
+    fn schedule(&mut self, offset: usize, on: bool) {
+        // After any others at the same offset.
+        let i = self.events.iter().position(|e| e.0 > offset).unwrap_or(self.events.len());
+        self.events.insert(i, (offset, on));
+    }
+
+    /// Back to the start value, forgetting any scheduled notes.
+    pub fn reset(&mut self) {
+        self.value = self.start;
+        self.stage = Stage::Idle;
+        self.events.clear();
+    }
+
+    fn start_segment(&mut self, index: usize) {
+        self.stage = if index < self.segments.len() {
+            Stage::Segment { index, frame: 0, from: self.value }
+        } else {
+            Stage::Done
+        };
+    }
+
+    fn trigger(&mut self, on: bool) {
+        if on {
+            self.start_segment(0);
+        } else if let Some(sustain) = self.sustain {
+            // Released early, the sustain is skipped; no sustain, no release.
+            match self.stage {
+                Stage::Segment { index, .. } if index <= sustain => self.start_segment(sustain + 1),
+                Stage::Sustain => self.start_segment(sustain + 1),
+                _ => {}
+            }
+        }
+    }
+
+    fn next_value(&mut self) -> f32 {
+        while let Stage::Segment { index, frame, from } = self.stage {
+            let segment = self.segments[index];
+            let length = (segment.duration * self.sample_rate as f32).round() as usize;
+            if frame < length {
+                let t = frame as f32 / length as f32;
+                self.value = from + (segment.target - from) * segment.curve.shape(t);
+                self.stage = Stage::Segment { index, frame: frame + 1, from };
+                break;
+            }
+            self.value = segment.target;
+            if self.sustain == Some(index) {
+                self.stage = Stage::Sustain;
+            } else {
+                self.start_segment(index + 1);
+            }
+        }
+        self.value
+    }
+
+    /// The next values, one per sample, applying any notes due in this block. Returns
+    /// how many come before the envelope finished (all of them if it hasn't).
+    pub fn fill(&mut self, out: &mut [f32]) -> usize {
+        let mut done_at = if self.is_done() { Some(0) } else { None };
+        for (i, x) in out.iter_mut().enumerate() {
+            while !self.events.is_empty() && self.events[0].0 <= i {
+                let (_, on) = self.events.remove(0);
+                self.trigger(on);
+                done_at = None;
+            }
+            *x = self.next_value();
+            if done_at.is_none() && self.is_done() {
+                done_at = Some(i);
+            }
+        }
+        for event in self.events.iter_mut() {
+            event.0 -= out.len();
+        }
+        match done_at {
+            Some(n) if self.events.is_empty() => n,
+            _ => out.len(),
+        }
+    }
+}
+
+/// A generator with its amplitude shaped by an envelope, finishing when the envelope
+/// does.
+pub struct Enveloped<G: Generator> {
+    pub generator: G,
+    pub envelope: Envelope,
+    buffer: Vec<f32>,
+}
+
+impl<G: Generator> Enveloped<G> {
+    pub fn new(generator: G, envelope: Envelope) -> Enveloped<G> {
+        Enveloped { generator, envelope, buffer: vec![] }
+    }
+}
+
+impl<G: Generator> Generator for Enveloped<G> {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        let channels = self.generator.channels();
+        let frames = self.generator.generate(out);
+        self.buffer.resize(out.len() / channels, 0.0);
+        let n = self.envelope.fill(&mut self.buffer).min(frames);
+        for (frame, gain) in out.chunks_mut(channels).zip(self.buffer.iter()) {
+            for x in frame {
+                *x *= gain;
+            }
+        }
+        n
+    }
+
+    fn channels(&self) -> usize {
+        self.generator.channels()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscillator::Oscillator;
+
+    fn approx(a: &[f32], b: &[f32]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-6)
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // Up to 1 over 4 samples, down to 0.5 over 2, hold, then 0 over 2.
+        let segments = vec![Segment::linear(0.004, 1.0), Segment::linear(0.002, 0.5), Segment::linear(0.002, 0.0)];
+        let mut envelope = Envelope::new(1000, 0.0, segments, Some(1));
+        let mut out = [0.0; 10];
+        assert_eq!(envelope.fill(&mut out), 10);
+        assert_eq!(out, [0.0; 10]);
+        assert!(!envelope.is_active());
+
+        envelope.note_on();
+        envelope.fill(&mut out);
+        assert!(approx(&out, &[0.0, 0.25, 0.5, 0.75, 1.0, 0.75, 0.5, 0.5, 0.5, 0.5]), "{:?}", out);
+        assert!(envelope.is_active());
+
+        envelope.note_off();
+        assert_eq!(envelope.fill(&mut out), 2);
+        assert!(approx(&out, &[0.5, 0.25, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]), "{:?}", out);
+        assert!(envelope.is_done());
+        assert_eq!(envelope.fill(&mut out), 0);
+
+        // Released during the attack, it goes down from where it got to.
+        envelope.note_on();
+        envelope.note_off_at(2);
+        envelope.fill(&mut out);
+        assert!(approx(&out[..5], &[0.0, 0.25, 0.25, 0.125, 0.0]), "{:?}", out);
+    }
+
+    #[test]
+    fn test_sample_accurate() {
+        let mut envelope = Envelope::new(1000, 0.0, vec![Segment::linear(0.002, 1.0), Segment::linear(0.002, 0.0)], Some(0));
+        envelope.note_on_at(5);
+        envelope.note_off_at(9);
+        let mut out = [0.0; 8];
+        assert_eq!(envelope.fill(&mut out), 8);
+        assert!(approx(&out, &[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 1.0]), "{:?}", out);
+        // The note off carries over into the next block.
+        assert_eq!(envelope.fill(&mut out), 3);
+        assert!(approx(&out[..4], &[1.0, 1.0, 0.5, 0.0]), "{:?}", out);
+
+        // Retriggered part way through, and again in the same block.
+        envelope.note_on_at(1);
+        envelope.note_on_at(3);
+        envelope.fill(&mut out);
+        assert!(approx(&out[..5], &[0.0, 0.0, 0.5, 0.5, 0.75]), "{:?}", out);
+    }
+
+    #[test]
+    fn test_exponential() {
+        let mut envelope = Envelope::adsr(1000, 0.0, 0.1, 0.2, 0.1);
+        envelope.note_on();
+        let mut out = vec![0.0; 200];
+        envelope.fill(&mut out);
+        // The attack takes no time at all.
+        assert_eq!(out[0], 1.0);
+        assert!(out[..101].windows(2).all(|w| w[1] < w[0]));
+        // Most of the way down by half way.
+        assert!(out[50] < 0.2 + 0.8 * 0.1);
+        assert_eq!(out[101..], vec![0.2; 99][..]);
+
+        envelope.note_off();
+        assert_eq!(envelope.fill(&mut out), 100);
+        assert!(out[..100].windows(2).all(|w| w[1] < w[0]) && out[99] < 0.01);
+        assert_eq!(out[100], 0.0);
+    }
+
+    #[test]
+    fn test_parameters() {
+        // Amplitude: the tone finishes with its release.
+        let mut envelope = Envelope::adsr(1000, 0.01, 0.01, 0.5, 0.02);
+        envelope.note_on();
+        envelope.note_off_at(100);
+        let mut tone = Enveloped::new(Oscillator::sine(100.0, 1000), envelope);
+        let mut out = vec![0.0; 64];
+        let mut frames = 0;
+        loop {
+            let n = tone.generate(&mut out);
+            frames += n;
+            if n < out.len() {
+                break;
+            }
+        }
+        assert_eq!(frames, 120);
+
+        // Frequency: an octave sweep up, one sample per step.
+        let mut sweep = Envelope::new(1000, 100.0, vec![Segment::linear(0.01, 200.0)], None);
+        sweep.note_on();
+        let mut frequencies = [0.0; 12];
+        sweep.fill(&mut frequencies);
+        let mut osc = Oscillator::new(crate::oscillator::Waveform::Saw, 100.0, 1000);
+        let mut out = [0.0; 12];
+        osc.fill_with_frequency(&mut out, &frequencies);
+        assert_eq!(osc.frequency(), 200.0);
+        let step: f32 = frequencies.iter().map(|f| f / 1000.0).sum();
+        assert!((osc.phase() - step.fract()).abs() < 1e-5, "{} vs {}", osc.phase(), step);
+    }
+}
@@ -0,0 +1,296 @@
+// FM synthesis, as in Yamaha's DX chips (strictly phase modulation, as they did it).
+//
+// A patch has two to four sine operators, each running at a ratio of the note's
+// frequency. The algorithm says which operators modulate which: a modulator's output,
+// scaled by its level in radians, is added to the phase of the operators it feeds.
+// Carriers are the operators that are heard, and their level is plain amplitude. The
+// last operator can also modulate itself, which brightens it from a sine towards a saw.
+//
+// Operators are worked out from the last to the first, so an operator modulated by a
+// higher numbered one hears this sample's output, and by a lower numbered one (only
+// possible with Algorithm::Custom) the last sample's.
+
+use std::f32::consts::PI;
+
+use crate::stream::Generator;
+
+pub const MAX_OPERATORS: usize = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Operator {
+    pub ratio: f32,
+    // Added to ratio * frequency, in Hz, for beating and inharmonic partials.
+    pub detune: f32,
+    pub level: f32,
+    // Seconds for the level to fall by 60dB, from note on; 0 holds it. Modulators
+    // decaying faster than the note is what makes struck and plucked sounds.
+    pub decay: f32,
+}
+
+impl Operator {
+    pub fn new(ratio: f32, level: f32) -> Operator {
+        Operator { ratio, detune: 0.0, level, decay: 0.0 }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Algorithm {
+    // Each operator modulates the one before: 4 -> 3 -> 2 -> 1, with 1 heard.
+    Stack,
+    // Two stacks: 2 -> 1 and 4 -> 3, with 1 and 3 heard. With three operators, 3 is
+    // heard unmodulated.
+    Pairs,
+    // All the others modulate 1, which is heard.
+    Branch,
+    // Every operator heard, none modulating: additive synthesis.
+    Parallel,
+    // For each operator, a bit mask of the operators modulating it (bit 0 for operator
+    // 1), and a mask of the carriers.
+    Custom { modulators: [u8; MAX_OPERATORS], carriers: u8 },
+}
+
+impl Algorithm {
+    // (modulators of each operator, carriers) for `n` operators.
+    fn routing(self, n: usize) -> ([u8; MAX_OPERATORS], u8) {
+        let mut modulators = [0u8; MAX_OPERATORS];
+        let all = (1u8 << n) - 1;
+        match self {
+            Algorithm::Stack => {
+                for i in 0..n - 1 {
+                    modulators[i] = 1 << (i + 1);
+                }
+                (modulators, 1)
+            }
+            Algorithm::Pairs => {
+                let mut carriers = 0;
+                for i in (0..n).step_by(2) {
+                    if i + 1 < n {
+                        modulators[i] = 1 << (i + 1);
+                    }
+                    carriers |= 1 << i;
+                }
+                (modulators, carriers)
+            }
+            Algorithm::Branch => {
+                modulators[0] = all & !1;
+                (modulators, 1)
+            }
+            Algorithm::Parallel => (modulators, all),
+            Algorithm::Custom { mut modulators, carriers } => {
+                for m in modulators.iter_mut() {
+                    *m &= all;
+                }
+                (modulators, carriers & all)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FmPatch {
+    pub operators: Vec<Operator>, // 2 to 4; more are ignored.
+    pub algorithm: Algorithm,
+    // The last operator's self modulation, in radians. Around 1 gets close to a saw.
+    pub feedback: f32,
+}
+
+impl FmPatch {
+    pub fn new(operators: Vec<Operator>, algorithm: Algorithm, feedback: f32) -> FmPatch {
+        FmPatch { operators, algorithm, feedback }
+    }
+
+    /// A struck bell: an inharmonic modulator, dying away faster than the tone.
+    pub fn bell() -> FmPatch {
+        FmPatch::new(vec![
+            Operator::new(1.0, 1.0),
+            Operator { decay: 2.0, ..Operator::new(3.5, 3.0) },
+        ], Algorithm::Stack, 0.0)
+    }
+
+    /// An electric piano: a tine, and a bright attack on top.
+    pub fn electric_piano() -> FmPatch {
+        FmPatch::new(vec![
+            Operator { decay: 3.0, ..Operator::new(1.0, 1.0) },
+            Operator { decay: 1.5, ..Operator::new(1.0, 1.2) },
+            Operator { decay: 1.5, ..Operator::new(1.0, 0.5) },
+            Operator { decay: 0.3, ..Operator::new(14.0, 1.5) },
+        ], Algorithm::Pairs, 0.0)
+    }
+
+    /// A deep, growling bass.
+    pub fn bass() -> FmPatch {
+        FmPatch::new(vec![
+            Operator::new(1.0, 1.0),
+            Operator { decay: 0.8, ..Operator::new(1.0, 2.5) },
+            Operator::new(0.5, 0.8),
+        ], Algorithm::Stack, 0.8)
+    }
+}
+
+/// Plays an FmPatch at a frequency, in mono, forever: put it in an Enveloped to give it
+/// a note's shape.
+pub struct FmVoice {
+    operators: Vec<Operator>,
+    modulators: [u8; MAX_OPERATORS],
+    carriers: u8,
+    feedback: f32,
+    frequency: f32,
+    sample_rate: u32,
+    phases: [f32; MAX_OPERATORS],
+    outputs: [f32; MAX_OPERATORS],
+    history: [f32; 2], // the last operator's last two outputs, for feedback.
+    levels: [f32; MAX_OPERATORS],
+    fall: [f32; MAX_OPERATORS], // how much the levels decay each sample.
+}
+
+impl FmVoice {
+    pub fn new(patch: &FmPatch, frequency: f32, sample_rate: u32) -> FmVoice {
+        let operators: Vec<Operator> = patch.operators.iter().take(MAX_OPERATORS).cloned().collect();
+        let (modulators, carriers) = if operators.is_empty() {
+            ([0; MAX_OPERATORS], 0)
+        } else {
+            patch.algorithm.routing(operators.len())
+        };
+        let mut voice = FmVoice {
+            operators,
+            modulators,
+            carriers,
+            feedback: patch.feedback,
+            frequency,
+            sample_rate,
+            phases: [0.0; MAX_OPERATORS],
+            outputs: [0.0; MAX_OPERATORS],
+            history: [0.0; 2],
+            levels: [0.0; MAX_OPERATORS],
+            fall: [1.0; MAX_OPERATORS],
+        };
+        for (i, op) in voice.operators.iter().enumerate() {
+            voice.levels[i] = op.level;
+            if op.decay > 0.0 {
+                voice.fall[i] = 10f32.powf(-3.0 / (op.decay * sample_rate as f32));
+            }
+        }
+        voice
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let n = self.operators.len();
+        let mut out = 0.0;
+        for i in (0..n).rev() {
+            let mut modulation = 0.0;
+            for j in 0..n {
+                if self.modulators[i] & (1 << j) != 0 {
+                    modulation += self.outputs[j];
+                }
+            }
+            if i == n - 1 {
+                // Averaging the last two outputs stops feedback breaking into noise.
+                modulation += self.feedback * (self.history[0] + self.history[1]) * 0.5;
+            }
+            let output = (2.0 * PI * self.phases[i] + modulation).sin() * self.levels[i];
+            self.outputs[i] = output;
+            if self.carriers & (1 << i) != 0 {
+                out += output;
+            }
+
+            let op = &self.operators[i];
+            let step = (self.frequency * op.ratio + op.detune) / self.sample_rate as f32;
+            self.phases[i] = (self.phases[i] + step).rem_euclid(1.0);
+            self.levels[i] *= self.fall[i];
+        }
+        if n > 0 {
+            self.history = [self.outputs[n - 1], self.history[0]];
+        }
+        // Carriers at full level add up; keep the whole at most 1.
+        out / (self.carriers.count_ones().max(1) as f32)
+    }
+}
+
+impl Generator for FmVoice {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        for x in out.iter_mut() {
+            *x = self.next_sample();
+        }
+        out.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The level of the `harmonic`th multiple of 100Hz, by correlating one second.
+    fn harmonic(samples: &[f32], harmonic: f32) -> f32 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, x) in samples.iter().enumerate() {
+            let phase = 2.0 * PI * 100.0 * harmonic * i as f32 / 8000.0;
+            re += x * phase.cos();
+            im += x * phase.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / samples.len() as f32
+    }
+
+    fn play(patch: &FmPatch) -> Vec<f32> {
+        let mut voice = FmVoice::new(patch, 100.0, 8000);
+        let mut out = vec![0.0; 8000];
+        voice.generate(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_routing() {
+        assert_eq!(Algorithm::Stack.routing(4), ([2, 4, 8, 0], 1));
+        assert_eq!(Algorithm::Pairs.routing(4), ([2, 0, 8, 0], 5));
+        assert_eq!(Algorithm::Pairs.routing(3), ([2, 0, 0, 0], 5));
+        assert_eq!(Algorithm::Branch.routing(3), ([6, 0, 0, 0], 1));
+        assert_eq!(Algorithm::Parallel.routing(2), ([0; 4], 3));
+        let custom = Algorithm::Custom { modulators: [0xf, 1, 0, 0], carriers: 0xf };
+        assert_eq!(custom.routing(2), ([3, 1, 0, 0], 3));
+    }
+
+    #[test]
+    fn test_spectrum() {
+        // No modulation: just the carrier.
+        let plain = play(&FmPatch::new(vec![Operator::new(1.0, 1.0), Operator::new(2.0, 0.0)], Algorithm::Stack, 0.0));
+        assert!((harmonic(&plain, 1.0) - 1.0).abs() < 0.01);
+        assert!(harmonic(&plain, 2.0) < 0.01);
+
+        // A 1:1 modulator at index 1 adds sidebands at every harmonic; the fundamental
+        // and second go by Bessel functions: J0(1) - J2(1) and J1(1) + J3(1).
+        let fm = play(&FmPatch::new(vec![Operator::new(1.0, 1.0), Operator::new(1.0, 1.0)], Algorithm::Stack, 0.0));
+        assert!((harmonic(&fm, 1.0) - 0.6501).abs() < 0.01, "{}", harmonic(&fm, 1.0));
+        assert!((harmonic(&fm, 2.0) - 0.4597).abs() < 0.01, "{}", harmonic(&fm, 2.0));
+
+        // Additive: two carriers, halved.
+        let parallel = play(&FmPatch::new(vec![Operator::new(1.0, 1.0), Operator::new(3.0, 1.0)], Algorithm::Parallel, 0.0));
+        assert!((harmonic(&parallel, 1.0) - 0.5).abs() < 0.01);
+        assert!((harmonic(&parallel, 3.0) - 0.5).abs() < 0.01);
+
+        // Feedback turns a lone sine towards a saw, with falling harmonics.
+        let saw = play(&FmPatch::new(vec![Operator::new(1.0, 1.0)], Algorithm::Stack, 1.2));
+        let (h2, h3) = (harmonic(&saw, 2.0), harmonic(&saw, 3.0));
+        assert!(h2 > 0.1 && h3 > 0.05 && h3 < h2, "{} {}", h2, h3);
+        assert!(saw.iter().all(|x| x.abs() <= 1.0));
+    }
+
+    #[test]
+    fn test_decay() {
+        // The modulator's gone 60dB down after its decay, leaving the carrier's sine.
+        let bell = FmPatch::bell();
+        let mut voice = FmVoice::new(&bell, 100.0, 8000);
+        let mut out = vec![0.0; 8000 * 3];
+        voice.generate(&mut out);
+        assert!(harmonic(&out[..8000], 4.5) > 0.05);
+        let end = &out[8000 * 2..];
+        assert!((harmonic(end, 1.0) - 1.0).abs() < 0.02);
+        assert!(harmonic(end, 4.5) < 0.01);
+    }
+}
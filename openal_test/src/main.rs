@@ -1,57 +1,164 @@
+mod analysis;
+mod effects;
+mod envelope;
+mod fm;
+mod midi;
+mod mixer;
+mod oscillator;
+mod sampler;
+mod sequencer;
+mod sfx;
+mod sink;
+mod stream;
+mod wav;
+mod wavetable;
+
 use alto::*;
 use alto::AltoError;
-use alto::Mono;
 use std::error::Error;
 use std::f32;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-struct SinWave {
-    sample_step: f32,
-}
+use effects::*;
+use envelope::*;
+use mixer::*;
+use oscillator::*;
+use sampler::*;
+use sequencer::*;
+use sfx::*;
+use sink::*;
+use stream::*;
+use wav::*;
 
-impl SinWave {
-    pub fn new(frequency: i32, sampling_rate: i32) -> Self {
-        SinWave {
-            sample_step: frequency as f32 / sampling_rate as f32 * 2.0 * std::f32::consts::PI,
-        }
-    }
+// A note of a tone at half volume, held for `seconds`, with a short attack and release
+// so it doesn't click.
+fn tone(waveform: Waveform, frequency: f32, seconds: f32) -> impl Generator {
+    let mut osc = Oscillator::new(waveform, frequency, 44100);
+    osc.set_amplitude(0.5);
+    let mut envelope = Envelope::adsr(44100, 0.01, 0.1, 0.8, 0.05);
+    envelope.note_on();
+    envelope.note_off_at((seconds * 44100.0) as usize);
+    Enveloped::new(osc, envelope)
+}
 
-    pub fn sample(self, i: i32) -> f32 {
-        return (i as f32 * self.sample_step).sin();
+// What main plays: a 440Hz square wave on the left, a fifth above it on the right,
+// and a low, filtered hum in the middle, all with a little reverb. Together they'd clip,
+// without the limiter. Returns the hum's handle too.
+fn layered(seconds: f32) -> (Mixer, Option<VoiceHandle>) {
+    let square = Waveform::Square { pulse_width: 0.5 };
+    let mut mixer = Mixer::new(44100, 8);
+    mixer.stop_when_idle = true;
+    mixer.add(tone(square, 440.0, seconds), VoiceSettings { pan: -0.5, ..VoiceSettings::default() });
+    mixer.add(tone(square, 660.0, seconds), VoiceSettings { pan: 0.5, fade_in: 0.5, ..VoiceSettings::default() });
+    let hum = mixer.add(tone(Waveform::Saw, 55.0, seconds), VoiceSettings { priority: 1, ..VoiceSettings::default() });
+    if let Some(hum) = hum {
+        mixer.voice_effects(hum).unwrap().push(Biquad::new(44100, FilterKind::LowPass, 400.0, 2.0));
     }
+    let mut reverb = Reverb::new(44100);
+    reverb.wet = 0.15;
+    mixer.master_effects.push(reverb);
+    (mixer, hum)
 }
 
 fn main() -> Result<(), Box<Error>> {
+    // `openal_test --wav <path>` renders the mix to a file, without a sound device.
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == "--wav" {
+        let mut wav = WavWriter::create(std::path::Path::new(&args[2]), 44100, 2, WavFormat::Pcm16)?;
+        let frames = render(&mut layered(2.0).0, &mut wav, 1024)?;
+        println!("wrote {} samples to {}", frames, args[2]);
+        return Ok(());
+    }
+
+    // `openal_test --midi <file> [wav]` plays a MIDI file, or renders it to a WAV file.
+    let midi = if args.len() >= 3 && args[1] == "--midi" {
+        let sequence = Sequence::load_midi(std::path::Path::new(&args[2]))?;
+        println!("{}: {} notes, {:.1}s", args[2], sequence.notes.len(), sequence.duration());
+        Some(sequence)
+    } else {
+        None
+    };
+    if let (Some(sequence), Some(path)) = (&midi, args.get(3)) {
+        let mut wav = WavWriter::create(std::path::Path::new(path), 44100, 2, WavFormat::Pcm16)?;
+        let frames = render(&mut Sequencer::new(sequence, 44100), &mut wav, 1024)?;
+        println!("wrote {} samples to {}", frames, path);
+        return Ok(());
+    }
+
+    // `openal_test --sfx <preset> <wav> [seed]` renders a sound effect, or a variation on
+    // one.
+    if args.len() >= 4 && args[1] == "--sfx" {
+        let preset = Preset::from_name(&args[2])
+            .ok_or_else(|| format!("unknown preset {}; try {}", args[2],
+                                   Preset::ALL.iter().map(|p| p.name()).collect::<Vec<_>>().join(", ")))?;
+        let mut params = preset.params();
+        if let Some(seed) = args.get(4) {
+            params = params.mutate(seed.parse()?, 0.3);
+        }
+        let frames = params.write_wav(std::path::Path::new(&args[3]), 44100)?;
+        println!("wrote {} samples to {}", frames, args[3]);
+        return Ok(());
+    }
+
     let alto = Alto::load_default()?;
 
     for s in alto.enumerate_outputs() {
         println!("Found device: {}", s.to_str()?);
     }
 
-    let device = alto.open(None)?; // Opens the default audio device
-    let context = device.new_context(None)?; // Creates a default context
-
-    let mut source = context.new_streaming_source()?;
+    if let Some(sequence) = midi {
+        Stream::new(Sequencer::new(&sequence, 44100), StreamConfig::new(44100))?.wait()?;
+        return Ok(());
+    }
 
-    // Now you can load your samples and store them in a buffer with
-    // `context.new_buffer(samples, frequency)`;
-    let mut samples: Vec<Mono<f32>> = vec![Mono{center: 0.0}; 44100];
-    for i in 0..44100 {
-        let mut x = i as f32 * 440.0 / 44100.0 * 2.0 * std::f32::consts::PI;
-        x = x.sin();
-        if x > 0.0 {
-            x = 1.0;
+    // `openal_test --play <wav> [rate]` plays a file: from a static buffer at its own
+    // pitch, or through the sample player at another rate.
+    if args.len() >= 3 && args[1] == "--play" {
+        let sample = Arc::new(Sample::load(std::path::Path::new(&args[2]))?);
+        println!("{}: {}Hz, {} channels, {:.2}s", args[2], sample.sample_rate, sample.channels, sample.duration());
+        if args.len() == 3 {
+            let device = alto.open(None)?;
+            let context = device.new_context(None)?;
+            let mut source = context.new_static_source()?;
+            source.set_buffer(Arc::new(sample.to_buffer(&context)?))?;
+            source.play();
+            while source.state() == SourceState::Playing {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
         } else {
-            x = -1.0;
+            let mut player = SamplePlayer::new(44100);
+            player.set_channels(sample.channels.min(2));
+            player.play(&sample, Playback { rate: args[3].parse()?, ..Playback::default() });
+            Stream::new(player, StreamConfig::new(44100))?.wait()?;
         }
-        samples[i].center = x * 0.50;
+        return Ok(());
     }
 
-    let buf = context.new_buffer(samples.as_slice(), 44100)?;
-    source.queue_buffer(buf)?;
-    source.play();
+    // Two seconds of the mix, streamed, with a pause in the middle and a beep after it.
+    let (mixer, hum) = layered(2.0);
+    let mixer = Arc::new(Mutex::new(mixer));
+    let stream = Stream::new(mixer.clone(), StreamConfig::new(44100))?;
+
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    stream.pause();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    stream.play();
+    {
+        // A beep, and the hum's filter opened up.
+        let mut mixer = mixer.lock().unwrap();
+        let beep = tone(Waveform::Sine, 880.0, 0.2);
+        mixer.add(beep, VoiceSettings { pan: 1.0, priority: 2, ..VoiceSettings::default() });
+        let filter = hum.and_then(|hum| mixer.voice_effects(hum)).and_then(|e| e.get_mut::<Biquad>(0));
+        if let Some(filter) = filter {
+            filter.set_frequency(2000.0);
+        }
+    }
+
+    while !stream.is_finished() {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    println!("played {} samples, {} underruns", stream.frames(), stream.underruns());
+    stream.wait()?;
 
-    std::thread::sleep(std::time::Duration::new(2, 0));
-    
     Ok(())
 }
@@ -0,0 +1,355 @@
+// Reading Standard MIDI Files, formats 0 and 1, into a Sequence.
+//
+// Note on/off pairs become notes, tempo and time signature meta events go into the
+// sequence's tempo map and signature, and everything else (controllers, pitch bend,
+// sysex, other meta events) is skipped. Each of the 16 channels gets an instrument,
+// picked from its first program change by General MIDI family (electric pianos, chromatic
+// percussion and basses get FM patches); channel 10 is drums.
+//
+// Only ticks-per-beat timing is read, not SMPTE.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::fm::FmPatch;
+use crate::oscillator::Waveform;
+use crate::sequencer::{Instrument, Note, Sequence};
+
+#[derive(Debug)]
+pub enum MidiError {
+    Io(io::Error),
+    NotMidi,
+    // A chunk or event (described) runs past the end of the file.
+    Truncated(String),
+    Malformed(String),
+    Unsupported(String),
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiError::Io(e) => write!(f, "{}", e),
+            MidiError::NotMidi => write!(f, "not a MIDI file"),
+            MidiError::Truncated(s) => write!(f, "{} runs past the end of the file", s),
+            MidiError::Malformed(s) => write!(f, "malformed: {}", s),
+            MidiError::Unsupported(s) => write!(f, "unsupported: {}", s),
+        }
+    }
+}
+
+impl Error for MidiError {}
+
+impl From<io::Error> for MidiError {
+    fn from(e: io::Error) -> MidiError {
+        MidiError::Io(e)
+    }
+}
+
+const DRUM_CHANNEL: usize = 9;
+
+fn u16_at(data: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes([data[i], data[i + 1]])
+}
+
+fn u32_at(data: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]])
+}
+
+// Reads through one track's bytes.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    track: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, MidiError> {
+        let b = *self.data.get(self.pos).ok_or_else(|| MidiError::Truncated(format!("an event in track {}", self.track)))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], MidiError> {
+        let bytes = self.data.get(self.pos..self.pos + n)
+            .ok_or_else(|| MidiError::Truncated(format!("an event in track {}", self.track)))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    // A variable length quantity: 7 bits a byte, most significant first, the top bit
+    // set on all but the last.
+    fn variable(&mut self) -> Result<u32, MidiError> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            value = (value << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MidiError::Malformed(format!("variable length number over 4 bytes in track {}", self.track)))
+    }
+}
+
+// A General MIDI program's rough equivalent.
+fn instrument(program: u8) -> Instrument {
+    let waveform = match program / 8 {
+        0 | 1 => Waveform::Triangle,           // piano, chromatic percussion.
+        2 => Waveform::Square { pulse_width: 0.5 }, // organ.
+        3 | 4 => Waveform::Saw,               // guitar, bass.
+        5 | 6 | 7 => Waveform::Saw,           // strings, ensemble, brass.
+        8 | 9 => Waveform::Square { pulse_width: 0.25 }, // reed, pipe.
+        10 | 11 => Waveform::Saw,             // synth lead, pad.
+        _ => Waveform::Sine,
+    };
+    let mut instrument = match program {
+        4 | 5 => Instrument::new(FmPatch::electric_piano()),
+        8..=15 => Instrument::new(FmPatch::bell()),
+        32..=39 => Instrument::new(FmPatch::bass()),
+        _ => Instrument::new(waveform),
+    };
+    if program / 8 < 2 {
+        // Struck and plucked: they die away, held or not.
+        instrument.sustain = 0.2;
+        instrument.decay = 0.6;
+    }
+    instrument
+}
+
+impl Sequence {
+    pub fn load_midi(path: &Path) -> Result<Sequence, MidiError> {
+        Sequence::read_midi(&std::fs::read(path)?)
+    }
+
+    pub fn read_midi(data: &[u8]) -> Result<Sequence, MidiError> {
+        if data.len() < 14 || &data[0..4] != b"MThd" {
+            return Err(MidiError::NotMidi);
+        }
+        let header_length = u32_at(data, 4) as usize;
+        if header_length < 6 {
+            return Err(MidiError::Malformed(format!("header is {} bytes", header_length)));
+        }
+        let format = u16_at(data, 8);
+        let tracks = u16_at(data, 10) as usize;
+        let division = u16_at(data, 12);
+        if format > 1 {
+            return Err(MidiError::Unsupported(format!("format {}", format)));
+        }
+        if division & 0x8000 != 0 {
+            return Err(MidiError::Unsupported("SMPTE timing".to_string()));
+        }
+        if division == 0 {
+            return Err(MidiError::Malformed("0 ticks per beat".to_string()));
+        }
+
+        let mut sequence = Sequence::new(120.0, (4, 4));
+        sequence.ticks_per_beat = division as u32;
+        let mut programs: [Option<u8>; 16] = [None; 16];
+
+        let mut pos = 8 + header_length;
+        let mut found = 0;
+        while found < tracks {
+            if pos + 8 > data.len() {
+                return Err(MidiError::Truncated(format!("track {}", found)));
+            }
+            let length = u32_at(data, pos + 4) as usize;
+            let body = data.get(pos + 8..pos + 8 + length).ok_or_else(|| MidiError::Truncated(format!("track {}", found)))?;
+            let is_track = &data[pos..pos + 4] == b"MTrk";
+            pos += 8 + length;
+            // Unknown chunks are skipped.
+            if is_track {
+                read_track(&mut Reader { data: body, pos: 0, track: found }, &mut sequence, &mut programs)?;
+                found += 1;
+            }
+        }
+
+        sequence.instruments = (0..16).map(|channel| {
+            if channel == DRUM_CHANNEL {
+                Instrument::drum()
+            } else {
+                instrument(programs[channel].unwrap_or(0))
+            }
+        }).collect();
+        sequence.notes.sort_by_key(|n| (n.start, n.key));
+        Ok(sequence)
+    }
+}
+
+fn read_track(reader: &mut Reader, sequence: &mut Sequence,
+              programs: &mut [Option<u8>; 16]) -> Result<(), MidiError> {
+    let mut tick = 0u32;
+    let mut status = 0u8;
+    // Notes sounding, as (channel, key, start, velocity).
+    let mut held: Vec<(usize, u8, u32, u8)> = vec![];
+
+    while reader.pos < reader.data.len() {
+        tick = tick.checked_add(reader.variable()?)
+            .ok_or_else(|| MidiError::Malformed(format!("track {} is too long", reader.track)))?;
+        let mut b = reader.byte()?;
+        if b < 0x80 {
+            // Running status: the same kind of message again, this being its first byte.
+            if status == 0 {
+                return Err(MidiError::Malformed(format!("data byte with no status in track {}", reader.track)));
+            }
+            reader.pos -= 1;
+            b = status;
+        }
+
+        match b {
+            0xff => {
+                let kind = reader.byte()?;
+                let length = reader.variable()? as usize;
+                let body = reader.bytes(length)?;
+                match kind {
+                    0x51 if length == 3 => {
+                        let micros = u32::from_be_bytes([0, body[0], body[1], body[2]]);
+                        sequence.set_tempo(tick, 60_000_000.0 / micros.max(1) as f64);
+                    }
+                    0x58 if length >= 2 => sequence.time_signature = (body[0] as u32, 1 << body[1].min(6)),
+                    0x2f => break,
+                    _ => {}
+                }
+            }
+            0xf0 | 0xf7 => {
+                let length = reader.variable()? as usize;
+                reader.bytes(length)?;
+            }
+            _ => {
+                status = b;
+                let channel = (b & 0x0f) as usize;
+                match b & 0xf0 {
+                    0x80 | 0x90 => {
+                        let (key, velocity) = (reader.byte()?, reader.byte()?);
+                        // A note off ends the earliest note on for the same key.
+                        if let Some(i) = held.iter().position(|h| h.0 == channel && h.1 == key) {
+                            let (_, _, start, on_velocity) = held.remove(i);
+                            sequence.notes.push(Note {
+                                start,
+                                length: tick - start,
+                                key,
+                                velocity: on_velocity,
+                                instrument: channel,
+                            });
+                        }
+                        if b & 0xf0 == 0x90 && velocity > 0 {
+                            held.push((channel, key, tick, velocity));
+                        }
+                    }
+                    0xa0 | 0xb0 | 0xe0 => {
+                        reader.bytes(2)?;
+                    }
+                    0xc0 => {
+                        let program = reader.byte()?;
+                        programs[channel].get_or_insert(program);
+                    }
+                    0xd0 => {
+                        reader.byte()?;
+                    }
+                    _ => {
+                        return Err(MidiError::Malformed(format!("status {:#x} in track {}", b, reader.track)));
+                    }
+                }
+            }
+        }
+    }
+
+    // Anything still held ends with the track.
+    for (channel, key, start, velocity) in held {
+        sequence.notes.push(Note { start, length: tick - start, key, velocity, instrument: channel });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sequencer::{Sequencer, Tone};
+    use crate::sink::*;
+    use crate::wav::Sample;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    // Format 1 at 96 ticks per beat: a tempo track at 60bpm in 3/4, then a track with
+    // an organ playing C for a beat, then E and G together for a beat and a half.
+    fn song() -> Vec<u8> {
+        let mut data = chunk(b"MThd", &[0, 1, 0, 2, 0, 96]);
+        data.extend(chunk(b"MTrk", &[
+            0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40,       // 1,000,000us a beat.
+            0x00, 0xff, 0x58, 0x04, 0x03, 0x02, 0x18, 0x08, // 3/4.
+            0x00, 0xff, 0x2f, 0x00,
+        ]));
+        data.extend(chunk(b"MTrk", &[
+            0x00, 0xc1, 0x10,             // channel 2: organ.
+            0x00, 0x91, 60, 100,
+            0x60, 0x81, 60, 0,
+            0x00, 0x91, 64, 80,
+            0x00, 67, 90,                 // running status.
+            0x81, 0x10, 64, 0,            // note on, velocity 0: off, 144 ticks later.
+            0x00, 67, 0,
+            0x00, 0xb1, 0x07, 0x64,       // a controller, skipped.
+            0x00, 0xff, 0x2f, 0x00,
+        ]));
+        data
+    }
+
+    #[test]
+    fn test_read_midi() {
+        let sequence = Sequence::read_midi(&song()).unwrap();
+        assert_eq!(sequence.ticks_per_beat, 96);
+        assert_eq!(sequence.tempo, vec![(0, 60.0)]);
+        assert_eq!(sequence.time_signature, (3, 4));
+        assert_eq!(sequence.instruments.len(), 16);
+        assert_eq!(sequence.instruments[1].tone, Tone::Oscillator(Waveform::Square { pulse_width: 0.5 }));
+        assert_eq!(sequence.notes, vec![
+            Note { start: 0, length: 96, key: 60, velocity: 100, instrument: 1 },
+            Note { start: 96, length: 144, key: 64, velocity: 80, instrument: 1 },
+            Note { start: 96, length: 144, key: 67, velocity: 90, instrument: 1 },
+        ]);
+        assert_eq!(sequence.duration(), 2.5);
+    }
+
+    #[test]
+    fn test_render_midi() {
+        let sequence = Sequence::read_midi(&song()).unwrap();
+        let mut wav = WavWriter::new(Cursor::new(vec![]), 8000, 2, WavFormat::Pcm16).unwrap();
+        let mut sequencer = Sequencer::new(&sequence, 8000);
+        let latency = sequencer.mixer.limiter().unwrap().latency();
+        render(&mut sequencer, &mut wav, 256).unwrap();
+        let sample = Sample::read(&wav.into_inner().into_inner()).unwrap();
+        // The last notes' release, and the limiter's delay, after 2.5 seconds.
+        assert_eq!(sample.frames(), 8000 * 5 / 2 + 800 + latency);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |data: &[u8]| Sequence::read_midi(data).unwrap_err().to_string();
+        assert_eq!(error(b"RIFF0000WAVEfmt "), "not a MIDI file");
+        assert_eq!(error(&chunk(b"MThd", &[0, 2, 0, 1, 0, 96])), "unsupported: format 2");
+        assert_eq!(error(&chunk(b"MThd", &[0, 0, 0, 1, 0xe7, 0x28])), "unsupported: SMPTE timing");
+
+        let mut data = song();
+        data.truncate(data.len() - 3);
+        assert_eq!(error(&data), "track 1 runs past the end of the file");
+
+        let mut data = chunk(b"MThd", &[0, 0, 0, 1, 0, 96]);
+        data.extend(chunk(b"MTrk", &[0x00, 60, 100]));
+        assert_eq!(error(&data), "malformed: data byte with no status in track 0");
+        let mut data = chunk(b"MThd", &[0, 0, 0, 1, 0, 96]);
+        data.extend(chunk(b"MTrk", &[0x00, 0x90, 60]));
+        assert_eq!(error(&data), "an event in track 0 runs past the end of the file");
+
+        // Enough of the longest delta times to overflow the tick count.
+        let mut data = chunk(b"MThd", &[0, 0, 0, 1, 0, 96]);
+        let track: Vec<u8> = (0..17).flat_map(|_| vec![0xff, 0xff, 0xff, 0x7f, 0xff, 0x01, 0x00]).collect();
+        data.extend(chunk(b"MTrk", &track));
+        assert_eq!(error(&data), "malformed: track 0 is too long");
+    }
+}
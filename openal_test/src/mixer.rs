@@ -0,0 +1,563 @@
+// Mixing many sounds into one stereo stream.
+//
+// Each voice is a Generator with its own gain and pan, both of which can fade to a new
+// value over time. Panning is constant power: a mono voice in the centre is -3dB on each
+// side, so it sounds as loud there as it does hard left or right. Stereo voices are
+// balanced instead, at full level on both sides in the centre.
+//
+// There's a limit on voices. When it's reached, a new voice takes the place of the
+// oldest with the lowest priority, as long as that's no higher than its own; the voice
+// it replaces is faded out quickly rather than cut, so it doesn't click.
+//
+// Each voice has its own chain of effects, which stop with it; the master bus has one
+// too, which rings on. After that, the master bus goes through a lookahead limiter,
+// then a soft clipper. The limiter delays the signal a little so it can turn the gain
+// down smoothly before a peak arrives, rather than squashing the peak itself; the soft
+// clipper catches whatever gets through, eg. with the limiter turned off.
+
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_4;
+
+use crate::effects::{Chain, Effect};
+use crate::stream::Generator;
+
+// How long a stolen voice takes to fade out.
+const STEAL_FADE: f32 = 0.005;
+
+// Above this, soft_clip starts to bend.
+const KNEE: f32 = 0.9;
+
+/// Leaves -KNEE..KNEE alone, and bends anything beyond it smoothly towards ±1.
+pub fn soft_clip(x: f32) -> f32 {
+    if x.abs() <= KNEE {
+        x
+    } else {
+        x.signum() * (KNEE + (1.0 - KNEE) * ((x.abs() - KNEE) / (1.0 - KNEE)).tanh())
+    }
+}
+
+// Left and right gains for `pan` in -1 (left) ..1 (right).
+fn pan_gains(pan: f32, channels: usize) -> (f32, f32) {
+    let angle = (pan.max(-1.0).min(1.0) + 1.0) * FRAC_PI_4;
+    if channels == 1 {
+        (angle.cos(), angle.sin())
+    } else {
+        let balance = std::f32::consts::SQRT_2;
+        ((angle.cos() * balance).min(1.0), (angle.sin() * balance).min(1.0))
+    }
+}
+
+// A value moving linearly to a target, one sample at a time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Ramp {
+    value: f32,
+    target: f32,
+    step: f32,
+}
+
+impl Ramp {
+    fn new(value: f32) -> Ramp {
+        Ramp { value, target: value, step: 0.0 }
+    }
+
+    // Reach `target` in `frames` samples; straight away if 0.
+    fn set(&mut self, target: f32, frames: usize) {
+        self.target = target;
+        if frames == 0 {
+            self.value = target;
+            self.step = 0.0;
+        } else {
+            self.step = (target - self.value) / frames as f32;
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.value == self.target
+    }
+
+    fn next(&mut self) -> f32 {
+        let value = self.value;
+        if !self.done() {
+            self.value += self.step;
+            if (self.step > 0.0) == (self.value >= self.target) {
+                self.value = self.target;
+            }
+        }
+        value
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct VoiceSettings {
+    pub gain: f32,
+    pub pan: f32, // -1 (left) ..1 (right).
+    // Higher priority voices are kept when the mixer runs out of voices.
+    pub priority: i32,
+    pub fade_in: f32, // seconds.
+}
+
+impl Default for VoiceSettings {
+    fn default() -> VoiceSettings {
+        VoiceSettings { gain: 1.0, pan: 0.0, priority: 0, fade_in: 0.0 }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VoiceHandle(usize);
+
+struct Voice {
+    handle: VoiceHandle,
+    generator: Box<dyn Generator + Send>,
+    gain: Ramp,
+    pan: Ramp,
+    priority: i32,
+    effects: Chain,
+    stopping: bool, // fading out, to be dropped once silent.
+    finished: bool,
+}
+
+/// Delays the signal by its lookahead, and turns the gain down ahead of any peak that
+/// would go over the ceiling. Both channels get the same gain, so the image doesn't
+/// shift.
+pub struct Limiter {
+    ceiling: f32,
+    window: usize,             // lookahead, in frames.
+    release: f32,              // per frame recovery, as a fraction of the distance to 1.
+    delay: VecDeque<(f32, f32)>,
+    required: VecDeque<f32>,   // the gain each frame in the window needs.
+    held: VecDeque<f32>,       // min of `required`, recovering at the release rate.
+    held_sum: f64,
+    hold: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    /// -1dB ceiling, 2ms lookahead, 100ms release.
+    pub fn new(sample_rate: u32) -> Limiter {
+        Limiter::with_settings(sample_rate, 0.891, 0.002, 0.1)
+    }
+
+    /// `lookahead` and `release` in seconds.
+    pub fn with_settings(sample_rate: u32, ceiling: f32, lookahead: f32, release: f32) -> Limiter {
+        let window = ((lookahead * sample_rate as f32) as usize).max(1);
+        Limiter {
+            ceiling,
+            window,
+            release: 1.0 - (-1.0 / (release * sample_rate as f32)).exp(),
+            delay: VecDeque::with_capacity(window),
+            required: VecDeque::with_capacity(window),
+            held: VecDeque::with_capacity(window),
+            held_sum: 0.0,
+            hold: 1.0,
+            gain: 1.0,
+        }
+    }
+
+    /// Frames of delay.
+    pub fn latency(&self) -> usize {
+        self.window - 1
+    }
+
+    /// The gain applied to the last frame out; below 1 while limiting.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    // The gain needed at each frame is held at the minimum over the window, then
+    // averaged over the window too. So every gain averaged for a frame, when it comes
+    // out of the delay, is no more than it needs: the result can't overshoot, and
+    // moves in straight lines rather than steps.
+    pub fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let peak = left.abs().max(right.abs());
+        self.required.push_back(if peak > self.ceiling { self.ceiling / peak } else { 1.0 });
+        if self.required.len() > self.window {
+            self.required.pop_front();
+        }
+        let min = self.required.iter().cloned().fold(1.0, f32::min);
+        self.hold = min.min(self.hold + (1.0 - self.hold) * self.release);
+
+        self.held.push_back(self.hold);
+        self.held_sum += self.hold as f64;
+        if self.held.len() > self.window {
+            self.held_sum -= self.held.pop_front().unwrap() as f64;
+        }
+
+        self.delay.push_back((left, right));
+        if self.delay.len() < self.window {
+            return (0.0, 0.0);
+        }
+        let (l, r) = self.delay.pop_front().unwrap();
+        self.gain = (self.held_sum / self.window as f64) as f32;
+        (l * self.gain, r * self.gain)
+    }
+}
+
+pub struct Mixer {
+    sample_rate: u32,
+    max_voices: usize,
+    voices: Vec<Voice>,
+    next_handle: usize,
+    scratch: Vec<f32>,
+    pub master_gain: f32,
+    pub master_effects: Chain, // on the master bus, before the limiter.
+    limiter: Option<Limiter>,
+    // Finish (as a Generator) once there are no voices left, rather than play silence.
+    pub stop_when_idle: bool,
+    tail: Option<usize>, // frames of the limiter's delay still to play out, once idle.
+}
+
+impl Mixer {
+    pub fn new(sample_rate: u32, max_voices: usize) -> Mixer {
+        Mixer {
+            sample_rate,
+            max_voices,
+            voices: vec![],
+            next_handle: 0,
+            scratch: vec![],
+            master_gain: 1.0,
+            master_effects: Chain::new(),
+            limiter: Some(Limiter::new(sample_rate)),
+            stop_when_idle: false,
+            tail: None,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Replace the master limiter; None leaves just the soft clipper.
+    pub fn set_limiter(&mut self, limiter: Option<Limiter>) {
+        self.limiter = limiter;
+    }
+
+    pub fn limiter(&self) -> Option<&Limiter> {
+        self.limiter.as_ref()
+    }
+
+    fn frames(&self, seconds: f32) -> usize {
+        (seconds * self.sample_rate as f32).round() as usize
+    }
+
+    /// Start a voice. If the mixer's full, the oldest voice with the lowest priority is
+    /// faded out to make room, as long as it's no higher priority than this; if
+    /// they all are, the new voice isn't played and None is returned.
+    pub fn add<G: Generator + Send + 'static>(&mut self, generator: G,
+                                              settings: VoiceSettings) -> Option<VoiceHandle> {
+        let playing = self.voices.iter().filter(|v| !v.stopping).count();
+        if playing >= self.max_voices {
+            let steal = self.voices.iter().enumerate()
+                .filter(|(_, v)| !v.stopping && v.priority <= settings.priority)
+                .min_by_key(|(_, v)| (v.priority, v.handle.0))
+                .map(|(i, _)| i)?;
+            let fade = self.frames(STEAL_FADE);
+            self.voices[steal].stopping = true;
+            self.voices[steal].gain.set(0.0, fade);
+        }
+
+        let handle = VoiceHandle(self.next_handle);
+        self.next_handle += 1;
+        let mut gain = Ramp::new(0.0);
+        gain.set(settings.gain, self.frames(settings.fade_in));
+        self.voices.push(Voice {
+            handle,
+            generator: Box::new(generator),
+            gain,
+            pan: Ramp::new(settings.pan),
+            priority: settings.priority,
+            effects: Chain::new(),
+            stopping: false,
+            finished: false,
+        });
+        self.tail = None;
+        Some(handle)
+    }
+
+    fn voice(&mut self, handle: VoiceHandle) -> Option<&mut Voice> {
+        self.voices.iter_mut().find(|v| v.handle == handle)
+    }
+
+    /// Fade the gain to `gain` over `seconds`.
+    pub fn set_gain(&mut self, handle: VoiceHandle, gain: f32, seconds: f32) {
+        let frames = self.frames(seconds);
+        if let Some(voice) = self.voice(handle) {
+            voice.gain.set(gain, frames);
+        }
+    }
+
+    /// Move to `pan` over `seconds`.
+    pub fn set_pan(&mut self, handle: VoiceHandle, pan: f32, seconds: f32) {
+        let frames = self.frames(seconds);
+        if let Some(voice) = self.voice(handle) {
+            voice.pan.set(pan, frames);
+        }
+    }
+
+    /// Fade out over `seconds`, then stop.
+    pub fn fade_out(&mut self, handle: VoiceHandle, seconds: f32) {
+        let frames = self.frames(seconds);
+        if let Some(voice) = self.voice(handle) {
+            voice.stopping = true;
+            voice.gain.set(0.0, frames);
+        }
+    }
+
+    /// The voice's effects, to add to or change.
+    pub fn voice_effects(&mut self, handle: VoiceHandle) -> Option<&mut Chain> {
+        self.voice(handle).map(|v| &mut v.effects)
+    }
+
+    /// Stop straight away.
+    pub fn stop(&mut self, handle: VoiceHandle) {
+        self.voices.retain(|v| v.handle != handle);
+    }
+
+    pub fn is_playing(&self, handle: VoiceHandle) -> bool {
+        self.voices.iter().any(|v| v.handle == handle)
+    }
+
+    /// Voices playing, including any fading out.
+    pub fn voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    // Sum the voices into `out`, returning the frame after the last voice to finish.
+    fn mix(&mut self, out: &mut [f32]) -> usize {
+        let frames = out.len() / 2;
+        let mut last = 0;
+        for voice in self.voices.iter_mut() {
+            let channels = voice.generator.channels();
+            self.scratch.resize(frames * channels, 0.0);
+            let mut end = voice.generator.generate(&mut self.scratch);
+            voice.effects.process(&mut self.scratch[..end * channels], channels);
+            for (i, frame) in out.chunks_mut(2).take(end).enumerate() {
+                let gain = voice.gain.next();
+                let (left, right) = pan_gains(voice.pan.next(), channels);
+                let (l, r) = if channels == 1 {
+                    (self.scratch[i], self.scratch[i])
+                } else {
+                    (self.scratch[i * 2], self.scratch[i * 2 + 1])
+                };
+                frame[0] += l * left * gain;
+                frame[1] += r * right * gain;
+                if voice.stopping && voice.gain.done() {
+                    end = i + 1;
+                    break;
+                }
+            }
+            if end < frames || (voice.stopping && voice.gain.done()) {
+                voice.finished = true;
+                last = last.max(end);
+            }
+        }
+        self.voices.retain(|v| !v.finished);
+        if self.voices.is_empty() { last } else { frames }
+    }
+}
+
+impl Generator for Mixer {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        let frames = out.len() / 2;
+        for x in out.iter_mut() {
+            *x = 0.0;
+        }
+        let was_idle = self.voices.is_empty();
+        let end = self.mix(out);
+        for x in out.iter_mut() {
+            *x *= self.master_gain;
+        }
+        self.master_effects.process(out, 2);
+
+        for frame in out.chunks_mut(2) {
+            let (mut l, mut r) = (frame[0], frame[1]);
+            if let Some(limiter) = self.limiter.as_mut() {
+                let limited = limiter.process(l, r);
+                l = limited.0;
+                r = limited.1;
+            }
+            frame[0] = soft_clip(l);
+            frame[1] = soft_clip(r);
+        }
+
+        if !self.stop_when_idle || !self.voices.is_empty() {
+            return frames;
+        }
+        // Play out the master effects' tails and the limiter's delay, then finish.
+        let latency = self.master_effects.tail() + self.limiter.as_ref().map_or(0, |l| l.latency());
+        let (start, tail) = if was_idle {
+            (0, self.tail.unwrap_or(latency))
+        } else {
+            (end, latency)
+        };
+        let n = (start + tail).min(frames);
+        self.tail = Some(tail - (n - start));
+        n
+    }
+
+    fn channels(&self) -> usize {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::*;
+    use crate::oscillator::Oscillator;
+    use crate::sink::*;
+
+    // A constant, for `frames` frames.
+    fn dc(value: f32, frames: usize) -> impl Generator {
+        let mut remaining = frames;
+        move |out: &mut [f32]| {
+            let n = out.len().min(remaining);
+            for x in &mut out[..n] {
+                *x = value;
+            }
+            remaining -= n;
+            n
+        }
+    }
+
+    fn frames(mixer: &mut Mixer, n: usize) -> Vec<f32> {
+        let mut out = vec![0.0; n * 2];
+        mixer.generate(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_pan() {
+        let mut mixer = Mixer::new(8000, 8);
+        mixer.set_limiter(None);
+        let voice = mixer.add(dc(0.5, 1000), VoiceSettings { pan: -1.0, ..VoiceSettings::default() }).unwrap();
+        let out = frames(&mut mixer, 1);
+        assert_eq!((out[0], out[1].abs() < 1e-6), (0.5, true));
+
+        // Constant power across the field.
+        for pan in [-0.5, 0.0, 0.3, 1.0].iter() {
+            mixer.set_pan(voice, *pan, 0.0);
+            let out = frames(&mut mixer, 1);
+            assert!((out[0] * out[0] + out[1] * out[1] - 0.25).abs() < 1e-6, "pan {}: {:?}", pan, out);
+        }
+
+        // Stereo voices are balanced, at full level in the middle.
+        let stereo = move |out: &mut [f32]| {
+            for lr in out.chunks_mut(2) {
+                lr[0] = 0.25;
+                lr[1] = -0.5;
+            }
+            out.len() / 2
+        };
+        let mut mixer = Mixer::new(8000, 8);
+        mixer.set_limiter(None);
+        let voice = mixer.add(StereoTest(stereo), VoiceSettings::default()).unwrap();
+        let out = frames(&mut mixer, 1);
+        assert!((out[0] - 0.25).abs() < 1e-6 && (out[1] + 0.5).abs() < 1e-6, "{:?}", out);
+        mixer.set_pan(voice, 1.0, 0.0);
+        let out = frames(&mut mixer, 1);
+        assert!(out[0].abs() < 1e-6 && (out[1] + 0.5).abs() < 1e-6, "{:?}", out);
+    }
+
+    struct StereoTest<F>(F);
+
+    impl<F: FnMut(&mut [f32]) -> usize> Generator for StereoTest<F> {
+        fn generate(&mut self, out: &mut [f32]) -> usize {
+            (self.0)(out)
+        }
+
+        fn channels(&self) -> usize {
+            2
+        }
+    }
+
+    #[test]
+    fn test_fades() {
+        let mut mixer = Mixer::new(1000, 8);
+        mixer.set_limiter(None);
+        let settings = VoiceSettings { gain: 0.5, pan: -1.0, fade_in: 0.004, ..VoiceSettings::default() };
+        let voice = mixer.add(dc(1.0, 100), settings).unwrap();
+        let left: Vec<f32> = frames(&mut mixer, 6).iter().step_by(2).cloned().collect();
+        assert_eq!(left, vec![0.0, 0.125, 0.25, 0.375, 0.5, 0.5]);
+
+        mixer.fade_out(voice, 0.002);
+        let left: Vec<f32> = frames(&mut mixer, 4).iter().step_by(2).cloned().collect();
+        assert_eq!(left, vec![0.5, 0.25, 0.0, 0.0]);
+        assert!(!mixer.is_playing(voice));
+        assert_eq!(mixer.voices(), 0);
+    }
+
+    #[test]
+    fn test_voice_stealing() {
+        let mut mixer = Mixer::new(1000, 2);
+        let a = mixer.add(dc(0.1, 1000), VoiceSettings { priority: 1, ..VoiceSettings::default() }).unwrap();
+        let b = mixer.add(dc(0.1, 1000), VoiceSettings::default()).unwrap();
+        let c = mixer.add(dc(0.1, 1000), VoiceSettings { priority: 1, ..VoiceSettings::default() }).unwrap();
+        // b fades out, rather than stopping dead.
+        assert!(mixer.is_playing(b));
+        frames(&mut mixer, 10);
+        assert!(mixer.is_playing(a) && !mixer.is_playing(b) && mixer.is_playing(c));
+
+        // Nothing lower priority to steal from.
+        assert_eq!(mixer.add(dc(0.1, 1000), VoiceSettings::default()), None);
+        // The oldest of equals goes.
+        let d = mixer.add(dc(0.1, 1000), VoiceSettings { priority: 1, ..VoiceSettings::default() }).unwrap();
+        frames(&mut mixer, 10);
+        assert!(!mixer.is_playing(a) && mixer.is_playing(c) && mixer.is_playing(d));
+    }
+
+    #[test]
+    fn test_effects() {
+        let mut mixer = Mixer::new(1000, 8);
+        mixer.set_limiter(None);
+        mixer.stop_when_idle = true;
+        let voice = mixer.add(dc(0.5, 20), VoiceSettings { pan: -1.0, ..VoiceSettings::default() }).unwrap();
+        let drive = mixer.voice_effects(voice).unwrap().push(Waveshaper::new(Shape::HardClip, 1.0));
+        mixer.master_effects.push(Delay::new(1000, 0.1, 0.01, 0.0, 0.5));
+        let out = frames(&mut mixer, 12);
+        assert_eq!(out[0], 0.25);
+        assert_eq!(out[20], 0.5);
+
+        // Changed while playing; the master delay rings on after the voice.
+        mixer.voice_effects(voice).unwrap().get_mut::<Waveshaper>(drive).unwrap().level = 0.5;
+        let mut out = vec![0.0; 64];
+        assert_eq!(mixer.generate(&mut out), 8 + 10);
+        assert_eq!(out[0], 0.125 + 0.25);
+        assert_eq!(out[2 * 17], 0.125);
+    }
+
+    #[test]
+    fn test_limiter() {
+        // Three 0.5 square waves would reach 1.5.
+        let mut mixer = Mixer::new(44100, 8);
+        mixer.stop_when_idle = true;
+        for (frequency, pan) in [(440.0, -0.5), (660.0, 0.5), (110.0, 0.0)].iter() {
+            let mut osc = Oscillator::square(*frequency, 44100);
+            osc.set_amplitude(0.5);
+            let mut remaining = 44100 / 2;
+            let square = move |out: &mut [f32]| {
+                let n = out.len().min(remaining);
+                osc.fill(&mut out[..n]);
+                remaining -= n;
+                n
+            };
+            mixer.add(square, VoiceSettings { pan: *pan, ..VoiceSettings::default() });
+        }
+        let latency = mixer.limiter().unwrap().latency();
+        let mut capture = Capture::new(44100, 2);
+        let frames = render(&mut mixer, &mut capture, 512).unwrap();
+        assert_eq!(frames, 44100 / 2 + latency);
+        let peak = capture.samples.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        assert!(peak <= 0.891 + 1e-4 && peak > 0.85, "peak {}", peak);
+
+        // Quiet signals pass through untouched, but delayed.
+        let mut limiter = Limiter::new(8000);
+        let out: Vec<f32> = (0..40).map(|i| limiter.process(i as f32 * 0.01, 0.0).0).collect();
+        assert_eq!(limiter.latency(), 15);
+        assert_eq!(out[15..], (0..25).map(|i| i as f32 * 0.01).collect::<Vec<_>>()[..]);
+        assert_eq!(limiter.gain(), 1.0);
+
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert!(soft_clip(1.0) < 1.0 && soft_clip(0.95) < 0.95);
+        assert_eq!((soft_clip(100.0), soft_clip(-100.0)), (1.0, -1.0));
+    }
+}
@@ -0,0 +1,261 @@
+// Oscillators for synthesising test tones.
+//
+// Phase runs from 0 to 1 over each cycle, and carries on across calls to `fill`, so
+// a tone can be generated one block at a time without clicks, and its frequency can
+// be changed between (or during) blocks.
+//
+// Square and saw have hard edges, which alias badly if sampled naively. They're
+// band-limited with PolyBLEP (Välimäki & Huovilainen 2007: Antialiasing Oscillators
+// in Subtractive Synthesis): a polynomial step residual is added around each jump,
+// smoothing it over one sample either side. The triangle has no jumps, and its
+// harmonics fall off fast enough that it's left naive.
+
+use std::f32::consts::PI;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Waveform {
+    Sine,
+    // Fraction of each cycle that's high; 0.5 is a square wave.
+    Square { pulse_width: f32 },
+    Saw,
+    Triangle,
+    WhiteNoise,
+    // -3dB per octave, using Paul Kellet's filter on white noise.
+    PinkNoise,
+}
+
+#[derive(Debug, Clone)]
+pub struct Oscillator {
+    waveform: Waveform,
+    frequency: f32,
+    sample_rate: u32,
+    phase: f32,
+    amplitude: f32,
+    noise: u32,       // xorshift state.
+    pink: [f32; 7],   // pink noise filter state.
+}
+
+// PolyBLEP residual for a jump at phase 0; t is the phase, dt the phase step.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+impl Oscillator {
+    pub fn new(waveform: Waveform, frequency: f32, sample_rate: u32) -> Oscillator {
+        Oscillator {
+            waveform,
+            frequency,
+            sample_rate,
+            phase: 0.0,
+            amplitude: 1.0,
+            noise: 0x9e37_79b9,
+            pink: [0.0; 7],
+        }
+    }
+
+    pub fn sine(frequency: f32, sample_rate: u32) -> Oscillator {
+        Oscillator::new(Waveform::Sine, frequency, sample_rate)
+    }
+
+    pub fn square(frequency: f32, sample_rate: u32) -> Oscillator {
+        Oscillator::new(Waveform::Square { pulse_width: 0.5 }, frequency, sample_rate)
+    }
+
+    pub fn waveform(&self) -> Waveform {
+        self.waveform
+    }
+
+    // Keeps the phase, so switching waveform mid tone doesn't restart the cycle.
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_amplitude(&mut self, amplitude: f32) {
+        self.amplitude = amplitude;
+    }
+
+    pub fn phase(&self) -> f32 {
+        self.phase
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+        self.pink = [0.0; 7];
+    }
+
+    // Uniform in -1..1.
+    fn white(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        (self.noise as f32 / std::u32::MAX as f32) * 2.0 - 1.0
+    }
+
+    fn pink(&mut self) -> f32 {
+        let white = self.white();
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink * 0.11 // roughly back to -1..1.
+    }
+
+    /// The next sample, advancing the phase.
+    pub fn next_sample(&mut self) -> f32 {
+        let t = self.phase;
+        // Negative frequencies run the cycle backwards. The step residuals are the same
+        // either way round, so they use the step's size.
+        let step = (self.frequency / self.sample_rate as f32).max(-0.5).min(0.5);
+        let dt = step.abs();
+
+        let value = match self.waveform {
+            Waveform::Sine => (t * 2.0 * PI).sin(),
+            Waveform::Square { pulse_width } => {
+                let pw = pulse_width.max(dt).min(1.0 - dt);
+                let naive = if t < pw { 1.0 } else { -1.0 };
+                // Up at phase 0, down at phase pw.
+                naive + poly_blep(t, dt) - poly_blep((t + 1.0 - pw) % 1.0, dt)
+            }
+            Waveform::Saw => 2.0 * t - 1.0 - poly_blep(t, dt),
+            Waveform::Triangle => 1.0 - 4.0 * (t - 0.5).abs(),
+            Waveform::WhiteNoise => self.white(),
+            Waveform::PinkNoise => self.pink(),
+        };
+
+        self.phase = (self.phase + step).rem_euclid(1.0);
+        value * self.amplitude
+    }
+
+    /// Fill a block, carrying on from where the last one ended.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for x in out.iter_mut() {
+            *x = self.next_sample();
+        }
+    }
+
+    /// Fill a block with the frequency changing every sample, eg. following an
+    /// envelope. Leaves the frequency at the last one.
+    pub fn fill_with_frequency(&mut self, out: &mut [f32], frequency: &[f32]) {
+        for (x, f) in out.iter_mut().zip(frequency) {
+            self.frequency = *f;
+            *x = self.next_sample();
+        }
+    }
+}
+
+impl Iterator for Oscillator {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        Some(self.next_sample())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAVEFORMS: [Waveform; 6] = [Waveform::Sine, Waveform::Square { pulse_width: 0.3 }, Waveform::Saw,
+                                      Waveform::Triangle, Waveform::WhiteNoise, Waveform::PinkNoise];
+
+    // The amplitude at `frequency`, which must be a whole number of cycles in `samples`.
+    fn level_at(samples: &[f32], frequency: f32, sample_rate: u32) -> f32 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, x) in samples.iter().enumerate() {
+            let phase = 2.0 * PI * frequency * i as f32 / sample_rate as f32;
+            re += x * phase.cos();
+            im += x * phase.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / samples.len() as f32
+    }
+
+    #[test]
+    fn test_blocks() {
+        for waveform in WAVEFORMS.iter() {
+            let mut whole = vec![0.0; 256];
+            Oscillator::new(*waveform, 441.0, 44100).fill(&mut whole);
+
+            let mut osc = Oscillator::new(*waveform, 441.0, 44100);
+            let mut parts = vec![0.0; 256];
+            osc.fill(&mut parts[..100]);
+            osc.fill(&mut parts[100..]);
+            assert_eq!(whole, parts, "{:?}", waveform);
+        }
+    }
+
+    #[test]
+    fn test_set_frequency() {
+        let mut osc = Oscillator::sine(100.0, 8000);
+        let mut out = vec![0.0; 30];
+        osc.fill(&mut out);
+        let phase = osc.phase();
+        assert!((phase - 30.0 / 80.0).abs() < 1e-5);
+
+        // The phase carries on from where it was, at the new rate: 40 samples a cycle.
+        osc.set_frequency(200.0);
+        assert_eq!(osc.phase(), phase);
+        let mut next = vec![0.0; 40];
+        osc.fill(&mut next);
+        assert!((osc.phase() - phase).abs() < 1e-4);
+        assert!((next[0] - (2.0 * PI * phase).sin()).abs() < 1e-5);
+        assert!((next[10] - (2.0 * PI * (phase + 0.25)).sin()).abs() < 1e-4);
+        // No jump where it changed.
+        assert!((next[0] - out[29]).abs() < 2.0 * PI * 100.0 / 8000.0);
+
+        // Backwards, wrapping below 0.
+        osc.reset();
+        osc.set_frequency(-200.0);
+        osc.fill(&mut next[..10]);
+        assert!((osc.phase() - 0.75).abs() < 1e-5);
+        osc.set_waveform(Waveform::Saw);
+        osc.fill(&mut next);
+        assert!(next.iter().all(|x| x.abs() <= 1.0));
+    }
+
+    #[test]
+    fn test_band_limited() {
+        // At 3kHz, the 15th harmonic is at 45kHz, which a naive waveform aliases down to
+        // 900Hz. 4410 samples is a whole number of cycles of both.
+        let (rate, frequency) = (44100, 3000.0);
+        let naive = |value: &dyn Fn(f32) -> f32| -> Vec<f32> {
+            (0..4410).map(|i| value((i as f32 * frequency / rate as f32).fract())).collect()
+        };
+        let naive_square = naive(&|t| if t < 0.5 { 1.0 } else { -1.0 });
+        let naive_saw = naive(&|t| 2.0 * t - 1.0);
+
+        let square = Oscillator::square(frequency, rate).take(4410).collect::<Vec<f32>>();
+        let saw = Oscillator::new(Waveform::Saw, frequency, rate).take(4410).collect::<Vec<f32>>();
+        for (band_limited, naive) in [(square, naive_square), (saw, naive_saw)].iter() {
+            // The fundamental's about the same.
+            let (a, b) = (level_at(band_limited, frequency, rate), level_at(naive, frequency, rate));
+            assert!((a - b).abs() < 0.1 * b, "{} {}", a, b);
+            let (a, b) = (level_at(band_limited, 900.0, rate), level_at(naive, 900.0, rate));
+            assert!(b > 0.02 && a < b / 4.0, "alias at {} against {} naive", a, b);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+// Playing recorded samples.
+//
+// A SamplePlayer mixes any number of voices, each playing a Sample from some position at
+// some rate, with linear interpolation between frames. Samples recorded at a different
+// rate from the player's are resampled, so a rate of 1 is always original pitch.
+//
+// One-shot voices are dropped once they reach the end. Looping voices go round between
+// their loop points until stopped, or released, after which they play on to the end.
+
+use std::sync::Arc;
+
+use crate::stream::Generator;
+use crate::wav::Sample;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Looping {
+    Off,
+    // The whole sample.
+    Whole,
+    // The loop points from the sample's file; the whole sample if it had none.
+    FromSample,
+    // First frame of the loop, and the frame after its last.
+    Points(usize, usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Playback {
+    pub rate: f32, // 2 is an octave up, and twice as fast.
+    pub gain: f32,
+    pub looping: Looping,
+    pub start: usize, // frame to start from.
+}
+
+impl Default for Playback {
+    fn default() -> Playback {
+        Playback { rate: 1.0, gain: 1.0, looping: Looping::Off, start: 0 }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VoiceId(usize);
+
+struct Voice {
+    id: VoiceId,
+    sample: Arc<Sample>,
+    position: f64, // in frames of the sample.
+    rate: f32,
+    gain: f32,
+    loop_points: Option<(usize, usize)>,
+}
+
+impl Voice {
+    // The next (left, right) frame, or None once past the end.
+    fn next(&mut self, step: f64) -> Option<(f32, f32)> {
+        let frames = self.sample.frames();
+        let i = self.position as usize;
+        if i >= frames {
+            return None;
+        }
+
+        // Interpolate towards the following frame, which wraps round at the loop end.
+        let j = match self.loop_points {
+            Some((start, end)) if i + 1 == end => start,
+            _ => (i + 1).min(frames - 1),
+        };
+        let t = (self.position - i as f64) as f32;
+        let (a, b) = (self.sample.frame(i), self.sample.frame(j));
+        let frame = ((a.0 + (b.0 - a.0) * t) * self.gain, (a.1 + (b.1 - a.1) * t) * self.gain);
+
+        self.position += step * self.rate as f64;
+        if let Some((start, end)) = self.loop_points {
+            if self.position >= end as f64 && i < end {
+                let length = (end - start) as f64;
+                self.position = start as f64 + (self.position - end as f64) % length;
+            }
+        }
+        Some(frame)
+    }
+}
+
+pub struct SamplePlayer {
+    sample_rate: u32,
+    channels: usize, // as a Generator.
+    voices: Vec<Voice>,
+    next_id: usize,
+}
+
+impl SamplePlayer {
+    pub fn new(sample_rate: u32) -> SamplePlayer {
+        SamplePlayer { sample_rate, channels: 1, voices: vec![], next_id: 0 }
+    }
+
+    /// How many channels to generate when played as a Generator, 1 or 2; mono to begin
+    /// with.
+    pub fn set_channels(&mut self, channels: usize) {
+        assert!(channels == 1 || channels == 2);
+        self.channels = channels;
+    }
+
+    pub fn play(&mut self, sample: &Arc<Sample>, playback: Playback) -> VoiceId {
+        let frames = sample.frames();
+        let loop_points = match playback.looping {
+            Looping::Off => None,
+            Looping::Whole => Some((0, frames)),
+            Looping::FromSample => Some(sample.loop_points.unwrap_or((0, frames))),
+            Looping::Points(start, end) => Some((start, end.min(frames))),
+        };
+        let loop_points = loop_points.filter(|(start, end)| start < end);
+
+        let id = VoiceId(self.next_id);
+        self.next_id += 1;
+        self.voices.push(Voice {
+            id,
+            sample: sample.clone(),
+            position: playback.start as f64,
+            rate: playback.rate,
+            gain: playback.gain,
+            loop_points,
+        });
+        id
+    }
+
+    /// Play the whole sample once, at its original pitch.
+    pub fn one_shot(&mut self, sample: &Arc<Sample>) -> VoiceId {
+        self.play(sample, Playback::default())
+    }
+
+    fn voice(&mut self, id: VoiceId) -> Option<&mut Voice> {
+        self.voices.iter_mut().find(|v| v.id == id)
+    }
+
+    /// Stop a voice straight away.
+    pub fn stop(&mut self, id: VoiceId) {
+        self.voices.retain(|v| v.id != id);
+    }
+
+    pub fn stop_all(&mut self) {
+        self.voices.clear();
+    }
+
+    /// Stop looping, and play on to the end.
+    pub fn release(&mut self, id: VoiceId) {
+        if let Some(voice) = self.voice(id) {
+            voice.loop_points = None;
+        }
+    }
+
+    pub fn set_rate(&mut self, id: VoiceId, rate: f32) {
+        if let Some(voice) = self.voice(id) {
+            voice.rate = rate;
+        }
+    }
+
+    pub fn set_gain(&mut self, id: VoiceId, gain: f32) {
+        if let Some(voice) = self.voice(id) {
+            voice.gain = gain;
+        }
+    }
+
+    pub fn is_playing(&self, id: VoiceId) -> bool {
+        self.voices.iter().any(|v| v.id == id)
+    }
+
+    pub fn voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Mix the next frames into `out`, interleaved for 1 or 2 channels; stereo samples
+    /// are averaged for mono. Returns how many frames were filled before the last
+    /// voice ended (all of them if any are still playing); the rest are silent.
+    pub fn fill(&mut self, out: &mut [f32], channels: usize) -> usize {
+        assert!(channels == 1 || channels == 2);
+        for x in out.iter_mut() {
+            *x = 0.0;
+        }
+
+        let mut last = 0;
+        for voice in self.voices.iter_mut() {
+            let step = voice.sample.sample_rate as f64 / self.sample_rate as f64;
+            for (n, frame) in out.chunks_mut(channels).enumerate() {
+                match voice.next(step) {
+                    Some((left, right)) if channels == 1 => frame[0] += (left + right) * 0.5,
+                    Some((left, right)) => {
+                        frame[0] += left;
+                        frame[1] += right;
+                    }
+                    None => break,
+                }
+                last = last.max(n + 1);
+            }
+        }
+
+        self.voices.retain(|v| (v.position as usize) < v.sample.frames());
+        if self.voices.is_empty() { last } else { out.len() / channels }
+    }
+}
+
+impl Generator for SamplePlayer {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        let channels = self.channels;
+        self.fill(out, channels)
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ramp, 0, 1, 2, ... at 8kHz.
+    fn ramp(frames: usize) -> Arc<Sample> {
+        Arc::new(Sample::new(8000, 1, (0..frames).map(|i| i as f32).collect()))
+    }
+
+    #[test]
+    fn test_one_shot() {
+        let mut player = SamplePlayer::new(8000);
+        let voice = player.one_shot(&ramp(10));
+        let mut out = [0.0; 8];
+        assert_eq!(player.fill(&mut out, 1), 8);
+        assert_eq!(out, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+        assert!(player.is_playing(voice));
+
+        assert_eq!(player.fill(&mut out, 1), 2);
+        assert_eq!(out[..3], [8.0, 9.0, 0.0]);
+        assert!(!player.is_playing(voice));
+        assert_eq!(player.voices(), 0);
+
+        // Half speed interpolates; a sample at half the player's rate is the same.
+        player.play(&ramp(4), Playback { rate: 0.5, ..Playback::default() });
+        let mut out = [0.0; 8];
+        assert_eq!(player.fill(&mut out, 1), 8);
+        assert_eq!(out[..7], [0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0]);
+        let mut player = SamplePlayer::new(16000);
+        player.one_shot(&ramp(4));
+        let mut out = [0.0; 8];
+        player.fill(&mut out, 1);
+        assert_eq!(out[..7], [0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0]);
+    }
+
+    #[test]
+    fn test_looping() {
+        let mut player = SamplePlayer::new(8000);
+        let voice = player.play(&ramp(6), Playback { looping: Looping::Points(2, 4), ..Playback::default() });
+        let mut out = [0.0; 8];
+        assert_eq!(player.fill(&mut out, 1), 8);
+        assert_eq!(out, [0.0, 1.0, 2.0, 3.0, 2.0, 3.0, 2.0, 3.0]);
+
+        // Interpolation wraps round the loop too.
+        player.set_rate(voice, 0.5);
+        player.fill(&mut out, 1);
+        assert_eq!(out[..4], [2.0, 2.5, 3.0, 2.5]);
+
+        // Released, it plays out past the loop end.
+        player.set_rate(voice, 1.0);
+        player.release(voice);
+        let n = player.fill(&mut out, 1);
+        assert_eq!(out[..n].last(), Some(&5.0));
+        assert!(!player.is_playing(voice));
+    }
+
+    #[test]
+    fn test_mixing() {
+        let stereo = Arc::new(Sample::new(8000, 2, vec![1.0, -1.0, 0.5, 0.25]));
+        let mut player = SamplePlayer::new(8000);
+        player.one_shot(&stereo);
+        let voice = player.play(&stereo, Playback { gain: 0.5, ..Playback::default() });
+        let mut out = [0.0; 6];
+        assert_eq!(player.fill(&mut out, 2), 2);
+        assert_eq!(out, [1.5, -1.5, 0.75, 0.375, 0.0, 0.0]);
+
+        player.play(&stereo, Playback { gain: 2.0, ..Playback::default() });
+        let mut out = [0.0; 2];
+        player.fill(&mut out, 1);
+        assert_eq!(out, [0.0, 0.75]);
+
+        player.play(&ramp(100), Playback { looping: Looping::Whole, ..Playback::default() });
+        player.stop(voice);
+        player.stop_all();
+        assert_eq!(player.voices(), 0);
+
+        // As a Generator, in stereo.
+        assert_eq!(player.channels(), 1);
+        player.set_channels(2);
+        player.one_shot(&stereo);
+        let mut out = [0.0; 6];
+        assert_eq!((player.channels(), player.generate(&mut out)), (2, 2));
+        assert_eq!(out, [1.0, -1.0, 0.5, 0.25, 0.0, 0.0]);
+    }
+}
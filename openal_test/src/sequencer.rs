@@ -0,0 +1,356 @@
+// Playing notes to a tempo.
+//
+// A Sequence is a list of notes, timed in ticks (fractions of a beat, as in MIDI),
+// with a tempo map and a time signature, and the instruments to play them on. An
+// instrument is a tone (a plain oscillator, an FM patch or a wavetable) shaped by an
+// ADSR envelope.
+//
+// The Sequencer turns the ticks into samples, and starts a mixer voice for each note in
+// the block it falls in, with its envelope's note on and off at the exact sample. So
+// the mixer's panning, voice stealing and limiter all apply.
+
+use std::sync::Arc;
+
+use crate::envelope::{Envelope, Enveloped};
+use crate::fm::{FmPatch, FmVoice};
+use crate::mixer::{Mixer, VoiceSettings};
+use crate::oscillator::{Oscillator, Waveform};
+use crate::stream::Generator;
+use crate::wavetable::{Wavetable, WavetableOscillator};
+
+/// 440Hz for A4, key 69.
+pub fn key_frequency(key: u8) -> f32 {
+    440.0 * 2f32.powf((key as f32 - 69.0) / 12.0)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tone {
+    Oscillator(Waveform),
+    Fm(FmPatch),
+    // Starting `position` along the tables, and moving `sweep` a second.
+    Wavetable { table: Arc<Wavetable>, position: f32, sweep: f32 },
+}
+
+impl From<Waveform> for Tone {
+    fn from(waveform: Waveform) -> Tone {
+        Tone::Oscillator(waveform)
+    }
+}
+
+impl From<FmPatch> for Tone {
+    fn from(patch: FmPatch) -> Tone {
+        Tone::Fm(patch)
+    }
+}
+
+// A playing tone.
+enum Voice {
+    Oscillator(Oscillator),
+    Fm(FmVoice),
+    Wavetable(WavetableOscillator),
+}
+
+impl Tone {
+    fn voice(&self, frequency: f32, sample_rate: u32) -> Voice {
+        match self {
+            Tone::Oscillator(waveform) => Voice::Oscillator(Oscillator::new(*waveform, frequency, sample_rate)),
+            Tone::Fm(patch) => Voice::Fm(FmVoice::new(patch, frequency, sample_rate)),
+            Tone::Wavetable { table, position, sweep } => {
+                let mut osc = WavetableOscillator::new(table.clone(), frequency, sample_rate);
+                osc.set_position(*position);
+                osc.set_sweep(*sweep);
+                Voice::Wavetable(osc)
+            }
+        }
+    }
+}
+
+impl Generator for Voice {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        match self {
+            Voice::Oscillator(osc) => osc.generate(out),
+            Voice::Fm(fm) => fm.generate(out),
+            Voice::Wavetable(osc) => osc.generate(out),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    pub tone: Tone,
+    // The envelope, in seconds, apart from the sustain level.
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub gain: f32,
+    pub pan: f32,
+    // Plays the same pitch whatever the key, eg. for drums.
+    pub fixed_frequency: Option<f32>,
+}
+
+impl Instrument {
+    /// A waveform or an FmPatch, with a short attack and release.
+    pub fn new<T: Into<Tone>>(tone: T) -> Instrument {
+        Instrument {
+            tone: tone.into(),
+            attack: 0.01,
+            decay: 0.1,
+            sustain: 0.7,
+            release: 0.1,
+            gain: 0.3,
+            pan: 0.0,
+            fixed_frequency: None,
+        }
+    }
+
+    /// A short burst of noise.
+    pub fn drum() -> Instrument {
+        Instrument {
+            attack: 0.001,
+            decay: 0.08,
+            sustain: 0.0,
+            release: 0.05,
+            fixed_frequency: Some(0.0),
+            ..Instrument::new(Waveform::WhiteNoise)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Note {
+    pub start: u32,  // ticks.
+    pub length: u32, // ticks.
+    pub key: u8,     // MIDI key number; 60 is middle C.
+    pub velocity: u8, // 1..127.
+    pub instrument: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequence {
+    pub ticks_per_beat: u32,
+    // (tick, beats per minute), in order, starting at tick 0.
+    pub tempo: Vec<(u32, f64)>,
+    // Beats per bar, and the note that gets one beat: (6, 8) is six quavers to a bar.
+    pub time_signature: (u32, u32),
+    pub notes: Vec<Note>,
+    pub instruments: Vec<Instrument>,
+}
+
+impl Sequence {
+    pub fn new(bpm: f64, time_signature: (u32, u32)) -> Sequence {
+        Sequence { ticks_per_beat: 480, tempo: vec![(0, bpm)], time_signature, notes: vec![], instruments: vec![] }
+    }
+
+    /// Change tempo from `tick` on.
+    pub fn set_tempo(&mut self, tick: u32, bpm: f64) {
+        self.tempo.retain(|t| t.0 != tick);
+        let i = self.tempo.iter().position(|t| t.0 > tick).unwrap_or(self.tempo.len());
+        self.tempo.insert(i, (tick, bpm));
+    }
+
+    pub fn add_instrument(&mut self, instrument: Instrument) -> usize {
+        self.instruments.push(instrument);
+        self.instruments.len() - 1
+    }
+
+    /// Ticks for a number of beats; a beat being a quarter note, as in MIDI.
+    pub fn beats(&self, beats: f64) -> u32 {
+        (beats * self.ticks_per_beat as f64).round() as u32
+    }
+
+    /// The tick `beat` beats of the time signature into bar `bar`, both from 0.
+    pub fn bar(&self, bar: u32, beat: f64) -> u32 {
+        let (per_bar, unit) = self.time_signature;
+        let quarters_per_beat = 4.0 / unit as f64;
+        self.beats((bar as f64 * per_bar as f64 + beat) * quarters_per_beat)
+    }
+
+    /// Add a note at `bar` and `beat`, lasting `beats` of the time signature.
+    pub fn note(&mut self, instrument: usize, bar: u32, beat: f64, beats: f64, key: u8, velocity: u8) {
+        let start = self.bar(bar, beat);
+        let length = self.bar(0, beats);
+        self.notes.push(Note { start, length, key, velocity, instrument });
+    }
+
+    /// Seconds from the start to `tick`, following the tempo changes.
+    pub fn seconds(&self, tick: u32) -> f64 {
+        let mut seconds = 0.0;
+        for (i, (from, bpm)) in self.tempo.iter().enumerate() {
+            if *from >= tick {
+                break;
+            }
+            let to = self.tempo.get(i + 1).map_or(tick, |t| t.0.min(tick));
+            seconds += (to - from) as f64 / self.ticks_per_beat as f64 * 60.0 / bpm;
+        }
+        seconds
+    }
+
+    /// When the last note ends, not counting its release.
+    pub fn duration(&self) -> f64 {
+        self.seconds(self.notes.iter().map(|n| n.start + n.length).max().unwrap_or(0))
+    }
+}
+
+// A note, timed in frames.
+#[derive(Debug, Copy, Clone)]
+struct Scheduled {
+    start: usize,
+    end: usize,
+    note: Note,
+}
+
+/// Plays a Sequence, in stereo, finishing after its last note has died away.
+pub struct Sequencer {
+    sample_rate: u32,
+    instruments: Vec<Instrument>,
+    notes: Vec<Scheduled>, // by start.
+    next: usize,           // the next note to start.
+    position: usize,       // frames played.
+    pub mixer: Mixer,
+}
+
+impl Sequencer {
+    pub fn new(sequence: &Sequence, sample_rate: u32) -> Sequencer {
+        let frame = |tick| (sequence.seconds(tick) * sample_rate as f64).round() as usize;
+        let mut notes: Vec<Scheduled> = sequence.notes.iter()
+            .map(|n| Scheduled { start: frame(n.start), end: frame(n.start + n.length), note: *n })
+            .collect();
+        notes.sort_by_key(|n| n.start);
+        Sequencer {
+            sample_rate,
+            instruments: sequence.instruments.clone(),
+            notes,
+            next: 0,
+            position: 0,
+            mixer: Mixer::new(sample_rate, 32),
+        }
+    }
+
+    /// Seconds played so far.
+    pub fn time(&self) -> f64 {
+        self.position as f64 / self.sample_rate as f64
+    }
+
+    fn start(&mut self, note: Scheduled) {
+        // Notes for instruments that don't exist get the first one, or a sine.
+        let instrument = self.instruments.get(note.note.instrument)
+            .or_else(|| self.instruments.first())
+            .cloned()
+            .unwrap_or_else(|| Instrument::new(Waveform::Sine));
+
+        let frequency = instrument.fixed_frequency.unwrap_or_else(|| key_frequency(note.note.key));
+        let voice = instrument.tone.voice(frequency, self.sample_rate);
+        let mut envelope = Envelope::adsr(self.sample_rate, instrument.attack, instrument.decay,
+                                          instrument.sustain, instrument.release);
+        let offset = note.start - self.position;
+        envelope.note_on_at(offset);
+        envelope.note_off_at(offset + (note.end - note.start));
+
+        let settings = VoiceSettings {
+            gain: instrument.gain * note.note.velocity as f32 / 127.0,
+            pan: instrument.pan,
+            ..VoiceSettings::default()
+        };
+        self.mixer.add(Enveloped::new(voice, envelope), settings);
+    }
+}
+
+impl Generator for Sequencer {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        let end = self.position + out.len() / 2;
+        while self.next < self.notes.len() && self.notes[self.next].start < end {
+            let note = self.notes[self.next];
+            self.start(note);
+            self.next += 1;
+        }
+        // Once every note's started, finish when they've all stopped.
+        self.mixer.stop_when_idle = self.next == self.notes.len();
+        self.position = end;
+        self.mixer.generate(out)
+    }
+
+    fn channels(&self) -> usize {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::*;
+
+    #[test]
+    fn test_timing() {
+        let mut sequence = Sequence::new(120.0, (6, 8));
+        assert_eq!(key_frequency(69), 440.0);
+        assert!((key_frequency(60) - 261.626).abs() < 1e-3);
+
+        // Six quavers to the bar: three quarter notes.
+        assert_eq!(sequence.bar(1, 0.0), 3 * 480);
+        assert_eq!(sequence.bar(2, 3.0), 7 * 480 + 240);
+        assert_eq!(sequence.seconds(480 * 4), 2.0);
+
+        // Twice as fast after beat 2.
+        sequence.set_tempo(960, 240.0);
+        assert_eq!(sequence.seconds(480), 0.5);
+        assert_eq!(sequence.seconds(480 * 4), 1.5);
+        sequence.set_tempo(960, 60.0);
+        assert_eq!(sequence.tempo.len(), 2);
+        assert_eq!(sequence.seconds(480 * 4), 3.0);
+    }
+
+    #[test]
+    fn test_sequencer() {
+        // Two notes, a beat apart, with no attack and a short release.
+        let mut sequence = Sequence::new(120.0, (4, 4));
+        let lead = sequence.add_instrument(Instrument {
+            attack: 0.0,
+            release: 0.01,
+            ..Instrument::new(Waveform::Square { pulse_width: 0.5 })
+        });
+        sequence.note(lead, 0, 0.0, 0.5, 69, 127);
+        sequence.note(lead, 0, 1.0, 1.0, 76, 64);
+        assert_eq!(sequence.duration(), 1.0);
+
+        let mut sequencer = Sequencer::new(&sequence, 8000);
+        sequencer.mixer.set_limiter(None);
+        let mut capture = Capture::new(8000, 2);
+        let frames = render(&mut sequencer, &mut capture, 96).unwrap();
+        // Ends with the release of the second note.
+        assert_eq!(frames, 8000 + 80);
+
+        // The first note's released a quarter of a second in, the second starts on the
+        // beat, to the sample.
+        let left = capture.channel(0);
+        assert!(left[1000..1999].iter().any(|x| x.abs() > 0.1));
+        assert!(left[2080..4000].iter().all(|x| *x == 0.0));
+        assert!(left[4001].abs() > 0.05);
+        assert!(left[5000..6000].iter().fold(0.0f32, |m, x| m.max(x.abs())) < 0.3 * 0.5);
+    }
+
+    #[test]
+    fn test_tones() {
+        // FM and wavetable instruments play through the same envelopes.
+        let mut sequence = Sequence::new(120.0, (4, 4));
+        let bell = sequence.add_instrument(Instrument { attack: 0.0, ..Instrument::new(FmPatch::bell()) });
+        let table = Arc::new(Wavetable::from_waveforms(&[Waveform::Sine, Waveform::Saw]));
+        let pad = sequence.add_instrument(Instrument {
+            attack: 0.0,
+            ..Instrument::new(Tone::Wavetable { table, position: 0.0, sweep: 1.0 })
+        });
+        sequence.note(bell, 0, 0.0, 1.0, 69, 127);
+        sequence.note(pad, 0, 2.0, 1.0, 57, 127);
+
+        let mut sequencer = Sequencer::new(&sequence, 8000);
+        sequencer.mixer.set_limiter(None);
+        let mut capture = Capture::new(8000, 2);
+        let frames = render(&mut sequencer, &mut capture, 128).unwrap();
+        assert_eq!(frames, 12000 + 800);
+        let left = capture.channel(0);
+        let peak = |range: std::ops::Range<usize>| left[range].iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        assert!(peak(0..4000) > 0.1);
+        assert_eq!(peak(4800..8000), 0.0);
+        assert!(peak(8000..12000) > 0.1);
+    }
+}
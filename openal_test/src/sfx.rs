@@ -0,0 +1,480 @@
+// Procedural sound effects, after DrPetter's sfxr.
+//
+// A handful of parameters describe a short sound: a tone or noise whose pitch slides,
+// wobbles and jumps, a duty cycle that sweeps, an attack/sustain/decay envelope with an
+// optional punch, and low and high-pass filters whose cutoffs sweep too. The same
+// parameters, at the same sample rate, always make exactly the same samples: noise
+// comes from a fixed seed, and mutate() from the seed it's given.
+//
+// Times are in seconds, frequencies in Hz, and slides and sweeps in octaves per second.
+
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::path::Path;
+
+use crate::effects::{Biquad, FilterKind};
+use crate::envelope::{Envelope, Segment};
+use crate::oscillator::{Oscillator, Waveform};
+use crate::sink::{render, SinkError, WavFormat, WavWriter};
+use crate::stream::Generator;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Wave {
+    Square,
+    Saw,
+    Sine,
+    Triangle,
+    // Held for 1/32 of a cycle at the current frequency, so it can be pitched too.
+    Noise,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SfxParams {
+    pub wave: Wave,
+    pub frequency: f32,
+    // Stop once the pitch slides below this.
+    pub min_frequency: f32,
+    pub slide: f32,
+    pub delta_slide: f32, // octaves per second, per second.
+    pub vibrato_depth: f32, // semitones.
+    pub vibrato_speed: f32, // Hz.
+    // Multiply the frequency by this, `arpeggio_time` in; 1 for none.
+    pub arpeggio: f32,
+    pub arpeggio_time: f32,
+
+    pub attack: f32,
+    pub sustain: f32,
+    // Extra level at the start of the sustain, falling away over it.
+    pub punch: f32,
+    pub decay: f32,
+
+    pub duty: f32,       // square waves' pulse width.
+    pub duty_sweep: f32, // per second.
+
+    pub lowpass: Option<f32>,
+    pub lowpass_sweep: f32,
+    pub lowpass_resonance: f32, // Q.
+    pub highpass: Option<f32>,
+    pub highpass_sweep: f32,
+
+    // Noise mixed into the tone, 0..1.
+    pub noise: f32,
+    pub gain: f32,
+}
+
+impl Default for SfxParams {
+    // A plain square wave beep.
+    fn default() -> SfxParams {
+        SfxParams {
+            wave: Wave::Square,
+            frequency: 440.0,
+            min_frequency: 20.0,
+            slide: 0.0,
+            delta_slide: 0.0,
+            vibrato_depth: 0.0,
+            vibrato_speed: 0.0,
+            arpeggio: 1.0,
+            arpeggio_time: 0.0,
+            attack: 0.0,
+            sustain: 0.1,
+            punch: 0.0,
+            decay: 0.2,
+            duty: 0.5,
+            duty_sweep: 0.0,
+            lowpass: None,
+            lowpass_sweep: 0.0,
+            lowpass_resonance: FRAC_1_SQRT_2,
+            highpass: None,
+            highpass_sweep: 0.0,
+            noise: 0.0,
+            gain: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Preset {
+    Thrust,
+    Jump,
+    Land,
+    Pickup,
+    Explosion,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 5] = [Preset::Thrust, Preset::Jump, Preset::Land, Preset::Pickup, Preset::Explosion];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Thrust => "thrust",
+            Preset::Jump => "jump",
+            Preset::Land => "land",
+            Preset::Pickup => "pickup",
+            Preset::Explosion => "explosion",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Preset> {
+        Preset::ALL.iter().cloned().find(|p| p.name() == name)
+    }
+
+    pub fn params(self) -> SfxParams {
+        let base = SfxParams::default();
+        match self {
+            // A steady, filtered roar.
+            Preset::Thrust => SfxParams {
+                wave: Wave::Noise,
+                frequency: 200.0,
+                vibrato_depth: 1.0,
+                vibrato_speed: 12.0,
+                attack: 0.08,
+                sustain: 0.6,
+                decay: 0.3,
+                lowpass: Some(900.0),
+                lowpass_resonance: 1.5,
+                highpass: Some(60.0),
+                ..base
+            },
+            // A quick rising square.
+            Preset::Jump => SfxParams {
+                frequency: 280.0,
+                slide: 2.5,
+                sustain: 0.08,
+                decay: 0.15,
+                duty: 0.3,
+                duty_sweep: 1.0,
+                highpass: Some(120.0),
+                ..base
+            },
+            // A dull thump.
+            Preset::Land => SfxParams {
+                wave: Wave::Sine,
+                frequency: 140.0,
+                slide: -4.0,
+                sustain: 0.03,
+                punch: 0.6,
+                decay: 0.15,
+                noise: 0.4,
+                lowpass: Some(1200.0),
+                lowpass_sweep: -3.0,
+                gain: 0.7,
+                ..base
+            },
+            // Two bright notes, a fourth apart.
+            Preset::Pickup => SfxParams {
+                frequency: 990.0,
+                arpeggio: 4.0 / 3.0,
+                arpeggio_time: 0.06,
+                sustain: 0.06,
+                punch: 0.4,
+                decay: 0.2,
+                duty: 0.25,
+                ..base
+            },
+            // Falling noise that rumbles away.
+            Preset::Explosion => SfxParams {
+                wave: Wave::Noise,
+                frequency: 120.0,
+                min_frequency: 5.0,
+                slide: -1.0,
+                sustain: 0.3,
+                punch: 0.7,
+                decay: 0.8,
+                lowpass: Some(4000.0),
+                lowpass_sweep: -2.0,
+                gain: 0.5,
+                ..base
+            },
+        }
+    }
+}
+
+// xorshift, for mutate.
+struct Rng(u32);
+
+impl Rng {
+    fn new(seed: u32) -> Rng {
+        Rng(seed.wrapping_mul(0x9e37_79b9) | 1)
+    }
+
+    // -1..1.
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 as f32 / std::u32::MAX as f32) * 2.0 - 1.0
+    }
+}
+
+impl SfxParams {
+    /// A variation: every continuous parameter moved by up to `amount` (0..1) of its
+    /// sensible range, randomly but the same way for the same seed.
+    pub fn mutate(&self, seed: u32, amount: f32) -> SfxParams {
+        let mut rng = Rng::new(seed);
+        let mut p = *self;
+        let mut nudge = |x: f32, range: f32, min: f32, max: f32| (x + rng.next() * amount * range).max(min).min(max);
+
+        // Frequencies move in octaves.
+        p.frequency *= 2f32.powf(nudge(0.0, 1.0, -1.0, 1.0));
+        p.slide = nudge(p.slide, 2.0, -8.0, 8.0);
+        p.delta_slide = nudge(p.delta_slide, 2.0, -8.0, 8.0);
+        p.vibrato_depth = nudge(p.vibrato_depth, 1.0, 0.0, 12.0);
+        p.vibrato_speed = nudge(p.vibrato_speed, 10.0, 0.0, 40.0);
+        p.attack = nudge(p.attack, 0.1, 0.0, 2.0);
+        p.sustain = nudge(p.sustain, 0.2, 0.0, 2.0);
+        p.punch = nudge(p.punch, 0.5, 0.0, 1.0);
+        p.decay = nudge(p.decay, 0.3, 0.0, 3.0);
+        p.duty = nudge(p.duty, 0.3, 0.05, 0.95);
+        p.duty_sweep = nudge(p.duty_sweep, 1.0, -2.0, 2.0);
+        p.noise = nudge(p.noise, 0.3, 0.0, 1.0);
+        if let Some(f) = p.lowpass {
+            p.lowpass = Some(f * 2f32.powf(nudge(0.0, 1.0, -1.0, 1.0)));
+            p.lowpass_sweep = nudge(p.lowpass_sweep, 2.0, -8.0, 8.0);
+        }
+        if let Some(f) = p.highpass {
+            p.highpass = Some(f * 2f32.powf(nudge(0.0, 1.0, -1.0, 1.0)));
+            p.highpass_sweep = nudge(p.highpass_sweep, 2.0, -8.0, 8.0);
+        }
+        p
+    }
+
+    /// Seconds, unless the pitch slides away first.
+    pub fn duration(&self) -> f32 {
+        self.attack + self.sustain + self.decay
+    }
+
+    /// The pitch `t` seconds in, or None once it's slid below min_frequency.
+    pub fn frequency_at(&self, t: f32) -> Option<f32> {
+        let octaves = self.slide * t + 0.5 * self.delta_slide * t * t;
+        let mut frequency = self.frequency * 2f32.powf(octaves);
+        if frequency < self.min_frequency {
+            return None;
+        }
+        if self.vibrato_depth > 0.0 {
+            let wobble = (2.0 * std::f32::consts::PI * self.vibrato_speed * t).sin();
+            frequency *= 2f32.powf(self.vibrato_depth * wobble / 12.0);
+        }
+        if self.arpeggio != 1.0 && t >= self.arpeggio_time {
+            frequency *= self.arpeggio;
+        }
+        Some(frequency)
+    }
+
+    /// Render to a 16 bit mono WAV file, returning the frames written.
+    pub fn write_wav(&self, path: &Path, sample_rate: u32) -> Result<usize, SinkError> {
+        let mut wav = WavWriter::create(path, sample_rate, 1, WavFormat::Pcm16)?;
+        render(&mut Sfx::new(*self, sample_rate), &mut wav, 1024)
+    }
+}
+
+/// Plays an SfxParams, finishing when it's done.
+pub struct Sfx {
+    params: SfxParams,
+    sample_rate: u32,
+    frame: usize,
+    osc: Oscillator,
+    noise: Oscillator,
+    noise_phase: f32,
+    noise_value: f32,
+    envelope: Envelope,
+    lowpass: Biquad,
+    highpass: Biquad,
+    finished: bool,
+    // Per block scratch.
+    levels: Vec<f32>,
+    cutoffs: Vec<f32>,
+}
+
+impl Sfx {
+    pub fn new(params: SfxParams, sample_rate: u32) -> Sfx {
+        let waveform = match params.wave {
+            Wave::Square => Waveform::Square { pulse_width: params.duty },
+            Wave::Saw => Waveform::Saw,
+            Wave::Sine => Waveform::Sine,
+            Wave::Triangle => Waveform::Triangle,
+            Wave::Noise => Waveform::Sine, // unused.
+        };
+        let segments = vec![
+            Segment::linear(params.attack, 1.0 + params.punch),
+            Segment::linear(params.sustain, 1.0),
+            Segment::linear(params.decay, 0.0),
+        ];
+        let mut envelope = Envelope::new(sample_rate, 0.0, segments, None);
+        envelope.note_on();
+        Sfx {
+            params,
+            sample_rate,
+            frame: 0,
+            osc: Oscillator::new(waveform, params.frequency, sample_rate),
+            noise: Oscillator::new(Waveform::WhiteNoise, 0.0, sample_rate),
+            noise_phase: 0.0,
+            noise_value: 0.0,
+            envelope,
+            lowpass: Biquad::new(sample_rate, FilterKind::LowPass, params.lowpass.unwrap_or(1000.0), params.lowpass_resonance),
+            highpass: Biquad::new(sample_rate, FilterKind::HighPass, params.highpass.unwrap_or(100.0), FRAC_1_SQRT_2),
+            finished: false,
+            levels: vec![],
+            cutoffs: vec![],
+        }
+    }
+
+    // Sample-and-hold noise, changing 32 times a cycle.
+    fn noise(&mut self, frequency: f32) -> f32 {
+        self.noise_phase += frequency * 32.0 / self.sample_rate as f32;
+        if self.noise_phase >= 1.0 || self.frame == 0 {
+            self.noise_phase = self.noise_phase.fract();
+            self.noise_value = self.noise.next_sample();
+        }
+        self.noise_value
+    }
+
+    fn sweep(&mut self, out: &mut [f32], start: f32, cutoff: Option<f32>, sweep: f32, low: bool) {
+        if let Some(cutoff) = cutoff {
+            let nyquist = self.sample_rate as f32 * 0.5;
+            self.cutoffs.clear();
+            for i in 0..out.len() {
+                let t = start + i as f32 / self.sample_rate as f32;
+                self.cutoffs.push((cutoff * 2f32.powf(sweep * t)).max(10.0).min(nyquist * 0.95));
+            }
+            let filter = if low { &mut self.lowpass } else { &mut self.highpass };
+            filter.process_with_frequency(out, 1, &self.cutoffs);
+        }
+    }
+}
+
+impl Generator for Sfx {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        let p = self.params;
+        let start = self.frame as f32 / self.sample_rate as f32;
+        let nyquist = self.sample_rate as f32 * 0.5;
+
+        let mut n = if self.finished { 0 } else { out.len() };
+        for i in 0..n {
+            let t = (self.frame) as f32 / self.sample_rate as f32;
+            let frequency = match p.frequency_at(t) {
+                Some(f) => f.min(nyquist * 0.95),
+                None => {
+                    n = i;
+                    break;
+                }
+            };
+            // Drawn once a sample, whether it's the tone, mixed in, or both.
+            let noise = if p.wave == Wave::Noise || p.noise > 0.0 { self.noise(frequency) } else { 0.0 };
+            let tone = if p.wave == Wave::Noise {
+                noise
+            } else {
+                if p.wave == Wave::Square {
+                    let duty = (p.duty + p.duty_sweep * t).max(0.05).min(0.95);
+                    self.osc.set_waveform(Waveform::Square { pulse_width: duty });
+                }
+                self.osc.set_frequency(frequency);
+                self.osc.next_sample()
+            };
+            out[i] = if p.noise > 0.0 {
+                tone * (1.0 - p.noise) + noise * p.noise
+            } else {
+                tone
+            };
+            self.frame += 1;
+        }
+        if n < out.len() {
+            self.finished = true;
+        }
+
+        self.levels.resize(n, 0.0);
+        let mut levels = std::mem::take(&mut self.levels);
+        let envelope_end = self.envelope.fill(&mut levels);
+        if envelope_end < n {
+            self.finished = true;
+            n = envelope_end;
+        }
+        for (x, level) in out[..n].iter_mut().zip(levels.iter()) {
+            *x *= level * p.gain;
+        }
+        self.levels = levels;
+
+        self.sweep(&mut out[..n], start, p.lowpass, p.lowpass_sweep, true);
+        self.sweep(&mut out[..n], start, p.highpass, p.highpass_sweep, false);
+        // Punch and resonance can overshoot; clip, like sfxr.
+        for x in out[..n].iter_mut() {
+            *x = x.max(-1.0).min(1.0);
+        }
+        for x in out[n..].iter_mut() {
+            *x = 0.0;
+        }
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::Capture;
+    use crate::wav::Sample;
+
+    fn samples(params: SfxParams) -> Vec<f32> {
+        let mut capture = Capture::new(8000, 1);
+        render(&mut Sfx::new(params, 8000), &mut capture, 256).unwrap();
+        capture.samples
+    }
+
+    #[test]
+    fn test_presets() {
+        for preset in Preset::ALL.iter() {
+            let params = preset.params();
+            assert_eq!(Preset::from_name(preset.name()), Some(*preset));
+            let a = samples(params);
+            assert_eq!(a, samples(params), "{} isn't deterministic", preset.name());
+            assert!(a.len() as f32 <= params.duration() * 8000.0 + 1.0);
+            let peak = a.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+            assert!(peak > 0.1 && peak <= 1.0, "{}: peak {}", preset.name(), peak);
+        }
+        assert_eq!(Preset::from_name("boing"), None);
+
+        // Noise mixed into noise is the same noise, held at the same rate.
+        let noise = SfxParams { wave: Wave::Noise, ..SfxParams::default() };
+        assert_eq!(samples(SfxParams { noise: 0.5, ..noise }), samples(noise));
+    }
+
+    #[test]
+    fn test_pitch() {
+        let params = SfxParams { slide: 1.0, vibrato_depth: 12.0, vibrato_speed: 1.0, ..SfxParams::default() };
+        assert_eq!(params.frequency_at(0.0), Some(440.0));
+        // An octave up from the slide, and an octave up from the top of the vibrato.
+        assert!((params.frequency_at(0.25).unwrap() - 440.0 * 2f32.powf(0.25) * 2.0).abs() < 0.01);
+
+        let params = SfxParams { arpeggio: 1.5, arpeggio_time: 0.1, ..SfxParams::default() };
+        assert_eq!(params.frequency_at(0.099), Some(440.0));
+        assert_eq!(params.frequency_at(0.1), Some(660.0));
+
+        // Sliding down out of range ends it early: just after 100Hz, from 400Hz at 2 octaves a second.
+        let params = SfxParams { frequency: 400.0, slide: -2.0, min_frequency: 100.0, sustain: 5.0, ..SfxParams::default() };
+        assert_eq!(params.frequency_at(1.01), None);
+        assert_eq!(samples(params).len(), 8001);
+        assert_eq!(samples(SfxParams::default()).len(), 2400);
+    }
+
+    #[test]
+    fn test_mutate() {
+        let jump = Preset::Jump.params();
+        assert_eq!(jump.mutate(7, 0.5), jump.mutate(7, 0.5));
+        assert_ne!(jump.mutate(7, 0.5), jump.mutate(8, 0.5));
+        assert_eq!(jump.mutate(7, 0.0), jump);
+        for seed in 0..50 {
+            let p = jump.mutate(seed, 1.0);
+            assert!(p.duty >= 0.05 && p.duty <= 0.95 && p.attack >= 0.0 && p.punch <= 1.0);
+            assert!(p.frequency >= 140.0 && p.frequency <= 560.0);
+            assert_eq!((p.wave, p.lowpass.is_some()), (jump.wave, false));
+        }
+        assert_eq!(samples(jump.mutate(3, 0.3)), samples(jump.mutate(3, 0.3)));
+    }
+
+    #[test]
+    fn test_write_wav() {
+        let path = std::env::temp_dir().join("openal_test_sfx.wav");
+        let frames = Preset::Pickup.params().write_wav(&path, 22050).unwrap();
+        let sample = Sample::load(&path).unwrap();
+        assert_eq!((sample.sample_rate, sample.channels, sample.frames()), (22050, 1, frames));
+        assert_eq!(frames, (Preset::Pickup.params().duration() * 22050.0).round() as usize);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,495 @@
+// Where the audio goes.
+//
+// Everything that makes sound writes interleaved f32 samples to a Sink, so the same
+// synthesis code can play through OpenAL, be rendered to a WAV file, captured into
+// memory for tests, or thrown away.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use alto::*;
+
+use crate::stream::Generator;
+
+#[derive(Debug)]
+pub enum SinkError {
+    Io(io::Error),
+    Alto(AltoError),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SinkError::Io(e) => write!(f, "{}", e),
+            SinkError::Alto(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SinkError {}
+
+impl From<io::Error> for SinkError {
+    fn from(e: io::Error) -> SinkError {
+        SinkError::Io(e)
+    }
+}
+
+impl From<AltoError> for SinkError {
+    fn from(e: AltoError) -> SinkError {
+        SinkError::Alto(e)
+    }
+}
+
+pub trait Sink {
+    fn sample_rate(&self) -> u32;
+
+    // 1 (mono) or 2 (stereo, left first).
+    fn channels(&self) -> usize;
+
+    /// Write interleaved samples, a whole number of frames. Real time sinks block
+    /// until there's room.
+    fn write(&mut self, samples: &[f32]) -> Result<(), SinkError>;
+
+    /// Flush everything written; real time sinks wait until it has played.
+    fn finish(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn pause(&mut self) {}
+
+    fn resume(&mut self) {}
+
+    /// How many times playback ran dry, waiting for samples.
+    fn underruns(&self) -> usize {
+        0
+    }
+}
+
+// Convert interleaved samples from one channel count to another: mono is copied to
+// both sides, and stereo averaged down to mono.
+pub fn convert(samples: &[f32], from: usize, to: usize, out: &mut Vec<f32>) {
+    out.clear();
+    match (from, to) {
+        (from, to) if from == to => out.extend_from_slice(samples),
+        (1, _) => {
+            for x in samples {
+                out.extend(std::iter::repeat(*x).take(to));
+            }
+        }
+        (2, 1) => out.extend(samples.chunks(2).map(|lr| (lr[0] + lr[1]) * 0.5)),
+        _ => panic!("can't convert {} channels to {}", from, to),
+    }
+}
+
+/// Play a generator into `sink` until it finishes, `block` frames at a time,
+/// converting between mono and stereo as needed. Returns the frames written.
+pub fn render<G: Generator + ?Sized, S: Sink + ?Sized>(generator: &mut G, sink: &mut S,
+                                                      block: usize) -> Result<usize, SinkError> {
+    let mut generated = vec![0.0; block * generator.channels()];
+    let mut converted = vec![];
+    let mut frames = 0;
+    loop {
+        let n = generator.generate(&mut generated);
+        convert(&generated[..n * generator.channels()], generator.channels(), sink.channels(), &mut converted);
+        sink.write(&converted)?;
+        frames += n;
+        if n < block {
+            break;
+        }
+    }
+    sink.finish()?;
+    Ok(frames)
+}
+
+/// Plays through the default OpenAL device, from a ring of streaming buffers.
+pub struct OpenAlSink {
+    _device: OutputDevice,
+    context: Context,
+    source: StreamingSource,
+    sample_rate: u32,
+    channels: usize,
+    buffer_frames: usize,
+    buffers: usize,
+    pending: Vec<f32>, // samples waiting for a buffer to fill.
+    started: bool,     // playing starts once the ring is first full.
+    paused: bool,
+    underruns: usize,
+}
+
+impl OpenAlSink {
+    /// `buffers` buffers of `buffer_frames` frames each; more is more latency, but
+    /// less likely to underrun.
+    pub fn new(sample_rate: u32, channels: usize, buffer_frames: usize,
+               buffers: usize) -> Result<OpenAlSink, SinkError> {
+        assert!(channels == 1 || channels == 2, "OpenAL plays mono or stereo");
+        let alto = Alto::load_default()?;
+        let device = alto.open(None)?;
+        let context = device.new_context(None)?;
+        let source = context.new_streaming_source()?;
+        Ok(OpenAlSink {
+            _device: device,
+            context,
+            source,
+            sample_rate,
+            channels,
+            buffer_frames,
+            buffers,
+            pending: Vec::with_capacity(buffer_frames * channels),
+            started: false,
+            paused: false,
+            underruns: 0,
+        })
+    }
+
+    fn poll_interval(&self) -> Duration {
+        Duration::from_micros(self.buffer_frames as u64 * 250_000 / self.sample_rate as u64)
+    }
+
+    // A new buffer holding `samples`, or refill `reuse` with them.
+    fn buffer(&self, samples: &[f32], reuse: Option<Buffer>) -> AltoResult<Buffer> {
+        let rate = self.sample_rate as i32;
+        if self.channels == 1 {
+            let frames: Vec<Mono<f32>> = samples.iter().map(|x| Mono { center: *x }).collect();
+            match reuse {
+                Some(mut buffer) => buffer.set_data(frames, rate).map(|_| buffer),
+                None => self.context.new_buffer(frames, rate),
+            }
+        } else {
+            let frames: Vec<Stereo<f32>> = samples.chunks(2)
+                .map(|lr| Stereo { left: lr[0], right: lr[1] })
+                .collect();
+            match reuse {
+                Some(mut buffer) => buffer.set_data(frames, rate).map(|_| buffer),
+                None => self.context.new_buffer(frames, rate),
+            }
+        }
+    }
+
+    // Queue the pending samples, waiting for a buffer to come back if the ring is full.
+    fn submit(&mut self) -> Result<(), SinkError> {
+        let reuse = if (self.source.buffers_queued() as usize) < self.buffers {
+            None
+        } else {
+            while self.source.buffers_processed() == 0 {
+                thread::sleep(self.poll_interval());
+            }
+            Some(self.source.unqueue_buffer()?)
+        };
+        let buffer = self.buffer(&self.pending, reuse)?;
+        self.pending.clear();
+        self.source.queue_buffer(buffer)?;
+
+        if self.paused {
+            return Ok(());
+        }
+        if self.started && self.source.state() != SourceState::Playing {
+            self.underruns += 1;
+            self.source.play();
+        } else if !self.started && self.source.buffers_queued() as usize == self.buffers {
+            self.started = true;
+            self.source.play();
+        }
+        Ok(())
+    }
+}
+
+impl Sink for OpenAlSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), SinkError> {
+        let full = self.buffer_frames * self.channels;
+        for x in samples {
+            self.pending.push(*x);
+            if self.pending.len() == full {
+                self.submit()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        if !self.pending.is_empty() {
+            self.submit()?;
+        }
+        self.started = true;
+        self.paused = false;
+        let unplayed = self.source.buffers_processed() < self.source.buffers_queued();
+        if unplayed && self.source.state() != SourceState::Playing {
+            self.source.play();
+        }
+        while self.source.state() == SourceState::Playing {
+            thread::sleep(self.poll_interval());
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) {
+        self.paused = true;
+        self.source.pause();
+    }
+
+    fn resume(&mut self) {
+        self.paused = false;
+        if self.started {
+            self.source.play();
+        }
+    }
+
+    fn underruns(&self) -> usize {
+        self.underruns
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WavFormat {
+    Pcm16,
+    Float32,
+}
+
+/// Writes a RIFF/WAVE file. The sizes in the header are filled in by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    sample_rate: u32,
+    channels: usize,
+    format: WavFormat,
+    data_start: u64, // offset of the data chunk's size field.
+    samples: u64,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &Path, sample_rate: u32, channels: usize,
+                  format: WavFormat) -> io::Result<WavWriter<BufWriter<File>>> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels, format)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: usize, format: WavFormat) -> io::Result<WavWriter<W>> {
+        let (tag, bytes): (u16, u16) = match format {
+            WavFormat::Pcm16 => (1, 2),
+            WavFormat::Float32 => (3, 4),
+        };
+        let block_align = bytes * channels as u16;
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?; // filled in by finish.
+        out.write_all(b"WAVE")?;
+
+        // Non-PCM formats have the extension size field, and a fact chunk.
+        out.write_all(b"fmt ")?;
+        out.write_all(&(if tag == 1 { 16u32 } else { 18 }).to_le_bytes())?;
+        out.write_all(&tag.to_le_bytes())?;
+        out.write_all(&(channels as u16).to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&(bytes * 8).to_le_bytes())?;
+        if tag != 1 {
+            out.write_all(&0u16.to_le_bytes())?;
+            out.write_all(b"fact")?;
+            out.write_all(&4u32.to_le_bytes())?;
+            out.write_all(&0u32.to_le_bytes())?; // frames, filled in by finish.
+        }
+
+        out.write_all(b"data")?;
+        let data_start = out.seek(SeekFrom::Current(0))?;
+        out.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter { out, sample_rate, channels, format, data_start, samples: 0 })
+    }
+
+    /// The underlying writer, once finished.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write + Seek> Sink for WavWriter<W> {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), SinkError> {
+        for x in samples {
+            match self.format {
+                WavFormat::Pcm16 => {
+                    let v = (x.max(-1.0).min(1.0) * 32767.0).round() as i16;
+                    self.out.write_all(&v.to_le_bytes())?;
+                }
+                WavFormat::Float32 => self.out.write_all(&x.to_bits().to_le_bytes())?,
+            }
+        }
+        self.samples += samples.len() as u64;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SinkError> {
+        let bytes = match self.format {
+            WavFormat::Pcm16 => 2,
+            WavFormat::Float32 => 4,
+        };
+        let data_size = (self.samples * bytes) as u32;
+        let end = self.out.seek(SeekFrom::Current(0))?;
+
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&((end - 8) as u32).to_le_bytes())?;
+        if self.format != WavFormat::Pcm16 {
+            // The fact chunk's frame count sits just before the data chunk.
+            self.out.seek(SeekFrom::Start(self.data_start - 8))?;
+            self.out.write_all(&((self.samples / self.channels as u64) as u32).to_le_bytes())?;
+        }
+        self.out.seek(SeekFrom::Start(self.data_start))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Keeps everything written, for checking in tests or further processing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>, // interleaved.
+}
+
+impl Capture {
+    pub fn new(sample_rate: u32, channels: usize) -> Capture {
+        Capture { sample_rate, channels, samples: vec![] }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    /// Samples of one channel.
+    pub fn channel(&self, channel: usize) -> Vec<f32> {
+        self.samples.iter().skip(channel).step_by(self.channels).cloned().collect()
+    }
+}
+
+impl Sink for Capture {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), SinkError> {
+        self.samples.extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Discards everything, counting the frames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NullSink {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub frames: usize,
+}
+
+impl NullSink {
+    pub fn new(sample_rate: u32, channels: usize) -> NullSink {
+        NullSink { sample_rate, channels, frames: 0 }
+    }
+}
+
+impl Sink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<(), SinkError> {
+        self.frames += samples.len() / self.channels;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oscillator::*;
+    use std::io::Cursor;
+
+    // The first `frames` frames of an oscillator.
+    fn tone(mut osc: Oscillator, frames: usize) -> impl Generator {
+        let mut remaining = frames;
+        move |out: &mut [f32]| {
+            let n = out.len().min(remaining);
+            osc.fill(&mut out[..n]);
+            remaining -= n;
+            n
+        }
+    }
+
+    #[test]
+    fn test_render_to_capture() {
+        let mut capture = Capture::new(8000, 2);
+        let frames = render(&mut tone(Oscillator::sine(1000.0, 8000), 1000), &mut capture, 64).unwrap();
+        assert_eq!((frames, capture.frames()), (1000, 1000));
+        assert_eq!(capture.channel(0), capture.channel(1));
+
+        // 8 samples per cycle, continuous across the blocks.
+        let left = capture.channel(0);
+        for (i, x) in left.iter().enumerate() {
+            let expected = (i as f32 * std::f32::consts::PI / 4.0).sin();
+            assert!((x - expected).abs() < 1e-3, "{}: {} vs {}", i, x, expected);
+        }
+
+        let mut null = NullSink::new(8000, 1);
+        render(&mut tone(Oscillator::sine(1000.0, 8000), 1000), &mut null, 300).unwrap();
+        assert_eq!(null.frames, 1000);
+    }
+
+    #[test]
+    fn test_wav_writer() {
+        let mut osc = Oscillator::square(100.0, 8000);
+        osc.set_amplitude(0.5);
+        let mut wav = WavWriter::new(Cursor::new(vec![]), 8000, 1, WavFormat::Pcm16).unwrap();
+        render(&mut tone(osc, 800), &mut wav, 256).unwrap();
+        let data = wav.into_inner().into_inner();
+
+        assert_eq!(data.len(), 44 + 1600);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([data[4], data[5], data[6], data[7]]), 36 + 1600);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes([data[24], data[25], data[26], data[27]]), 8000);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32::from_le_bytes([data[40], data[41], data[42], data[43]]), 1600);
+        // Half way through the high half of the first cycle.
+        assert_eq!(i16::from_le_bytes([data[44 + 40], data[45 + 40]]), 16384);
+
+        let mut wav = WavWriter::new(Cursor::new(vec![]), 8000, 2, WavFormat::Float32).unwrap();
+        wav.write(&[0.25, -0.25]).unwrap();
+        wav.finish().unwrap();
+        let data = wav.into_inner().into_inner();
+        assert_eq!(data.len(), 58 + 8);
+        assert_eq!(&data[38..42], b"fact");
+        assert_eq!(u32::from_le_bytes([data[46], data[47], data[48], data[49]]), 1);
+        assert_eq!(f32::from_le_bytes([data[58], data[59], data[60], data[61]]), 0.25);
+    }
+}
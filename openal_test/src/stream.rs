@@ -0,0 +1,264 @@
+// Continuous playback of generated sound.
+//
+// A background thread pulls blocks from a Generator and writes them to a Sink, so a
+// sound of any length plays without gaps. The Stream controls the thread through a
+// channel. The sink is opened on the thread too, so it needn't be Send.
+//
+// With an OpenAlSink, a ring of buffers is kept queued on a streaming source and
+// refilled as OpenAL reports them processed. If the source runs dry before a buffer
+// is refilled (eg. the generator is too slow) that's an underrun: it's counted, and
+// playback restarted.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::oscillator::Oscillator;
+use crate::sink::*;
+
+// Something that produces samples, one block at a time.
+pub trait Generator {
+    /// Fill `out` with the next frames, interleaved. Returns how many frames were
+    /// written; fewer than will fit means the sound has finished.
+    fn generate(&mut self, out: &mut [f32]) -> usize;
+
+    // 1 (mono) or 2 (stereo).
+    fn channels(&self) -> usize {
+        1
+    }
+}
+
+impl<F: FnMut(&mut [f32]) -> usize> Generator for F {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        self(out)
+    }
+}
+
+// So a generator can be changed while a Stream plays it.
+impl<G: Generator> Generator for Arc<Mutex<G>> {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        self.lock().unwrap().generate(out)
+    }
+
+    fn channels(&self) -> usize {
+        self.lock().unwrap().channels()
+    }
+}
+
+impl Generator for Oscillator {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        self.fill(out);
+        out.len()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StreamConfig {
+    pub sample_rate: u32,
+    pub buffer_frames: usize, // samples in each buffer, and in each block generated.
+    pub buffers: usize,       // buffers in the ring.
+}
+
+impl StreamConfig {
+    // ~93ms of latency at 44.1kHz.
+    pub fn new(sample_rate: u32) -> StreamConfig {
+        StreamConfig { sample_rate, buffer_frames: 1024, buffers: 4 }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Command {
+    Play,
+    Pause,
+    Stop,
+}
+
+// Shared between the Stream and its thread.
+#[derive(Default)]
+struct Status {
+    underruns: AtomicUsize,
+    frames: AtomicUsize, // generated so far.
+    finished: AtomicBool,
+}
+
+pub struct Stream {
+    commands: Sender<Command>,
+    status: Arc<Status>,
+    thread: Option<thread::JoinHandle<Result<(), SinkError>>>,
+}
+
+impl Stream {
+    /// Open the default OpenAL device, with as many channels as the generator has, and start playing `generator` on a background thread.
+    pub fn new<G: Generator + Send + 'static>(generator: G, config: StreamConfig) -> Result<Stream, SinkError> {
+        let channels = generator.channels();
+        Stream::with_sink(generator, config.buffer_frames, move || {
+            OpenAlSink::new(config.sample_rate, channels, config.buffer_frames, config.buffers)
+        })
+    }
+
+    /// Start playing `generator` on a background thread, into the sink made by `open`,
+    /// `block` frames at a time.
+    pub fn with_sink<G, S, F>(generator: G, block: usize, open: F) -> Result<Stream, SinkError>
+        where G: Generator + Send + 'static, S: Sink, F: FnOnce() -> Result<S, SinkError> + Send + 'static {
+        let (commands, receiver) = channel();
+        let (started, on_start) = channel();
+        let status = Arc::new(Status::default());
+
+        let thread_status = status.clone();
+        let thread = thread::spawn(move || {
+            let sink = match open() {
+                Ok(sink) => sink,
+                Err(e) => {
+                    let _ = started.send(Err(e));
+                    return Ok(());
+                }
+            };
+            let _ = started.send(Ok(()));
+            run(generator, sink, block, receiver, &thread_status)
+        });
+
+        // Wait for the sink to open, so failures show up here.
+        match on_start.recv().expect("stream thread panicked") {
+            Ok(()) => Ok(Stream { commands, status, thread: Some(thread) }),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn play(&self) {
+        let _ = self.commands.send(Command::Play);
+    }
+
+    pub fn pause(&self) {
+        let _ = self.commands.send(Command::Pause);
+    }
+
+    /// Stop straight away, and close the sink.
+    pub fn stop(mut self) -> Result<(), SinkError> {
+        let _ = self.commands.send(Command::Stop);
+        self.join()
+    }
+
+    /// Block until the generator has finished and everything it made has played. A
+    /// paused stream is resumed first, or it would never finish.
+    pub fn wait(mut self) -> Result<(), SinkError> {
+        self.play();
+        self.join()
+    }
+
+    /// How many times the sink ran dry.
+    pub fn underruns(&self) -> usize {
+        self.status.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Samples generated so far.
+    pub fn frames(&self) -> usize {
+        self.status.frames.load(Ordering::Relaxed)
+    }
+
+    /// Has the generator finished? (Its last blocks may still be playing.)
+    pub fn is_finished(&self) -> bool {
+        self.status.finished.load(Ordering::Relaxed)
+    }
+
+    fn join(&mut self) -> Result<(), SinkError> {
+        match self.thread.take() {
+            Some(thread) => thread.join().expect("stream thread panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+        let _ = self.join();
+    }
+}
+
+fn run<G: Generator, S: Sink>(mut generator: G, mut sink: S, block: usize, commands: Receiver<Command>,
+                              status: &Status) -> Result<(), SinkError> {
+    let mut generated = vec![0.0; block * generator.channels()];
+    let mut converted = Vec::with_capacity(block * sink.channels());
+    let mut paused = false;
+    loop {
+        // While paused, wait for the next command.
+        let command = if paused {
+            commands.recv().unwrap_or(Command::Stop)
+        } else {
+            match commands.try_recv() {
+                Ok(command) => command,
+                Err(TryRecvError::Empty) => Command::Play,
+                Err(TryRecvError::Disconnected) => Command::Stop,
+            }
+        };
+        match command {
+            Command::Play if paused => {
+                paused = false;
+                sink.resume();
+            }
+            Command::Play => {}
+            Command::Pause => {
+                paused = true;
+                sink.pause();
+                continue;
+            }
+            Command::Stop => return Ok(()),
+        }
+
+        let n = generator.generate(&mut generated);
+        convert(&generated[..n * generator.channels()], generator.channels(), sink.channels(), &mut converted);
+        sink.write(&converted)?;
+        status.frames.fetch_add(n, Ordering::Relaxed);
+        status.underruns.store(sink.underruns(), Ordering::Relaxed);
+        if n < block {
+            break;
+        }
+    }
+
+    status.finished.store(true, Ordering::Relaxed);
+    sink.finish()?;
+    status.underruns.store(sink.underruns(), Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_to_null_sink() {
+        let mut remaining = 10000;
+        let generator = move |out: &mut [f32]| {
+            let n = out.len().min(remaining);
+            remaining -= n;
+            n
+        };
+        let stream = Stream::with_sink(generator, 256, || Ok(NullSink::new(8000, 2))).unwrap();
+        while !stream.is_finished() {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(stream.frames(), 10000);
+        assert_eq!(stream.underruns(), 0);
+        stream.wait().unwrap();
+
+        // Waiting on a paused stream plays it to the end. The generator's slowed down so
+        // the pause lands before it's done.
+        let mut remaining = 10000;
+        let generator = move |out: &mut [f32]| {
+            thread::sleep(std::time::Duration::from_millis(1));
+            let n = out.len().min(remaining);
+            remaining -= n;
+            n
+        };
+        let stream = Stream::with_sink(generator, 256, || Ok(NullSink::new(8000, 2))).unwrap();
+        stream.pause();
+        stream.wait().unwrap();
+
+        // Failing to open is reported straight away.
+        let error = Stream::with_sink(Oscillator::sine(440.0, 8000), 256, || -> Result<NullSink, SinkError> {
+            Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no device").into())
+        });
+        assert!(error.is_err());
+    }
+}
@@ -0,0 +1,305 @@
+// Reading RIFF/WAVE files.
+//
+// Handles PCM at 8, 16, 24 and 32 bits, and 32 bit float, in mono or stereo, including
+// WAVE_FORMAT_EXTENSIBLE files of those. Samples are converted to f32 in -1..1 on load,
+// whatever they were stored as. Loop points in a `smpl` chunk are kept.
+//
+// The file is read whole; unknown chunks are skipped.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use alto::*;
+
+#[derive(Debug)]
+pub enum WavError {
+    Io(io::Error),
+    NotRiff,
+    NotWave,
+    MissingChunk(&'static str),
+    // A chunk (named) runs past the end of the file.
+    Truncated(String),
+    // The fmt chunk makes no sense, eg. zero channels.
+    BadFormat(String),
+    // Well formed, but not something we read.
+    Unsupported(String),
+    Alto(AltoError),
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WavError::Io(e) => write!(f, "{}", e),
+            WavError::NotRiff => write!(f, "not a RIFF file"),
+            WavError::NotWave => write!(f, "RIFF file is not WAVE"),
+            WavError::MissingChunk(name) => write!(f, "no '{}' chunk", name),
+            WavError::Truncated(name) => write!(f, "'{}' chunk runs past the end of the file", name),
+            WavError::BadFormat(s) => write!(f, "bad format: {}", s),
+            WavError::Unsupported(s) => write!(f, "unsupported: {}", s),
+            WavError::Alto(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for WavError {}
+
+impl From<io::Error> for WavError {
+    fn from(e: io::Error) -> WavError {
+        WavError::Io(e)
+    }
+}
+
+impl From<AltoError> for WavError {
+    fn from(e: AltoError) -> WavError {
+        WavError::Alto(e)
+    }
+}
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// A decoded sound.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>, // interleaved.
+    // First frame of the loop, and the frame after its last; from the smpl chunk.
+    pub loop_points: Option<(usize, usize)>,
+}
+
+fn u16_at(data: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([data[i], data[i + 1]])
+}
+
+fn u32_at(data: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]])
+}
+
+// The chunks after the RIFF header, as (id, contents).
+fn chunks(data: &[u8]) -> Result<Vec<(String, &[u8])>, WavError> {
+    let mut chunks = vec![];
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = String::from_utf8_lossy(&data[pos..pos + 4]).into_owned();
+        let size = u32_at(data, pos + 4) as usize;
+        let start = pos + 8;
+        if size > data.len() - start {
+            return Err(WavError::Truncated(id));
+        }
+        chunks.push((id, &data[start..start + size]));
+        pos = start + size + (size & 1); // chunks are padded to an even length.
+    }
+    Ok(chunks)
+}
+
+impl Sample {
+    pub fn new(sample_rate: u32, channels: usize, samples: Vec<f32>) -> Sample {
+        Sample { sample_rate, channels, samples, loop_points: None }
+    }
+
+    pub fn load(path: &Path) -> Result<Sample, WavError> {
+        Sample::read(&std::fs::read(path)?)
+    }
+
+    /// Decode a whole WAV file.
+    pub fn read(data: &[u8]) -> Result<Sample, WavError> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" {
+            return Err(WavError::NotRiff);
+        }
+        if &data[8..12] != b"WAVE" {
+            return Err(WavError::NotWave);
+        }
+        let chunks = chunks(data)?;
+        let find = |name: &'static str| {
+            chunks.iter().find(|c| c.0 == name).map(|c| c.1).ok_or(WavError::MissingChunk(name))
+        };
+
+        let fmt = find("fmt ")?;
+        if fmt.len() < 16 {
+            return Err(WavError::BadFormat(format!("fmt chunk is {} bytes, expected at least 16", fmt.len())));
+        }
+        let mut tag = u16_at(fmt, 0);
+        let channels = u16_at(fmt, 2) as usize;
+        let sample_rate = u32_at(fmt, 4);
+        let block_align = u16_at(fmt, 12) as usize;
+        let bits = u16_at(fmt, 14);
+        if tag == FORMAT_EXTENSIBLE {
+            // The real format is the first two bytes of the sub-format GUID.
+            if fmt.len() < 40 {
+                return Err(WavError::BadFormat("extensible fmt chunk is too short".to_string()));
+            }
+            tag = u16_at(fmt, 24);
+        }
+
+        if channels == 0 || sample_rate == 0 {
+            return Err(WavError::BadFormat(format!("{} channels at {}Hz", channels, sample_rate)));
+        }
+        if channels > 2 {
+            return Err(WavError::Unsupported(format!("{} channels", channels)));
+        }
+        match (tag, bits) {
+            (FORMAT_PCM, 8) | (FORMAT_PCM, 16) | (FORMAT_PCM, 24) | (FORMAT_PCM, 32) | (FORMAT_FLOAT, 32) => {}
+            (FORMAT_PCM, _) | (FORMAT_FLOAT, _) => {
+                return Err(WavError::Unsupported(format!("{} bit samples", bits)));
+            }
+            _ => return Err(WavError::Unsupported(format!("format tag {:#x}", tag))),
+        }
+        let bytes = bits as usize / 8;
+        if block_align != bytes * channels {
+            return Err(WavError::BadFormat(format!("block align {} for {} channels of {} bits",
+                                                   block_align, channels, bits)));
+        }
+
+        // A trailing partial frame is dropped.
+        let data = find("data")?;
+        let frames = data.len() / block_align;
+        let samples = data[..frames * block_align].chunks(bytes).map(|b| match (tag, bits) {
+            (FORMAT_FLOAT, _) => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            (_, 8) => (b[0] as f32 - 128.0) / 128.0,
+            (_, 16) => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+            // Shifted up to the top of an i32, to sign extend.
+            (_, 24) => i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2_147_483_648.0,
+            _ => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0,
+        }).collect();
+
+        // The first loop in the smpl chunk, if there is one. Its end is inclusive.
+        let loop_points = match find("smpl") {
+            Ok(smpl) if smpl.len() >= 60 && u32_at(smpl, 28) > 0 => {
+                let (start, end) = (u32_at(smpl, 44) as usize, u32_at(smpl, 48) as usize + 1);
+                if start >= end || end > frames {
+                    return Err(WavError::BadFormat(format!("loop {}..{} outside of {} frames", start, end, frames)));
+                }
+                Some((start, end))
+            }
+            _ => None,
+        };
+
+        Ok(Sample { sample_rate, channels, samples, loop_points })
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.frames() as f32 / self.sample_rate as f32
+    }
+
+    /// One frame, as (left, right); mono samples give the same on both sides.
+    pub fn frame(&self, i: usize) -> (f32, f32) {
+        if self.channels == 1 {
+            (self.samples[i], self.samples[i])
+        } else {
+            (self.samples[i * 2], self.samples[i * 2 + 1])
+        }
+    }
+
+    /// An OpenAL buffer holding the sample, as 16 bit PCM, which every implementation
+    /// can play.
+    pub fn to_buffer(&self, context: &Context) -> AltoResult<Buffer> {
+        let pcm: Vec<i16> = self.samples.iter().map(|x| (x.max(-1.0).min(1.0) * 32767.0).round() as i16).collect();
+        let rate = self.sample_rate as i32;
+        if self.channels == 1 {
+            let frames: Vec<Mono<i16>> = pcm.iter().map(|x| Mono { center: *x }).collect();
+            context.new_buffer(frames, rate)
+        } else {
+            let frames: Vec<Stereo<i16>> = pcm.chunks(2).map(|lr| Stereo { left: lr[0], right: lr[1] }).collect();
+            context.new_buffer(frames, rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::*;
+    use std::io::Cursor;
+
+    // A WAV file with the given fmt fields and data, and any extra chunks.
+    fn wav(tag: u16, channels: u16, bits: u16, data: &[u8], extra: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut out = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        out.extend_from_slice(&16u32.to_le_bytes());
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&channels.to_le_bytes());
+        out.extend_from_slice(&8000u32.to_le_bytes());
+        out.extend_from_slice(&(8000 * block_align as u32).to_le_bytes());
+        out.extend_from_slice(&block_align.to_le_bytes());
+        out.extend_from_slice(&bits.to_le_bytes());
+        out.extend_from_slice(extra);
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        let size = out.len() as u32 - 8;
+        out[4..8].copy_from_slice(&size.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn test_read_formats() {
+        let sample = Sample::read(&wav(1, 1, 8, &[0, 128, 255, 192], &[])).unwrap();
+        assert_eq!((sample.sample_rate, sample.channels, sample.frames()), (8000, 1, 4));
+        assert_eq!(sample.samples, vec![-1.0, 0.0, 127.0 / 128.0, 0.5]);
+
+        // Stereo 24 bit, with an odd sized chunk (and its padding) before the data.
+        let data = [0x00, 0x00, 0xc0, 0x00, 0x00, 0x40];
+        let sample = Sample::read(&wav(1, 2, 24, &data, b"junk\x03\0\0\0abc\0")).unwrap();
+        assert_eq!(sample.frame(0), (-0.5, 0.5));
+
+        let data: Vec<u8> = [0.25f32, -0.75].iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+        assert_eq!(Sample::read(&wav(3, 1, 32, &data, &[])).unwrap().samples, vec![0.25, -0.75]);
+
+        // Round trip through WavWriter.
+        for format in [WavFormat::Pcm16, WavFormat::Float32].iter() {
+            let mut writer = WavWriter::new(Cursor::new(vec![]), 22050, 2, *format).unwrap();
+            writer.write(&[0.5, -0.25, 0.125, 0.0]).unwrap();
+            writer.finish().unwrap();
+            let sample = Sample::read(&writer.into_inner().into_inner()).unwrap();
+            assert_eq!((sample.sample_rate, sample.channels), (22050, 2));
+            for (x, y) in sample.samples.iter().zip([0.5, -0.25, 0.125, 0.0].iter()) {
+                assert!((x - y).abs() < 1e-4, "{:?}: {} vs {}", format, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_loop_points() {
+        let mut smpl = b"smpl".to_vec();
+        smpl.extend_from_slice(&60u32.to_le_bytes());
+        let mut fields = [0u32; 15];
+        fields[7] = 1; // loop count.
+        fields[11] = 2; // start.
+        fields[12] = 5; // end, inclusive.
+        smpl.extend(fields.iter().flat_map(|x| x.to_le_bytes().to_vec()));
+        let sample = Sample::read(&wav(1, 1, 16, &[0; 16], &smpl)).unwrap();
+        assert_eq!(sample.loop_points, Some((2, 6)));
+
+        fields[12] = 8;
+        let mut smpl = b"smpl".to_vec();
+        smpl.extend_from_slice(&60u32.to_le_bytes());
+        smpl.extend(fields.iter().flat_map(|x| x.to_le_bytes().to_vec()));
+        assert!(Sample::read(&wav(1, 1, 16, &[0; 16], &smpl)).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |data: &[u8]| Sample::read(data).unwrap_err().to_string();
+        assert_eq!(error(b"RIFX"), "not a RIFF file");
+        assert_eq!(error(b"RIFF\0\0\0\0AVI "), "RIFF file is not WAVE");
+        assert_eq!(error(b"RIFF\0\0\0\0WAVE"), "no 'fmt ' chunk");
+        assert_eq!(error(&wav(1, 1, 12, &[0; 4], &[])), "unsupported: 12 bit samples");
+        assert_eq!(error(&wav(2, 1, 4, &[0; 4], &[])), "unsupported: format tag 0x2");
+        assert_eq!(error(&wav(1, 6, 16, &[0; 12], &[])), "unsupported: 6 channels");
+        assert_eq!(error(&wav(1, 0, 16, &[0; 4], &[])), "bad format: 0 channels at 8000Hz");
+
+        let mut data = wav(1, 1, 16, &[0; 8], &[]);
+        data[32] = 3; // block align.
+        assert_eq!(error(&data), "bad format: block align 3 for 1 channels of 16 bits");
+        data.truncate(data.len() - 2);
+        assert_eq!(error(&data), "'data' chunk runs past the end of the file");
+    }
+}
@@ -0,0 +1,235 @@
+// Wavetable synthesis.
+//
+// A Wavetable is a row of single-cycle waveforms, all resampled to TABLE_SIZE. The
+// oscillator reads through a cycle with linear interpolation, and its position picks
+// a point along the row: between two tables, it crossfades, so sweeping the position
+// morphs smoothly from one waveform to the next.
+//
+// Wavetable files are WAVs holding the cycles end to end, usually 2048 frames each (as
+// Serum and others save them). Stereo is mixed to mono.
+//
+// There's no band-limiting: tables with strong high harmonics, played high, alias.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::oscillator::{Oscillator, Waveform};
+use crate::stream::Generator;
+use crate::wav::{Sample, WavError};
+
+pub const TABLE_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wavetable {
+    tables: Vec<Vec<f32>>,
+}
+
+// Linear interpolation in a cycle, `phase` from 0 to 1.
+fn lookup(table: &[f32], phase: f32) -> f32 {
+    let x = phase * table.len() as f32;
+    let i = x as usize % table.len();
+    let frac = x - x.floor();
+    table[i] + (table[(i + 1) % table.len()] - table[i]) * frac
+}
+
+impl Wavetable {
+    /// Cycles of any length; each is resampled to TABLE_SIZE. Empty ones are silent.
+    pub fn new(cycles: Vec<Vec<f32>>) -> Wavetable {
+        let tables = cycles.into_iter().map(|cycle| {
+            if cycle.len() == TABLE_SIZE {
+                cycle
+            } else if cycle.is_empty() {
+                vec![0.0; TABLE_SIZE]
+            } else {
+                (0..TABLE_SIZE).map(|i| lookup(&cycle, i as f32 / TABLE_SIZE as f32)).collect()
+            }
+        }).collect();
+        Wavetable { tables }
+    }
+
+    /// One cycle of each of the basic waveforms; noise gives a fixed random cycle.
+    pub fn from_waveforms(waveforms: &[Waveform]) -> Wavetable {
+        Wavetable::new(waveforms.iter().map(|w| {
+            Oscillator::new(*w, 1.0, TABLE_SIZE as u32).take(TABLE_SIZE).collect()
+        }).collect())
+    }
+
+    /// Split a sample into cycles of `cycle` frames; a partial one at the end is dropped.
+    pub fn from_sample(sample: &Sample, cycle: usize) -> Wavetable {
+        let mono: Vec<f32> = (0..sample.frames()).map(|i| {
+            let (l, r) = sample.frame(i);
+            (l + r) * 0.5
+        }).collect();
+        Wavetable::new(mono.chunks_exact(cycle.max(1)).map(|c| c.to_vec()).collect())
+    }
+
+    pub fn load(path: &Path, cycle: usize) -> Result<Wavetable, WavError> {
+        let table = Wavetable::from_sample(&Sample::load(path)?, cycle);
+        if table.is_empty() {
+            return Err(WavError::BadFormat(format!("shorter than one cycle of {} frames", cycle)));
+        }
+        Ok(table)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// The value at `phase` through the cycle, `position` of the way along the tables,
+    /// both 0 to 1.
+    pub fn value(&self, phase: f32, position: f32) -> f32 {
+        if self.tables.is_empty() {
+            return 0.0;
+        }
+        let x = position.max(0.0).min(1.0) * (self.tables.len() - 1) as f32;
+        let i = (x as usize).min(self.tables.len() - 1);
+        let a = lookup(&self.tables[i], phase);
+        match self.tables.get(i + 1) {
+            Some(next) if x > i as f32 => a + (lookup(next, phase) - a) * (x - i as f32),
+            _ => a,
+        }
+    }
+}
+
+/// Plays a Wavetable at a frequency, in mono, forever. The tables are shared, so many
+/// voices can play one.
+#[derive(Debug, Clone)]
+pub struct WavetableOscillator {
+    table: Arc<Wavetable>,
+    frequency: f32,
+    sample_rate: u32,
+    phase: f32,
+    position: f32,
+    sweep: f32, // position per sample.
+    amplitude: f32,
+}
+
+impl WavetableOscillator {
+    pub fn new(table: Arc<Wavetable>, frequency: f32, sample_rate: u32) -> WavetableOscillator {
+        WavetableOscillator { table, frequency, sample_rate, phase: 0.0, position: 0.0, sweep: 0.0, amplitude: 1.0 }
+    }
+
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+
+    pub fn set_amplitude(&mut self, amplitude: f32) {
+        self.amplitude = amplitude;
+    }
+
+    pub fn position(&self) -> f32 {
+        self.position
+    }
+
+    /// 0 for the first table, 1 for the last.
+    pub fn set_position(&mut self, position: f32) {
+        self.position = position.max(0.0).min(1.0);
+    }
+
+    /// Move the position by this much a second, stopping at either end; eg. 0.5 morphs
+    /// through the whole row in two seconds.
+    pub fn set_sweep(&mut self, per_second: f32) {
+        self.sweep = per_second / self.sample_rate as f32;
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let value = self.table.value(self.phase, self.position);
+        self.phase += (self.frequency / self.sample_rate as f32).min(0.5);
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        }
+        if self.sweep != 0.0 {
+            self.set_position(self.position + self.sweep);
+        }
+        value * self.amplitude
+    }
+}
+
+impl Generator for WavetableOscillator {
+    fn generate(&mut self, out: &mut [f32]) -> usize {
+        for x in out.iter_mut() {
+            *x = self.next_sample();
+        }
+        out.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Sink, WavFormat, WavWriter};
+
+    #[test]
+    fn test_tables() {
+        let table = Wavetable::from_waveforms(&[Waveform::Sine, Waveform::Triangle]);
+        assert_eq!(table.len(), 2);
+        assert!((table.value(0.25, 0.0) - 1.0).abs() < 1e-4);
+        assert!((table.value(0.125, 0.0) - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        assert!((table.value(0.125, 1.0) + 0.5).abs() < 1e-3);
+        // Halfway: half of each.
+        assert!((table.value(0.125, 0.5) - 0.1036).abs() < 1e-3);
+
+        // Short cycles are stretched to fit.
+        let square = Wavetable::new(vec![vec![1.0, 1.0, -1.0, -1.0], vec![]]);
+        assert_eq!(square.value(0.1, 0.0), 1.0);
+        assert_eq!(square.value(0.6, 0.0), -1.0);
+        assert_eq!(square.value(0.1, 1.0), 0.0);
+        assert_eq!(Wavetable::new(vec![]).value(0.5, 0.5), 0.0);
+    }
+
+    #[test]
+    fn test_oscillator() {
+        let table = Arc::new(Wavetable::from_waveforms(&[Waveform::Sine, Waveform::Saw]));
+        let mut osc = WavetableOscillator::new(table, 100.0, 8000);
+        let mut out = vec![0.0; 80];
+        osc.generate(&mut out);
+        for (i, x) in out.iter().enumerate() {
+            let expected = (2.0 * std::f32::consts::PI * i as f32 / 80.0).sin();
+            assert!((x - expected).abs() < 1e-3);
+        }
+
+        // Sweeping morphs along the row, and stops at the end.
+        osc.set_sweep(2.0);
+        let mut out = vec![0.0; 2000];
+        osc.generate(&mut out);
+        assert!((osc.position() - 0.5).abs() < 1e-3);
+        osc.generate(&mut out);
+        osc.generate(&mut out);
+        assert_eq!(osc.position(), 1.0);
+    }
+
+    #[test]
+    fn test_load() {
+        // Two cycles of 64 frames, a sine then a square, in stereo.
+        let mut cycles = vec![];
+        for i in 0..64 {
+            let x = (2.0 * std::f32::consts::PI * i as f32 / 64.0).sin();
+            cycles.push(x);
+            cycles.push(x);
+        }
+        for i in 0..64 {
+            let x = if i < 32 { 0.5 } else { -0.5 };
+            cycles.push(x);
+            cycles.push(x);
+        }
+        let path = std::env::temp_dir().join("openal_test_wavetable.wav");
+        let mut wav = WavWriter::create(&path, 44100, 2, WavFormat::Float32).unwrap();
+        wav.write(&cycles).unwrap();
+        wav.finish().unwrap();
+        drop(wav);
+
+        let table = Wavetable::load(&path, 64).unwrap();
+        assert_eq!(table.len(), 2);
+        assert!((table.value(0.25, 0.0) - 1.0).abs() < 1e-3);
+        assert_eq!(table.value(0.25, 1.0), 0.5);
+        match Wavetable::load(&path, 4096) {
+            Err(WavError::BadFormat(_)) => {}
+            other => panic!("{:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+// Texture atlas builder: packs a directory of PNG sprites into pages,
+// and writes a manifest of where each sprite ended up.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::packer::*;
+
+#[derive(Debug)]
+pub enum AtlasError {
+    Io(io::Error),
+    Decode(PathBuf, String),
+    Pack(PackError),
+}
+
+impl fmt::Display for AtlasError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtlasError::Io(e) => write!(f, "{}", e),
+            AtlasError::Decode(path, e) => write!(f, "{}: {}", path.display(), e),
+            AtlasError::Pack(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for AtlasError {}
+
+impl From<io::Error> for AtlasError {
+    fn from(e: io::Error) -> AtlasError {
+        AtlasError::Io(e)
+    }
+}
+
+impl From<PackError> for AtlasError {
+    fn from(e: PackError) -> AtlasError {
+        AtlasError::Pack(e)
+    }
+}
+
+// An 8 bit RGBA image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: u32, height: u32) -> Image {
+        Image { width, height, pixels: vec![0; (width * height * 4) as usize] }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = ((x + y * self.width) * 4) as usize;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, rgba: [u8; 4]) {
+        let i = ((x + y * self.width) * 4) as usize;
+        self.pixels[i..i + 4].copy_from_slice(&rgba);
+    }
+
+    pub fn load_png(path: &Path) -> Result<Image, AtlasError> {
+        let decode_err = |e: png::DecodingError| AtlasError::Decode(path.to_owned(), e.to_string());
+
+        let mut decoder = png::Decoder::new(File::open(path)?);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info().map_err(decode_err)?;
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).map_err(decode_err)?;
+
+        let channels = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            png::ColorType::Indexed => {
+                return Err(AtlasError::Decode(path.to_owned(), "unexpanded palette".to_string()));
+            }
+        };
+
+        let mut image = Image::new(info.width, info.height);
+        for (i, px) in data.chunks(channels).enumerate() {
+            let rgba = match channels {
+                1 => [px[0], px[0], px[0], 255],
+                2 => [px[0], px[0], px[0], px[1]],
+                3 => [px[0], px[1], px[2], 255],
+                _ => [px[0], px[1], px[2], px[3]],
+            };
+            image.pixels[i * 4..i * 4 + 4].copy_from_slice(&rgba);
+        }
+        Ok(image)
+    }
+
+    pub fn write_png(&self, path: &Path) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), self.width, self.height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+
+    // Copy `src` to (x, y). If rotated, `src` is turned 90 degrees clockwise.
+    pub fn blit(&mut self, src: &Image, x: u32, y: u32, rotated: bool) {
+        for sy in 0..src.height {
+            for sx in 0..src.width {
+                let (dx, dy) = if rotated { (src.height - 1 - sy, sx) } else { (sx, sy) };
+                self.set_pixel(x + dx, y + dy, src.pixel(sx, sy));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sprite {
+    pub name: String,
+    pub image: Image,
+}
+
+/// Load every .png in `dir`, named by file stem, sorted by name.
+pub fn load_sprites(dir: &Path) -> Result<Vec<Sprite>, AtlasError> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |e| e.eq_ignore_ascii_case("png")) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    paths.iter()
+        .map(|path| Ok(Sprite {
+            name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+            image: Image::load_png(path)?,
+        }))
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct AtlasEntry {
+    pub name: String,
+    pub page: usize,
+    // Pixel rectangle in the page.
+    pub rect: Rect,
+    // Sprite is stored turned 90 degrees clockwise.
+    pub rotated: bool,
+    // (u0, v0, u1, v1) in the page, with v down.
+    pub uv: [f32; 4],
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ManifestFormat {
+    Json,
+    Ron,
+}
+
+pub struct Atlas {
+    pub pages: Vec<Image>,
+    pub entries: Vec<AtlasEntry>,
+    pub efficiency: f32,
+}
+
+pub fn build_atlas(sprites: &[Sprite], config: &PackerConfig) -> Result<Atlas, AtlasError> {
+    let sizes: Vec<(u32, u32)> = sprites.iter().map(|s| (s.image.width, s.image.height)).collect();
+    let packing = pack(&sizes, config)?;
+
+    let mut pages: Vec<Image> = packing.pages.iter().map(|p| Image::new(p.0, p.1)).collect();
+    let mut entries = Vec::with_capacity(sprites.len());
+    for (sprite, p) in sprites.iter().zip(packing.placements.iter()) {
+        let page = &mut pages[p.page];
+        page.blit(&sprite.image, p.rect.x, p.rect.y, p.rotated);
+        entries.push(AtlasEntry {
+            name: sprite.name.clone(),
+            page: p.page,
+            rect: p.rect,
+            rotated: p.rotated,
+            uv: [p.rect.x as f32 / page.width as f32,
+                 p.rect.y as f32 / page.height as f32,
+                 p.rect.right() as f32 / page.width as f32,
+                 p.rect.bottom() as f32 / page.height as f32],
+        });
+    }
+
+    Ok(Atlas { pages, entries, efficiency: packing.efficiency() })
+}
+
+impl Atlas {
+    fn page_path(&self, stem: &Path, page: usize) -> PathBuf {
+        if self.pages.len() == 1 {
+            with_suffix(stem, ".png")
+        } else {
+            with_suffix(stem, &format!("_{}.png", page))
+        }
+    }
+
+    /// Write the pages as `<stem>.png` (or `<stem>_<page>.png` if there are several),
+    /// and the manifest as `<stem>.json` or `<stem>.ron`.
+    pub fn write(&self, stem: &Path, format: ManifestFormat) -> io::Result<Vec<PathBuf>> {
+        let mut written = vec![];
+        for (i, page) in self.pages.iter().enumerate() {
+            let path = self.page_path(stem, i);
+            page.write_png(&path)?;
+            written.push(path);
+        }
+
+        let path = with_suffix(stem, match format {
+            ManifestFormat::Json => ".json",
+            ManifestFormat::Ron => ".ron",
+        });
+        let mut out = BufWriter::new(File::create(&path)?);
+        match format {
+            ManifestFormat::Json => self.write_json(&mut out, stem)?,
+            ManifestFormat::Ron => self.write_ron(&mut out, stem)?,
+        }
+        out.flush()?;
+        written.push(path);
+        Ok(written)
+    }
+
+    fn page_names(&self, stem: &Path) -> Vec<String> {
+        (0..self.pages.len())
+            .map(|i| self.page_path(stem, i).file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn write_json(&self, out: &mut dyn Write, stem: &Path) -> io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"efficiency\": {},", self.efficiency)?;
+        writeln!(out, "  \"pages\": [")?;
+        let names = self.page_names(stem);
+        for (i, (name, page)) in names.iter().zip(self.pages.iter()).enumerate() {
+            let comma = if i + 1 < self.pages.len() { "," } else { "" };
+            writeln!(out, "    {{\"file\": \"{}\", \"width\": {}, \"height\": {}}}{}",
+                     escape(name), page.width, page.height, comma)?;
+        }
+        writeln!(out, "  ],")?;
+        writeln!(out, "  \"sprites\": [")?;
+        for (i, e) in self.entries.iter().enumerate() {
+            let comma = if i + 1 < self.entries.len() { "," } else { "" };
+            writeln!(out, "    {{\"name\": \"{}\", \"page\": {}, \"x\": {}, \"y\": {}, \"w\": {}, \"h\": {}, \
+                           \"rotated\": {}, \"uv\": [{}, {}, {}, {}]}}{}",
+                     escape(&e.name), e.page, e.rect.x, e.rect.y, e.rect.w, e.rect.h,
+                     e.rotated, e.uv[0], e.uv[1], e.uv[2], e.uv[3], comma)?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")
+    }
+
+    fn write_ron(&self, out: &mut dyn Write, stem: &Path) -> io::Result<()> {
+        writeln!(out, "(")?;
+        writeln!(out, "    efficiency: {},", self.efficiency)?;
+        writeln!(out, "    pages: [")?;
+        let names = self.page_names(stem);
+        for (name, page) in names.iter().zip(self.pages.iter()) {
+            writeln!(out, "        (file: \"{}\", width: {}, height: {}),",
+                     escape(name), page.width, page.height)?;
+        }
+        writeln!(out, "    ],")?;
+        writeln!(out, "    sprites: [")?;
+        for e in &self.entries {
+            writeln!(out, "        (name: \"{}\", page: {}, x: {}, y: {}, w: {}, h: {}, \
+                           rotated: {}, uv: ({}, {}, {}, {})),",
+                     escape(&e.name), e.page, e.rect.x, e.rect.y, e.rect.w, e.rect.h,
+                     e.rotated, e.uv[0], e.uv[1], e.uv[2], e.uv[3])?;
+        }
+        writeln!(out, "    ],")?;
+        writeln!(out, ")")
+    }
+}
+
+// The whole stem is kept, dots and all: with_extension would turn ui.v2 into ui.png.
+fn with_suffix(stem: &Path, suffix: &str) -> PathBuf {
+    let name = format!("{}{}", stem.file_name().unwrap().to_string_lossy(), suffix);
+    stem.with_file_name(name)
+}
+
+// For JSON and RON strings alike.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: u32, height: u32, rgba: [u8; 4]) -> Image {
+        let mut image = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                image.set_pixel(x, y, rgba);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn test_blit_rotated() {
+        let mut src = Image::new(2, 1);
+        src.set_pixel(0, 0, [1, 1, 1, 1]);
+        src.set_pixel(1, 0, [2, 2, 2, 2]);
+        let mut dst = Image::new(1, 2);
+        dst.blit(&src, 0, 0, true);
+        assert_eq!(dst.pixel(0, 0), [1, 1, 1, 1]);
+        assert_eq!(dst.pixel(0, 1), [2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_build_and_write_atlas() {
+        let dir = std::env::temp_dir().join("packing2d_test_atlas");
+        std::fs::create_dir_all(&dir).unwrap();
+        let colours = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 128]];
+        for (i, size) in [(16, 8), (8, 8), (4, 12)].iter().enumerate() {
+            solid(size.0, size.1, colours[i]).write_png(&dir.join(format!("sprite{}.png", i))).unwrap();
+        }
+
+        let sprites = load_sprites(&dir).unwrap();
+        assert_eq!(sprites.len(), 3);
+        assert_eq!(sprites[2].name, "sprite2");
+        assert_eq!(sprites[2].image.pixel(3, 11), [0, 0, 255, 128]);
+
+        let mut config = PackerConfig::new(32, 32);
+        config.padding = 1;
+        config.power_of_two = true;
+        let atlas = build_atlas(&sprites, &config).unwrap();
+        assert_eq!(atlas.pages.len(), 1);
+        for (e, colour) in atlas.entries.iter().zip(colours.iter()) {
+            assert_eq!(atlas.pages[0].pixel(e.rect.x, e.rect.y), *colour);
+            assert_eq!(atlas.pages[0].pixel(e.rect.right() - 1, e.rect.bottom() - 1), *colour);
+        }
+
+        let out = dir.join("out").join("atlas");
+        std::fs::create_dir_all(out.parent().unwrap()).unwrap();
+        let written = atlas.write(&out, ManifestFormat::Json).unwrap();
+        assert_eq!(Image::load_png(&written[0]).unwrap(), atlas.pages[0]);
+        let json = std::fs::read_to_string(&written[1]).unwrap();
+        assert!(json.contains("\"name\": \"sprite1\""));
+        assert!(json.contains("\"file\": \"atlas.png\""));
+
+        // A dotted stem is kept whole, rather than losing what's after the dot.
+        let dotted = dir.join("out").join("ui.v2");
+        let written = atlas.write(&dotted, ManifestFormat::Ron).unwrap();
+        assert_eq!(written, vec![dir.join("out").join("ui.v2.png"), dir.join("out").join("ui.v2.ron")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+        assert_eq!(escape("tab\tline\n\u{1f}"), "tab\\u0009line\\u000a\\u001f");
+    }
+}
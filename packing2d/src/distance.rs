@@ -0,0 +1,401 @@
+// Signed distance fields from binary masks (painted bitmaps), using the exact
+// Euclidean distance transform in Felzenszwalb & Huttenlocher 2012: Distance
+// Transforms of Sampled Functions.
+//
+// The 2D transform is a 1D transform down each column and then along each row.
+// Each 1D pass finds the lower envelope of the parabolas rooted at every sample.
+//
+// A DistanceField is an Sdf, so it can be given to Grid::apply_sdf or composed with
+// the sdf::* combinators. It can also be written out as an image for use in shaders.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use nalgebra::Vector2;
+
+use crate::atlas::{AtlasError, Image};
+use crate::sdf::Sdf;
+
+#[derive(Debug)]
+pub enum MaskError {
+    Io(io::Error),
+    Decode(PathBuf, String),
+}
+
+impl fmt::Display for MaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaskError::Io(e) => write!(f, "{}", e),
+            MaskError::Decode(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl Error for MaskError {}
+
+impl From<io::Error> for MaskError {
+    fn from(e: io::Error) -> MaskError {
+        MaskError::Io(e)
+    }
+}
+
+// Which pixels are inside the shape. Row 0 is at the top.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mask {
+    pub width: usize,
+    pub height: usize,
+    pub inside: Vec<bool>,
+}
+
+impl Mask {
+    pub fn new(width: usize, height: usize) -> Mask {
+        let size = width.checked_mul(height).expect("mask too large");
+        Mask { width, height, inside: vec![false; size] }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.inside[x + y * self.width]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, inside: bool) {
+        self.inside[x + y * self.width] = inside;
+    }
+
+    /// Load a .pgm or .png, by extension.
+    pub fn load(path: &Path) -> Result<Mask, MaskError> {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()) {
+            Some(ref e) if e == "pgm" => Mask::load_pgm(path),
+            Some(ref e) if e == "png" => Mask::load_png(path),
+            _ => Err(MaskError::Decode(path.to_owned(), "expected a .pgm or .png".to_string())),
+        }
+    }
+
+    /// Binary (P5) or plain (P2) PGM. Pixels at least half of the maximum are inside.
+    pub fn load_pgm(path: &Path) -> Result<Mask, MaskError> {
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        let decode_err = |e: &str| MaskError::Decode(path.to_owned(), e.to_string());
+
+        // Header: magic, width, height, maxval, separated by whitespace and # comments.
+        let mut pos = 0;
+        let mut fields = vec![];
+        while fields.len() < 4 {
+            while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+                if data[pos] == b'#' {
+                    while pos < data.len() && data[pos] != b'\n' {
+                        pos += 1;
+                    }
+                } else {
+                    pos += 1;
+                }
+            }
+            let start = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                return Err(decode_err("truncated header"));
+            }
+            fields.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+        }
+        pos += 1; // the single whitespace before the raster.
+
+        let number = |s: &str| s.parse::<usize>().map_err(|_| decode_err("bad header"));
+        let (width, height, maxval) = (number(&fields[1])?, number(&fields[2])?, number(&fields[3])?);
+        if maxval == 0 || maxval > 65535 {
+            return Err(decode_err("bad maxval"));
+        }
+        if width == 0 || height == 0 {
+            return Err(decode_err("empty image"));
+        }
+        let size = width.checked_mul(height).ok_or_else(|| decode_err("image too large"))?;
+
+        let values: Vec<usize> = match fields[0].as_str() {
+            "P5" => {
+                let bytes = if maxval > 255 { 2 } else { 1 };
+                let end = size.checked_mul(bytes).and_then(|n| n.checked_add(pos))
+                    .ok_or_else(|| decode_err("image too large"))?;
+                let raster = data.get(pos..end)
+                    .ok_or_else(|| decode_err("truncated raster"))?;
+                raster.chunks(bytes)
+                    .map(|b| if bytes == 2 { (b[0] as usize) << 8 | b[1] as usize } else { b[0] as usize })
+                    .collect()
+            }
+            "P2" => {
+                let text = String::from_utf8_lossy(data.get(pos..).unwrap_or(&[]));
+                let values = text.split_whitespace().take(size)
+                    .map(number)
+                    .collect::<Result<Vec<usize>, MaskError>>()?;
+                if values.len() < size {
+                    return Err(decode_err("truncated raster"));
+                }
+                values
+            }
+            _ => return Err(decode_err("not a PGM")),
+        };
+
+        Ok(Mask { width, height, inside: values.iter().map(|v| v * 2 > maxval).collect() })
+    }
+
+    /// Pixels that are bright and opaque are inside.
+    pub fn load_png(path: &Path) -> Result<Mask, MaskError> {
+        let image = Image::load_png(path).map_err(|e| match e {
+            AtlasError::Io(e) => MaskError::Io(e),
+            AtlasError::Decode(path, e) => MaskError::Decode(path, e),
+            AtlasError::Pack(e) => MaskError::Decode(path.to_owned(), e.to_string()),
+        })?;
+        Ok(Mask::from_image(&image))
+    }
+
+    pub fn from_image(image: &Image) -> Mask {
+        let inside = image.pixels.chunks(4).map(|px| {
+            let grey = (px[0] as u32 + px[1] as u32 + px[2] as u32) / 3;
+            grey * px[3] as u32 >= 128 * 255
+        }).collect();
+        Mask { width: image.width as usize, height: image.height as usize, inside }
+    }
+}
+
+// Stands in for infinity, without inf - inf giving NaN in the envelope intersections.
+const FAR: f64 = 1e20;
+
+// Squared distance transform of one row or column (f is the cost at each sample).
+// v, z are scratch space of at least f.len() and f.len() + 1.
+fn transform_1d(f: &[f64], d: &mut [f64], v: &mut [usize], z: &mut [f64]) {
+    let n = f.len();
+    let mut k = 0; // rightmost parabola in the lower envelope.
+    v[0] = 0;
+    z[0] = -FAR;
+    z[1] = FAR;
+    for q in 1..n {
+        let qf = q as f64;
+        loop {
+            let r = v[k] as f64;
+            // Where the parabola from q meets the one from v[k].
+            let s = ((f[q] + qf * qf) - (f[v[k]] + r * r)) / (2.0 * qf - 2.0 * r);
+            if s <= z[k] {
+                k -= 1; // z[0] is below any s, so this stops there.
+                continue;
+            }
+            k += 1;
+            v[k] = q;
+            z[k] = s;
+            z[k + 1] = FAR;
+            break;
+        }
+    }
+
+    k = 0;
+    for q in 0..n {
+        while z[k + 1] < q as f64 {
+            k += 1;
+        }
+        let r = q as f64 - v[k] as f64;
+        d[q] = r * r + f[v[k]];
+    }
+}
+
+/// Squared distance, in pixels, from each pixel to the nearest pixel where
+/// `features` is true. FAR (or more) where there are no features at all.
+pub fn squared_distance(features: &[bool], width: usize, height: usize) -> Vec<f64> {
+    let mut grid: Vec<f64> = features.iter().map(|f| if *f { 0.0 } else { FAR }).collect();
+    let n = width.max(height);
+    let (mut f, mut d, mut v, mut z) = (vec![0.0; n], vec![0.0; n], vec![0; n], vec![0.0; n + 1]);
+
+    for x in 0..width {
+        for y in 0..height {
+            f[y] = grid[x + y * width];
+        }
+        transform_1d(&f[..height], &mut d[..height], &mut v, &mut z);
+        for y in 0..height {
+            grid[x + y * width] = d[y];
+        }
+    }
+    for y in 0..height {
+        let row = &mut grid[y * width..(y + 1) * width];
+        f[..width].copy_from_slice(row);
+        transform_1d(&f[..width], &mut d[..width], &mut v, &mut z);
+        row.copy_from_slice(&d[..width]);
+    }
+    grid
+}
+
+// A signed distance field sampled at pixel centres, negative inside.
+// Pixel (x, y) covers origin + (x, y) * pixel_size to origin + (x + 1, y + 1) * pixel_size.
+#[derive(Debug, Clone)]
+pub struct DistanceField {
+    pub width: usize,
+    pub height: usize,
+    pub origin: Vector2<f32>,
+    pub pixel_size: f32,
+    pub values: Vec<f32>, // world units.
+}
+
+impl DistanceField {
+    pub fn from_mask(mask: &Mask, origin: Vector2<f32>, pixel_size: f32) -> DistanceField {
+        let outside: Vec<bool> = mask.inside.iter().map(|i| !i).collect();
+        let to_inside = squared_distance(&mask.inside, mask.width, mask.height);
+        let to_outside = squared_distance(&outside, mask.width, mask.height);
+
+        // The surface runs along pixel edges, half a pixel from the centres either side.
+        let values = mask.inside.iter().enumerate().map(|(i, inside)| {
+            let d = if *inside { 0.5 - to_outside[i].sqrt() } else { to_inside[i].sqrt() - 0.5 };
+            d as f32 * pixel_size
+        }).collect();
+
+        DistanceField { width: mask.width, height: mask.height, origin, pixel_size, values }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.values[x + y * self.width]
+    }
+
+    // Distances mapped to bytes, 128 on the surface, 255 at `range` inside and
+    // 0 at `range` outside.
+    pub fn to_bytes(&self, range: f32) -> Vec<u8> {
+        self.values.iter()
+            .map(|d| ((0.5 - d / (2.0 * range)).max(0.0).min(1.0) * 255.0).round() as u8)
+            .collect()
+    }
+
+    /// Binary (P5) PGM, encoded as in to_bytes.
+    pub fn write_pgm(&self, path: &Path, range: f32) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "P5\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.to_bytes(range))?;
+        out.flush()
+    }
+
+    /// Greyscale PNG, encoded as in to_bytes.
+    pub fn write_png(&self, path: &Path, range: f32) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?),
+                                            self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.to_bytes(range))?;
+        Ok(())
+    }
+}
+
+impl Sdf for DistanceField {
+    // Bilinear between pixel centres. Outside of the image, the distance to it is added on.
+    // An empty field is everywhere outside.
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        if self.width == 0 || self.height == 0 {
+            return std::f32::MAX;
+        }
+        let local = (p - self.origin) / self.pixel_size - Vector2::new(0.5, 0.5);
+        let x = local.x.max(0.0).min((self.width - 1) as f32);
+        let y = local.y.max(0.0).min((self.height - 1) as f32);
+        let beyond = (Vector2::new(x, y) - local).norm() * self.pixel_size;
+
+        let i = (x.floor() as usize).min(self.width.max(2) - 2);
+        let j = (y.floor() as usize).min(self.height.max(2) - 2);
+        let (tx, ty) = (x - i as f32, y - j as f32);
+        let v = |i: usize, j: usize| self.get(i.min(self.width - 1), j.min(self.height - 1));
+        let top = v(i, j) * (1.0 - tx) + v(i + 1, j) * tx;
+        let bottom = v(i, j + 1) * (1.0 - tx) + v(i + 1, j + 1) * tx;
+        top * (1.0 - ty) + bottom * ty + beyond
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grid;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_exact_against_brute_force() {
+        let mut rng = StdRng::seed_from_u64(37);
+        let (width, height) = (23, 17);
+        let features: Vec<bool> = (0..width * height).map(|_| rng.gen_range(0, 40) == 0).collect();
+        let fast = squared_distance(&features, width, height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let mut best = FAR;
+                for (i, _) in features.iter().enumerate().filter(|(_, f)| **f) {
+                    let (dx, dy) = (x as f64 - (i % width) as f64, y as f64 - (i / width) as f64);
+                    best = best.min(dx * dx + dy * dy);
+                }
+                assert_eq!(fast[x + y * width], best, "at {}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_disc_mask_contours_to_circle() {
+        let (center, radius) = (Vector2::new(20.0, 18.0), 11.0);
+        let mut mask = Mask::new(40, 36);
+        for y in 0..mask.height {
+            for x in 0..mask.width {
+                let p = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+                mask.set(x, y, (p - center).norm() < radius);
+            }
+        }
+
+        // Half size pixels: the field is in world units.
+        let field = DistanceField::from_mask(&mask, Vector2::new(0.0, 0.0), 0.5);
+        let world = (center * 0.5, radius * 0.5);
+        for (i, d) in field.values.iter().enumerate() {
+            let p = Vector2::new((i % mask.width) as f32 + 0.5, (i / mask.width) as f32 + 0.5) * 0.5;
+            assert!((d - ((p - world.0).norm() - world.1)).abs() < 0.5, "{} at {}", d, p);
+        }
+
+        // Off of the pixel edges, where the field is exactly zero.
+        let mut grid = Grid::with_resolution(Vector2::new(0.1, 0.1), Vector2::new(19.1, 17.1), (19, 17));
+        grid.apply_sdf(&field);
+        let contour = grid.contour();
+        assert_eq!(contour.len(), 1);
+        assert!(contour[0].closed);
+        for p in &contour[0].points {
+            assert!(((p - world.0).norm() - world.1).abs() < 0.5, "{} is off the circle", p);
+        }
+    }
+
+    #[test]
+    fn test_pgm_round_trip() {
+        let dir = std::env::temp_dir();
+        let plain = dir.join("packing2d_test_mask_plain.pgm");
+        std::fs::write(&plain, "P2\n# a comment\n3 2\n15\n0 15 8\n7 0 15\n").unwrap();
+        let mask = Mask::load(&plain).unwrap();
+        assert_eq!(mask.inside, vec![false, true, true, false, false, true]);
+
+        let field = DistanceField::from_mask(&mask, Vector2::new(0.0, 0.0), 1.0);
+        let binary = dir.join("packing2d_test_mask_binary.pgm");
+        field.write_pgm(&binary, 4.0).unwrap();
+        assert_eq!(Mask::load(&binary).unwrap(), mask);
+
+        let png = dir.join("packing2d_test_mask.png");
+        field.write_png(&png, 4.0).unwrap();
+        assert_eq!(Mask::load(&png).unwrap(), mask);
+
+        for path in [plain, binary, png].iter() {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bad_pgm() {
+        let path = std::env::temp_dir().join("packing2d_test_mask_bad.pgm");
+        for (header, error) in [("P2 0 0 255\n", "empty image"),
+                                ("P5 4294967296 4294967296 255\n", "image too large")].iter() {
+            std::fs::write(&path, header).unwrap();
+            match Mask::load(&path) {
+                Err(MaskError::Decode(_, e)) => assert_eq!(&e, error),
+                other => panic!("{:?}", other),
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let field = DistanceField::from_mask(&Mask::new(0, 0), Vector2::new(0.0, 0.0), 1.0);
+        assert_eq!(field.distance(Vector2::new(1.0, 1.0)), std::f32::MAX);
+    }
+}
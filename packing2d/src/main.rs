@@ -2,17 +2,29 @@ extern crate sdl2;
 extern crate gl;
 extern crate nalgebra;
 extern crate rand;
+extern crate png;
+
+mod atlas;
+mod distance;
+mod metrics;
+mod nesting;
+mod packer;
+mod raster;
+mod scatter;
+mod sdf;
 
 use sdl2::pixels::Color;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
 use sdl2::event::Event;
 use sdl2::rect::Rect;
+use raster::{DrawTarget, Raster};
 use nalgebra::Vector2;
 use nalgebra::Vector3;
 use nalgebra::RowVector3;
+use nalgebra::Matrix2;
 use nalgebra::Matrix3;
+use nalgebra::{U1, U2};
 use nalgebra::zero;
+use sdf::Sdf;
 
 struct Face {
     internal_vertex: Vector2<f32>,
@@ -37,42 +49,93 @@ struct Edge {
     face_index: [Option<usize>; 2],
 }
 
+// A piece of contour, through the vertices of neighbouring faces.
+#[derive(Debug, Clone, PartialEq)]
+struct Polyline {
+    points: Vec<Vector2<f32>>,
+    normals: Vec<Vector2<f32>>, // outward facing, one per point.
+    closed: bool,
+}
+
+// How apply_sdf places the vertex inside each crossed face.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum VertexMethod {
+    MassPoint, // the average of the edge crossings; smooth, but cuts corners.
+    Qef,       // where the crossings' tangent lines meet; keeps sharp corners.
+}
+
 struct Grid {
+    width: usize,  // verts across.
+    height: usize, // verts down.
+    origin: Vector2<f32>,  // world position of the first vertex.
+    spacing: Vector2<f32>, // world size of each face.
+    draw_scale: f32,       // pixels per world unit, when drawing.
+    vertex_method: VertexMethod, // see set_vertex_method.
+    redo_faces: bool,            // rebuild every face on the next apply_sdf, not just changed ones.
     faces: Vec<Face>,
     verts: Vec<Vert>,
     edges: Vec<Edge>,
 }
 
 impl Grid {
+    // A square grid of unit faces, with its first vertex at the origin.
     fn new(nverts: usize) -> Grid {
+        Grid::with_layout(Vector2::new(0.0, 0.0), Vector2::new(1.0, 1.0), nverts, nverts)
+    }
+
+    // A grid covering min..max, with faces (at most) cell_size big.
+    fn with_cell_size(min: Vector2<f32>, max: Vector2<f32>, cell_size: Vector2<f32>) -> Grid {
+        let extent = max - min;
+        let cells = ((extent.x / cell_size.x).ceil().max(1.0) as usize,
+                     (extent.y / cell_size.y).ceil().max(1.0) as usize);
+        Grid::with_resolution(min, max, cells)
+    }
+
+    // A grid covering min..max, with cells.0 faces across and cells.1 faces down.
+    fn with_resolution(min: Vector2<f32>, max: Vector2<f32>, cells: (usize, usize)) -> Grid {
+        let extent = max - min;
+        let spacing = Vector2::new(extent.x / cells.0 as f32, extent.y / cells.1 as f32);
+        Grid::with_layout(min, spacing, cells.0 + 1, cells.1 + 1)
+    }
+
+    // An empty grid with the same layout as this one.
+    fn with_same_layout(&self) -> Grid {
+        let mut grid = Grid::with_layout(self.origin, self.spacing, self.width, self.height);
+        grid.draw_scale = self.draw_scale;
+        grid.set_vertex_method(self.vertex_method);
+        grid
+    }
+
+    fn with_layout(origin: Vector2<f32>, spacing: Vector2<f32>, width: usize, height: usize) -> Grid {
+        assert!(width > 1 && height > 1, "a grid needs at least one face");
         let mut faces: Vec<Face> = vec![];
         let mut verts: Vec<Vert> = vec![];
         let mut edges: Vec<Edge> = vec![];
 
-        verts.reserve(nverts * nverts);
-        edges.reserve(nverts * (nverts - 1) * 2);
-        faces.reserve((nverts - 1) * (nverts - 1));
+        verts.reserve(width * height);
+        edges.reserve((width - 1) * height + width * (height - 1));
+        faces.reserve((width - 1) * (height - 1));
 
         // verts
-        for j in 0..nverts {
-            for i in 0..nverts {
-                verts.push(Vert{sdf: 0.0, 
-                                pos: Vector2::new(i as f32, j as f32)});
+        for j in 0..height {
+            for i in 0..width {
+                verts.push(Vert{sdf: std::f32::MAX, // nothing applied yet: all outside.
+                                pos: origin + Vector2::new(i as f32 * spacing.x, j as f32 * spacing.y)});
             }
         }
 
         // horizontal edges
-        for j in 0..nverts {
-            for i in 0..nverts-1 {
-                let vert_index = i + nverts * j;
+        for j in 0..height {
+            for i in 0..width-1 {
+                let vert_index = i + width * j;
                 
                 // we might not have a face on either side...
                 let top_face_index: Option<usize> = if j > 0 { 
-                    Some(i + (nverts - 1) * (j - 1))
+                    Some(i + (width - 1) * (j - 1))
                 } else { None };
 
-                let bottom_face_index: Option<usize> = if j < nverts - 1 {
-                    Some(i + (nverts - 1) * j)
+                let bottom_face_index: Option<usize> = if j < height - 1 {
+                    Some(i + (width - 1) * j)
                 } else { None };
 
                 edges.push(Edge{pos: Vector2::new(0.0, 0.0), 
@@ -84,91 +147,143 @@ impl Grid {
         }
 
         // vertical edges
-        for j in 0..nverts-1 {
-            for i in 0..nverts {
-                let vert_index = i + nverts * j;
+        let nhorizontal = (width - 1) * height;
+        for j in 0..height-1 {
+            for i in 0..width {
+                let vert_index = i + width * j;
 
                 // we might not have a face on either side...
                 let left_face_index: Option<usize> = if i > 0 { 
-                    Some(i - 1 + (nverts - 1) * j)
+                    Some(i - 1 + (width - 1) * j)
                 } else { None };
 
-                let right_face_index: Option<usize> = if i < nverts - 1 {
-                    Some(i + (nverts - 1) * j)
+                let right_face_index: Option<usize> = if i < width - 1 {
+                    Some(i + (width - 1) * j)
                 } else { None };
 
                 edges.push(Edge{pos: Vector2::new(0.0, 0.0), 
                                 normal: Vector2::new(0.0, 0.0), 
                                 crossed: false,
-                                vert_index: [vert_index, vert_index+nverts],
+                                vert_index: [vert_index, vert_index+width],
                                 face_index: [left_face_index, right_face_index]});
             }
         }
 
         // faces
-        for j in 0..nverts-1 {
-            for i in 0..nverts-1 {
-                let vert_index = i + nverts * j;
-                let edge_index = i + (nverts - 1) * j;
+        for j in 0..height-1 {
+            for i in 0..width-1 {
+                let vert_index = i + width * j;
+                let edge_index = i + (width - 1) * j;
                 faces.push(Face{internal_vertex: Vector2::new(0.0, 0.0),
                                 has_vertex: false,
-                                edges: [edge_index, edge_index + nverts - 1,
-                                        (nverts * (nverts - 1)) + vert_index, 
-                                        (nverts * (nverts - 1) + vert_index + 1)],
+                                edges: [edge_index, edge_index + width - 1,
+                                        nhorizontal + vert_index, 
+                                        nhorizontal + vert_index + 1],
                 });
             }
         }
 
         Grid{
+            width: width,
+            height: height,
+            origin: origin,
+            spacing: spacing,
+            draw_scale: 30.0,
+            vertex_method: VertexMethod::Qef,
+            redo_faces: false,
             faces: faces,
             verts: verts,
             edges: edges,
         }
     }
 
-    fn apply_sdf(&mut self, sdf: fn(pos: Vector2<f32>) -> f32) {
+    // World position of the last vertex.
+    fn max(&self) -> Vector2<f32> {
+        self.verts[self.verts.len() - 1].pos
+    }
+
+    // Takes effect on the next apply_sdf, which then rebuilds every face, so none are
+    // left placed by the old method.
+    fn set_vertex_method(&mut self, method: VertexMethod) {
+        if method != self.vertex_method {
+            self.vertex_method = method;
+            self.redo_faces = true;
+        }
+    }
+
+    // Bilinear interpolation of the sdf values from the last apply_sdf.
+    // Positions outside of the grid are clamped to its edge.
+    fn sample(&self, p: Vector2<f32>) -> f32 {
+        let x = ((p.x - self.origin.x) / self.spacing.x).max(0.0).min((self.width - 1) as f32);
+        let y = ((p.y - self.origin.y) / self.spacing.y).max(0.0).min((self.height - 1) as f32);
+        let i = (x.floor() as usize).min(self.width - 2);
+        let j = (y.floor() as usize).min(self.height - 2);
+        let (tx, ty) = (x - i as f32, y - j as f32);
+
+        let v = |i: usize, j: usize| self.verts[i + j * self.width].sdf;
+        let top = v(i, j) * (1.0 - tx) + v(i + 1, j) * tx;
+        let bottom = v(i, j + 1) * (1.0 - tx) + v(i + 1, j + 1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+
+    // Takes any Sdf: a closure, a plain fn, a composed sdf::* expression or a &dyn Sdf.
+    // Can be called again with a new sdf (eg. every frame of an animation). Faces whose
+    // corner signs didn't change are skipped unless they're crossed and their corner
+    // values moved. Returns the indices of the faces whose vertex appeared, went or moved.
+    fn apply_sdf<S: Sdf + ?Sized>(&mut self, sdf: &S) -> Vec<usize> {
+        let previous: Vec<f32> = self.verts.iter().map(|v| v.sdf).collect();
         for v in &mut self.verts {
-            v.sdf = sdf(v.pos);
+            v.sdf = sdf.distance(v.pos);
         }
 
         // use bisection method to find the intersection of the sdf and each edge.
         for e in &mut self.edges {
             let v1 = &self.verts[e.vert_index[0]];
             let v2 = &self.verts[e.vert_index[1]];
-            let vector = (v2.pos - v1.pos).normalize();
+            let vector = v2.pos - v1.pos; // offsets below are a fraction of the edge.
+
+            if v1.sdf != 0.0 && v2.sdf != 0.0 && v1.sdf.signum() == v2.sdf.signum() {
+                e.crossed = false;
+                continue;
+            }
+            if e.crossed && v1.sdf == previous[e.vert_index[0]] && v2.sdf == previous[e.vert_index[1]] {
+                continue; // same crossing as last time.
+            }
 
             if v1.sdf == 0.0 {
                 e.pos = v1.pos;
             } else if v2.sdf == 0.0 {
                 e.pos = v2.pos;
-            } else if v1.sdf.signum() == v2.sdf.signum() {
-                continue;
             } else {
                 let mut aoffset = 0.0;
                 let mut boffset = 1.0;
-                let mut midoffset = 0.0;
+                let mut aval = v1.sdf;
+                let mut bval = v2.sdf;
 
-                let a_is_low = v1.sdf < 0.0;
-
-                while boffset - aoffset > 0.04 { // 0.04 = 1 / 256
-                    midoffset = (aoffset + boffset) / 2.0;
-                    let midval = sdf(v1.pos + vector * midoffset);
+                while boffset - aoffset > 0.04 { // 0.04 ~= 1 / 32 of the edge
+                    let midoffset = (aoffset + boffset) / 2.0;
+                    let midval = sdf.distance(v1.pos + vector * midoffset);
                     if midval == 0.0 {
+                        aoffset = midoffset;
+                        aval = midval;
                         break;
-                        println!("MATCH");
                     }
-                    if (a_is_low && (midval < 0.0)) || (!a_is_low && midval > 0.0) {
+                    if midval.signum() == aval.signum() {
                         aoffset = midoffset;
+                        aval = midval;
                     } else {
                         boffset = midoffset;
+                        bval = midval;
                     }
                 }
-                e.pos = v1.pos + vector * midoffset;
-                //println!("MIDPOS: {}", e.pos);
+
+                // Finish with a secant step, so SDFs that are linear along
+                // the edge (eg. polygons) are crossed exactly.
+                let t = aval / (aval - bval);
+                e.pos = v1.pos + vector * (aoffset + (boffset - aoffset) * t);
             }
 
-            e.normal = Vector2::new(sdf(e.pos + (Vector2::x() * 0.01)) - sdf(e.pos - (Vector2::x() * 0.01)), 
-                                    sdf(e.pos + (Vector2::y() * 0.01)) - sdf(e.pos - (Vector2::y() * 0.01))).normalize();
+            e.normal = sdf.normal(e.pos);
             e.crossed = true;
         }
 
@@ -176,11 +291,27 @@ impl Grid {
         // Using method in Garland 1997: Surface Simplification Using Quadric Error Metrics.
         // Basically find the intersection of all of the lines defined by the edge crossing
         // position and edge crossing normal.
-        for f in &mut self.faces {
+        let (verts, edges) = (&self.verts, &self.edges);
+        let (vertex_method, redo_faces) = (self.vertex_method, self.redo_faces);
+        let mut changed = vec![];
+        for (index, f) in self.faces.iter_mut().enumerate() {
+            let top = &edges[f.edges[0]];
+            let bottom = &edges[f.edges[1]];
+            let corners = [top.vert_index[0], top.vert_index[1], bottom.vert_index[0], bottom.vert_index[1]];
+            let signs_changed = corners.iter().any(|v| sign(previous[*v]) != sign(verts[*v].sdf));
+            let values_changed = corners.iter().any(|v| previous[*v] != verts[*v].sdf);
+            let crossed = f.edges.iter().any(|e| edges[*e].crossed);
+            // With the same signs, the same edges are crossed as last time.
+            if !redo_faces && !signs_changed && (!crossed || !values_changed) {
+                continue;
+            }
+
+            let before = (f.has_vertex, f.internal_vertex);
+            f.has_vertex = false;
             let mut qef: Matrix3<f32> = zero();
             let mut crossed_edge: Vec<&Edge> = Vec::with_capacity(4);
             for i in 0..4 {
-                let edge = &self.edges[f.edges[i]];
+                let edge = &edges[f.edges[i]];
                 if edge.crossed {
                     crossed_edge.push(&edge);
                     let normal_equation = Vector3::new(edge.normal.x, edge.normal.y, -edge.normal.dot(&edge.pos));
@@ -191,60 +322,135 @@ impl Grid {
             if crossed_edge.len() == 1 {
                 f.internal_vertex = crossed_edge[0].pos;
                 f.has_vertex = true;
-            } /*else if crossed_edge.len() > 1 {
-                let mat = Matrix3::new(
-                    qef[(0, 0)], qef[(0, 1)], qef[(0, 2)],
-                    qef[(1, 0)], qef[(1, 1)], qef[(1, 2)],
-                    0.0, 0.0, 1.0);
-                let maybe_inverse = mat.try_inverse();
-                if let Some(inv) = maybe_inverse {
-                    let joined_pos = inv * Vector3::new(0.0, 0.0, 1.0);
-                    f.internal_vertex = joined_pos.xy();
-                    println!("FV: {}", joined_pos);
-                    f.has_vertex = true;
-                }*/ else {
-                    if crossed_edge.len() > 0 {
-                        for e in &crossed_edge {
-                            f.internal_vertex += e.pos;
-                        }
-                        f.internal_vertex /= crossed_edge.len() as f32;
-                        f.has_vertex = true;
-                    //}
+            } else if crossed_edge.len() > 1 {
+                let mut mass_point = Vector2::new(0.0, 0.0);
+                for e in &crossed_edge {
+                    mass_point += e.pos;
                 }
+                mass_point /= crossed_edge.len() as f32;
+
+                let min = verts[top.vert_index[0]].pos;
+                let max = verts[bottom.vert_index[1]].pos;
+                f.internal_vertex = match vertex_method {
+                    VertexMethod::MassPoint => mass_point,
+                    VertexMethod::Qef => solve_qef(&qef, mass_point, min, max),
+                };
+                f.has_vertex = true;
+            }
+
+            if (f.has_vertex, f.internal_vertex) != before {
+                changed.push(index);
             }
         }
+        self.redo_faces = false;
+        changed
     }
 
-    fn draw_edge(&self, canvas: &mut Canvas<Window>, e: &Edge) {
-        let v1 = self.verts[e.vert_index[0]].pos * 30.0;
-        let v2 = self.verts[e.vert_index[1]].pos * 30.0;
+    // Average normal of the crossed edges around a face.
+    fn face_normal(&self, f: &Face) -> Vector2<f32> {
+        let mut normal = Vector2::new(0.0, 0.0);
+        for i in 0..4 {
+            let edge = &self.edges[f.edges[i]];
+            if edge.crossed {
+                normal += edge.normal;
+            }
+        }
+        if normal.norm() > 0.0 { normal.normalize() } else { normal }
+    }
+
+    // Connect the face vertices into polylines, following the crossed edges
+    // between neighbouring faces.
+    // Closed loops are returned with closed = true, and without repeating the first point.
+    // Contours that leave the grid are returned open, ending at the last face inside it.
+    fn contour(&self) -> Vec<Polyline> {
+        // Crossed edges between two faces that both have a vertex.
+        let mut links: Vec<Vec<(usize, usize)>> = vec![vec![]; self.faces.len()]; // (edge, face)
+        for (i, e) in self.edges.iter().enumerate() {
+            if let (true, Some(f1), Some(f2)) = (e.crossed, e.face_index[0], e.face_index[1]) {
+                if self.faces[f1].has_vertex && self.faces[f2].has_vertex {
+                    links[f1].push((i, f2));
+                    links[f2].push((i, f1));
+                }
+            }
+        }
+
+        let mut used = vec![false; self.edges.len()];
+        let mut polylines = vec![];
+
+        // Walk from the open ends first, then whatever is left must be loops.
+        let ends = (0..self.faces.len()).filter(|f| links[*f].len() % 2 == 1);
+        let rest = (0..self.faces.len()).filter(|f| links[*f].len() % 2 == 0);
+        for start in ends.chain(rest) {
+            while links[start].iter().any(|l| !used[l.0]) {
+                let mut faces = vec![start];
+                let mut current = start;
+                while let Some(&(edge, next)) = links[current].iter().find(|l| !used[l.0]) {
+                    used[edge] = true;
+                    faces.push(next);
+                    current = next;
+                }
+
+                let closed = faces.len() > 2 && faces[0] == faces[faces.len() - 1];
+                if closed {
+                    faces.pop();
+                }
+                polylines.push(Polyline {
+                    points: faces.iter().map(|f| self.faces[*f].internal_vertex).collect(),
+                    normals: faces.iter().map(|f| self.face_normal(&self.faces[*f])).collect(),
+                    closed,
+                });
+            }
+        }
+        polylines
+    }
+
+    // World position to pixels; the grid's first vertex is drawn at the top left.
+    fn to_screen(&self, p: Vector2<f32>) -> (i32, i32) {
+        let screen = (p - self.origin) * self.draw_scale;
+        (screen.x as i32, screen.y as i32)
+    }
+
+    fn draw_edge(&self, canvas: &mut impl DrawTarget, e: &Edge) {
+        let v1 = self.verts[e.vert_index[0]].pos;
+        let v2 = self.verts[e.vert_index[1]].pos;
         //println!("A: {}, B: {}", v1, v2);
-        canvas.draw_line((v1.x as i32, v1.y as i32),
-                         (v2.x as i32, v2.y as i32)).expect("bad line");
+        canvas.draw_line(self.to_screen(v1), self.to_screen(v2)).expect("bad line");
     }
 
-    fn draw_line(&self, canvas: &mut Canvas<Window>, v1: Vector2<f32>, v2: Vector2<f32>) {
-        let v1i = v1 * 30.0;
-        let v2i = v2 * 30.0;
+    fn draw_line(&self, canvas: &mut impl DrawTarget, v1: Vector2<f32>, v2: Vector2<f32>) {
         //println!("A: {}, B: {}", v1, v2);
-        canvas.draw_line((v1i.x as i32, v1i.y as i32),
-                         (v2i.x as i32, v2i.y as i32)).expect("bad line");
+        canvas.draw_line(self.to_screen(v1), self.to_screen(v2)).expect("bad line");
     }
 
-    fn draw_face(&self, canvas: &mut Canvas<Window>, f: &Face) {
+    fn draw_face(&self, canvas: &mut impl DrawTarget, f: &Face) {
         self.draw_edge(canvas, &self.edges[f.edges[0]]);
         self.draw_edge(canvas, &self.edges[f.edges[1]]);
         self.draw_edge(canvas, &self.edges[f.edges[2]]);
         self.draw_edge(canvas, &self.edges[f.edges[3]]);
     }
 
-    fn draw_points(&self, canvas: &mut Canvas<Window>) {
+    fn draw_contour(&self, canvas: &mut impl DrawTarget, contour: &Vec<Polyline>) {
+        for polyline in contour {
+            let n = polyline.points.len();
+            let segments = if polyline.closed { n } else { n - 1 };
+            canvas.set_draw_color(Color::RGB(255, 255, 255));
+            for i in 0..segments {
+                self.draw_line(canvas, polyline.points[i], polyline.points[(i + 1) % n]);
+            }
+            canvas.set_draw_color(Color::RGB(255, 255, 0));
+            for (p, normal) in polyline.points.iter().zip(polyline.normals.iter()) {
+                self.draw_line(canvas, *p, p + normal * self.spacing.norm() * 0.2);
+            }
+        }
+    }
+
+    fn draw_points(&self, canvas: &mut impl DrawTarget) {
         for v in &self.verts {
             canvas.set_draw_color(Color::RGB(0, 255, 0));
             if v.sdf > 0.0 {
                 canvas.set_draw_color(Color::RGB(255, 0, 0));
             }
-            canvas.draw_point(((v.pos.x * 30.0) as i32, (v.pos.y * 30.0) as i32)).expect("bad draw");
+            canvas.draw_point(self.to_screen(v.pos)).expect("bad draw");
         }
 
         canvas.set_draw_color(Color::RGB(255, 255, 255));
@@ -254,12 +460,12 @@ impl Grid {
                 continue;
             }
 
-            let point = e.pos * 30.0;
-            canvas.draw_point((point.x as i32, point.y as i32)).expect("bad edge");
+            let point = self.to_screen(e.pos);
+            canvas.draw_point(point).expect("bad edge");
             /*
-            canvas.draw_line((point.x as i32, point.y as i32),
-                             ((point.x + e.normal.x * 5.0) as i32,
-                              (point.y + e.normal.y * 5.0) as i32)).expect("bad line");
+            canvas.draw_line(point,
+                             (point.0 + (e.normal.x * 5.0) as i32,
+                              point.1 + (e.normal.y * 5.0) as i32)).expect("bad line");
                               */
 
             if e.face_index[0].is_some() && e.face_index[1].is_some() {
@@ -278,21 +484,154 @@ impl Grid {
             }
             if f.has_vertex {
                 canvas.set_draw_color(Color::RGB(255, 0, 255));
-                let point = f.internal_vertex * 30.0;
-                //canvas.fill_rect(Rect::new(point.x as i32 - 2, point.y as i32 - 2, 4, 4)).expect("bad face");
+                let point = self.to_screen(f.internal_vertex);
+                //canvas.fill_rect(Rect::new(point.0 - 2, point.1 - 2, 4, 4)).expect("bad face");
             }
         }
     }
 }
 
+// Which side of the surface a sample is on; 0 is on it.
+fn sign(d: f32) -> i8 {
+    if d < 0.0 { -1 } else if d > 0.0 { 1 } else { 0 }
+}
+
+/// Find the point that minimizes a quadric error function, built from
+/// the homogeneous plane equations (n.x, n.y, -n.p) of each edge crossing.
+///
+/// The least squares solve uses a truncated pseudo-inverse, relative to the mass
+/// point of the crossings. Near parallel normals (eg. a flat part of the surface) give
+/// small singular values; truncating them keeps the vertex at the mass point along
+/// that direction, rather than shooting off along the line.
+/// The result is clamped to the face (min, max).
+fn solve_qef(qef: &Matrix3<f32>, mass_point: Vector2<f32>,
+             min: Vector2<f32>, max: Vector2<f32>) -> Vector2<f32> {
+    let ata = qef.fixed_slice::<U2, U2>(0, 0).into_owned();
+    let atb = -qef.fixed_slice::<U2, U1>(0, 2).into_owned();
+
+    let svd = ata.svd(true, true);
+    let u = svd.u.unwrap();
+    let v_t = svd.v_t.unwrap();
+    let max_singular = svd.singular_values.amax();
+
+    let mut pseudo_inverse: Matrix2<f32> = zero();
+    for i in 0..2 {
+        let singular = svd.singular_values[i];
+        if singular > max_singular * 0.1 && singular > std::f32::EPSILON {
+            pseudo_inverse += v_t.row(i).transpose() * u.column(i).transpose() / singular;
+        }
+    }
+
+    let vertex = mass_point + pseudo_inverse * (atb - ata * mass_point);
+    Vector2::new(vertex.x.max(min.x).min(max.x),
+                 vertex.y.max(min.y).min(max.y))
+}
+
 const GRID_SIZE: usize = 40;
 
-fn circle_sdf(p: Vector2<f32>) -> f32 {
-    let delta = Vector2::new(5.5, 5.5) - p;
-    delta.dot(&delta) - 18.0
+// `packing2d --atlas <sprite dir> <output stem> [page size]` packs a directory of
+// PNGs into a texture atlas, without opening a window.
+fn build_atlas(args: &[String]) -> Result<(), atlas::AtlasError> {
+    let size = args.get(2).map_or(2048, |s| s.parse().expect("page size should be a number"));
+    let mut config = packer::PackerConfig::new(size, size);
+    config.allow_rotation = true;
+    config.padding = 2;
+    config.power_of_two = true;
+
+    let sprites = atlas::load_sprites(std::path::Path::new(&args[0]))?;
+    let atlas = atlas::build_atlas(&sprites, &config)?;
+    for path in atlas.write(std::path::Path::new(&args[1]), atlas::ManifestFormat::Json)? {
+        println!("wrote {}", path.display());
+    }
+    println!("packed {} sprites into {} page(s), {:.1}% efficient",
+             sprites.len(), atlas.pages.len(), atlas.efficiency * 100.0);
+    Ok(())
+}
+
+// `packing2d --distance <mask.pgm|png> <output.pgm|png> [range in pixels]` turns a
+// binary mask into a signed distance field image.
+fn build_distance_field(args: &[String]) -> Result<(), distance::MaskError> {
+    let range = args.get(2).map_or(8.0, |s| s.parse().expect("range should be a number"));
+    let mask = distance::Mask::load(std::path::Path::new(&args[0]))?;
+    let field = distance::DistanceField::from_mask(&mask, Vector2::new(0.0, 0.0), 1.0);
+
+    let out = std::path::Path::new(&args[1]);
+    if out.extension().map_or(false, |e| e.eq_ignore_ascii_case("png")) {
+        field.write_png(out, range)?;
+    } else {
+        field.write_pgm(out, range)?;
+    }
+    println!("wrote {}", out.display());
+    Ok(())
+}
+
+// Everything the window shows: the samples, edge crossings, face vertices and the contour.
+fn draw_grid(canvas: &mut impl DrawTarget, grid: &Grid, contour: &Vec<Polyline>) {
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.clear();
+    grid.draw_points(canvas);
+    grid.draw_contour(canvas, contour);
+}
+
+// Draw the grid without a window, just big enough to hold it.
+fn render(grid: &Grid, contour: &Vec<Polyline>) -> Raster {
+    let (x, y) = grid.to_screen(grid.max());
+    let mut raster = Raster::new(x as u32 + 1, y as u32 + 1);
+    draw_grid(&mut raster, grid, contour);
+    raster
+}
+
+// The circle the demo starts with.
+fn demo_circle(frame: u32) -> impl Sdf {
+    // Drifting back and forth across the grid.
+    let x = 5.5 + 4.0 * (frame as f32 * 0.02).sin();
+    sdf::circle(18f32.sqrt()).translate(Vector2::new(x, 5.5))
+}
+
+// `packing2d --metrics [csv|md]` reports how accurate the contour is, for each vertex
+// method at a few resolutions, on a shape with both curves and corners.
+fn print_metrics(format: &str) {
+    let shape = sdf::rect(Vector2::new(5.0, 3.0)).rotate(0.3).translate(Vector2::new(8.3, 9.1))
+        .union(sdf::circle(4.5).translate(Vector2::new(13.2, 11.4)));
+    let resolutions = [(10, 10), (20, 20), (40, 40), (80, 80)];
+    let rows = metrics::compare(&shape, Vector2::new(0.0, 0.0), Vector2::new(20.0, 20.0), &resolutions);
+    if format == "csv" {
+        print!("{}", metrics::to_csv(&rows));
+    } else {
+        print!("{}", metrics::to_markdown(&rows));
+    }
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() >= 2 && args[1] == "--metrics" {
+        print_metrics(args.get(2).map_or("md", |s| s.as_str()));
+        return;
+    }
+    // `packing2d --render <output.ppm|png>` draws the first frame without a window.
+    if args.len() == 3 && args[1] == "--render" {
+        let mut grid = Grid::new(32);
+        grid.apply_sdf(&demo_circle(0));
+        let path = std::path::Path::new(&args[2]);
+        render(&grid, &grid.contour()).write(path).expect("failed to write image");
+        println!("wrote {}", path.display());
+        return;
+    }
+    if args.len() >= 4 && args[1] == "--distance" {
+        if let Err(e) = build_distance_field(&args[2..]) {
+            eprintln!("failed to build distance field: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.len() >= 4 && args[1] == "--atlas" {
+        if let Err(e) = build_atlas(&args[2..]) {
+            eprintln!("failed to build atlas: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let sdl = sdl2::init().unwrap();
     let video_subsystem = sdl.video().unwrap();
     let window = video_subsystem.window("Hello", 640, 480).build().unwrap();
@@ -300,16 +639,13 @@ fn main() {
     let mut event_pump = sdl.event_pump().unwrap();
 
     let mut grid = Grid::new(32);
-    grid.apply_sdf(circle_sdf);
-
-    let mut lines = Vec::new();
-    for e in &grid.edges {
-        if e.crossed {
-            lines.push((1, 1));
-        }
-    }
+    let mut frame = 0;
 
     'main: loop {
+        grid.apply_sdf(&demo_circle(frame));
+        let contour = grid.contour();
+        frame += 1;
+
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit{..} => break 'main,
@@ -317,10 +653,7 @@ fn main() {
             }
         }
 
-        canvas.set_draw_color(Color::RGB(0, 0, 0));
-        canvas.clear();
-
-        grid.draw_points(&mut canvas);
+        draw_grid(&mut canvas, &grid, &contour);
         canvas.present();
     }
 }
@@ -368,4 +701,279 @@ mod tests {
             assert_eq!(grid.edges[*e as usize].face_index[1], *r);
         }
     }
+
+    fn square_sdf(p: Vector2<f32>) -> f32 {
+        let delta = p - Vector2::new(5.0, 5.0);
+        delta.x.abs().max(delta.y.abs()) - 2.5
+    }
+
+    fn diamond_sdf(p: Vector2<f32>) -> f32 {
+        let delta = p - Vector2::new(5.1, 5.15);
+        delta.x.abs() + delta.y.abs() - 2.7
+    }
+
+    fn face_containing(grid: &Grid, p: Vector2<f32>) -> &Face {
+        &grid.faces[p.x as usize + (p.y as usize) * (10 - 1)]
+    }
+
+    fn assert_near(a: Vector2<f32>, b: Vector2<f32>) {
+        assert!((a - b).norm() < 1e-4, "expected {}, got {}", b, a);
+    }
+
+    #[test]
+    fn test_qef_square_corners() {
+        let mut grid = Grid::new(10);
+        grid.apply_sdf(&square_sdf);
+
+        for corner in [Vector2::new(2.5, 2.5), Vector2::new(7.5, 2.5),
+                       Vector2::new(2.5, 7.5), Vector2::new(7.5, 7.5)].iter() {
+            let face = face_containing(&grid, *corner);
+            assert!(face.has_vertex);
+            assert_near(face.internal_vertex, *corner);
+        }
+
+        // Flat sides keep the vertex on the side, at the mass point.
+        let side = face_containing(&grid, Vector2::new(2.5, 4.5));
+        assert_near(side.internal_vertex, Vector2::new(2.5, 4.5));
+    }
+
+    #[test]
+    fn test_qef_diamond_corners() {
+        let mut grid = Grid::new(10);
+        grid.apply_sdf(&diamond_sdf);
+
+        for corner in [Vector2::new(5.1, 2.45), Vector2::new(7.8, 5.15),
+                       Vector2::new(5.1, 7.85), Vector2::new(2.4, 5.15)].iter() {
+            let face = face_containing(&grid, *corner);
+            assert!(face.has_vertex);
+            assert_near(face.internal_vertex, *corner);
+        }
+    }
+
+    #[test]
+    fn test_apply_parameterised_sdf() {
+        use sdf::*;
+
+        // The same diamond as diamond_sdf, built from a captured parameter
+        // and as a composed expression.
+        let center = Vector2::new(5.1, 5.15);
+        let closure = move |p: Vector2<f32>| (p.x - center.x).abs() + (p.y - center.y).abs() - 2.7;
+        let expression: Box<dyn Sdf> = rect(Vector2::new(1.0, 1.0))
+            .rotate(std::f32::consts::FRAC_PI_4)
+            .scale(2.7 / 2f32.sqrt())
+            .translate(center)
+            .boxed();
+
+        let mut expected = Grid::new(10);
+        expected.apply_sdf(&diamond_sdf);
+        let mut from_closure = Grid::new(10);
+        from_closure.apply_sdf(&closure);
+        let mut from_expression = Grid::new(10);
+        from_expression.apply_sdf(&*expression);
+
+        for grid in [&from_closure, &from_expression].iter() {
+            for (a, b) in expected.faces.iter().zip(grid.faces.iter()) {
+                assert_eq!(a.has_vertex, b.has_vertex);
+                assert!((a.internal_vertex - b.internal_vertex).norm() < 1e-3);
+            }
+        }
+    }
+
+    fn assert_closed_around(polyline: &Polyline, center: Vector2<f32>, radius: f32) {
+        assert!(polyline.closed);
+        for (p, n) in polyline.points.iter().zip(polyline.normals.iter()) {
+            assert!(((p - center).norm() - radius).abs() < 0.1, "{} is off the circle", p);
+            assert!((n - (p - center).normalize()).norm() < 0.1, "bad normal {} at {}", n, p);
+        }
+        // Neighbouring points are in neighbouring faces.
+        let n = polyline.points.len();
+        for i in 0..n {
+            assert!((polyline.points[i] - polyline.points[(i + 1) % n]).norm() < 2f32.sqrt() + 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_contour_circles() {
+        let mut grid = Grid::new(20);
+        let a = Vector2::new(5.2, 5.3);
+        let b = Vector2::new(13.6, 12.9);
+        let shape = sdf::circle(3.1).translate(a).union(sdf::circle(4.2).translate(b));
+        grid.apply_sdf(&shape);
+
+        let mut contour = grid.contour();
+        assert_eq!(contour.len(), 2);
+        contour.sort_by_key(|p| p.points.len());
+        assert_closed_around(&contour[0], a, 3.1);
+        assert_closed_around(&contour[1], b, 4.2);
+
+        // Every crossed edge is used exactly once.
+        let crossed = grid.edges.iter().filter(|e| e.crossed).count();
+        assert_eq!(contour[0].points.len() + contour[1].points.len(), crossed);
+    }
+
+    #[test]
+    fn test_contour_leaving_grid() {
+        let mut grid = Grid::new(10);
+        grid.apply_sdf(&sdf::circle(4.3).translate(Vector2::new(0.2, 0.1)));
+
+        let contour = grid.contour();
+        assert_eq!(contour.len(), 1);
+        assert!(!contour[0].closed);
+        let first = contour[0].points[0];
+        let last = contour[0].points[contour[0].points.len() - 1];
+        assert!(first.x < 1.0 || first.y < 1.0);
+        assert!(last.x < 1.0 || last.y < 1.0);
+    }
+
+    #[test]
+    fn test_grid_layout() {
+        let grid = Grid::with_resolution(Vector2::new(-2.0, 1.0), Vector2::new(4.0, 3.0), (3, 4));
+        assert_eq!((grid.width, grid.height), (4, 5));
+        assert_eq!(grid.verts.len(), 20);
+        assert_eq!(grid.edges.len(), 3 * 5 + 4 * 4);
+        assert_eq!(grid.faces.len(), 12);
+        assert_eq!(grid.verts[0].pos, Vector2::new(-2.0, 1.0));
+        assert_eq!(grid.max(), Vector2::new(4.0, 3.0));
+
+        // Each face's edges go around the face.
+        for f in &grid.faces {
+            let top = &grid.edges[f.edges[0]];
+            let bottom = &grid.edges[f.edges[1]];
+            let left = &grid.edges[f.edges[2]];
+            let right = &grid.edges[f.edges[3]];
+            assert_eq!(top.vert_index[0], left.vert_index[0]);
+            assert_eq!(top.vert_index[1], right.vert_index[0]);
+            assert_eq!(bottom.vert_index[0], left.vert_index[1]);
+            assert_eq!(bottom.vert_index[1], right.vert_index[1]);
+        }
+
+        let grid = Grid::with_cell_size(Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.5),
+                                        Vector2::new(0.3, 0.3));
+        assert_eq!((grid.width, grid.height), (5, 3));
+    }
+
+    #[test]
+    fn test_contour_at_several_resolutions() {
+        // A wide ellipse-ish capsule, away from the origin.
+        let shape = sdf::capsule(Vector2::new(-30.0, 0.0), Vector2::new(30.0, 0.0), 12.0)
+            .translate(Vector2::new(100.0, -50.0));
+        let min = Vector2::new(50.0, -70.0);
+        let max = Vector2::new(150.0, -30.0);
+
+        let mut errors = vec![];
+        for cells in [(10, 4), (20, 8), (40, 16), (80, 32)].iter() {
+            let mut grid = Grid::with_resolution(min, max, *cells);
+            grid.apply_sdf(&shape);
+            let contour = grid.contour();
+            assert_eq!(contour.len(), 1);
+            assert!(contour[0].closed);
+
+            let error = contour[0].points.iter()
+                .map(|p| shape.distance(*p).abs())
+                .fold(0.0, f32::max);
+            assert!(error < grid.spacing.norm() * 0.25, "{:?}: {}", cells, error);
+            errors.push(error);
+        }
+        // Finer grids are no worse.
+        assert!(errors[3] <= errors[0]);
+    }
+
+    fn assert_same_faces(a: &Grid, b: &Grid) {
+        for (i, (fa, fb)) in a.faces.iter().zip(b.faces.iter()).enumerate() {
+            assert_eq!(fa.has_vertex, fb.has_vertex, "face {}", i);
+            if fa.has_vertex {
+                assert!((fa.internal_vertex - fb.internal_vertex).norm() < 1e-5, "face {}", i);
+            }
+        }
+        for (ea, eb) in a.edges.iter().zip(b.edges.iter()) {
+            assert_eq!(ea.crossed, eb.crossed);
+        }
+    }
+
+    #[test]
+    fn test_reapply_sdf() {
+        let at = |x: f32| sdf::circle(3.3).translate(Vector2::new(x, 7.2));
+        let mut grid = Grid::new(16);
+        let first = grid.apply_sdf(&at(5.1));
+        assert!(!first.is_empty());
+
+        // Nothing moved, nothing changed.
+        assert!(grid.apply_sdf(&at(5.1)).is_empty());
+
+        // Moving the circle gives the same grid as starting afresh.
+        let changed = grid.apply_sdf(&at(9.4));
+        let mut fresh = Grid::new(16);
+        fresh.apply_sdf(&at(9.4));
+        assert_same_faces(&grid, &fresh);
+
+        // Only faces near either circle changed.
+        for f in &changed {
+            let face = &grid.faces[*f];
+            let corner = grid.verts[grid.edges[face.edges[0]].vert_index[0]].pos;
+            let center = corner + Vector2::new(0.5, 0.5);
+            let near = |x: f32| ((center - Vector2::new(x, 7.2)).norm() - 3.3).abs() < 1.5;
+            assert!(near(5.1) || near(9.4), "face {} at {} changed", f, center);
+        }
+
+        // Faces that had a vertex on the old circle but not the new are reported.
+        let mut old = Grid::new(16);
+        old.apply_sdf(&at(5.1));
+        for (i, (a, b)) in old.faces.iter().zip(grid.faces.iter()).enumerate() {
+            if a.has_vertex != b.has_vertex {
+                assert!(changed.contains(&i), "face {} not reported", i);
+            }
+        }
+
+        // Changing the vertex method redoes every face, not just the ones that moved.
+        grid.set_vertex_method(VertexMethod::MassPoint);
+        grid.apply_sdf(&at(9.4));
+        let mut fresh = Grid::new(16);
+        fresh.set_vertex_method(VertexMethod::MassPoint);
+        fresh.apply_sdf(&at(9.4));
+        assert_same_faces(&grid, &fresh);
+        grid.set_vertex_method(VertexMethod::Qef);
+
+        // And back again, with the shape gone entirely.
+        grid.apply_sdf(&|_: Vector2<f32>| 1.0);
+        assert!(grid.faces.iter().all(|f| !f.has_vertex));
+        assert!(grid.edges.iter().all(|e| !e.crossed));
+    }
+
+    // Compare against an image in golden/. On a mismatch the new image is left in the
+    // temp directory, to look at and copy over the golden one if the change is intended.
+    fn assert_golden(raster: &Raster, golden: &[u8], name: &str) {
+        let matches = Raster::from_ppm(golden).map_or(false, |expected| {
+            (expected.width, expected.height, &expected.pixels) == (raster.width, raster.height, &raster.pixels)
+        });
+        if !matches {
+            let path = std::env::temp_dir().join(name);
+            raster.write_ppm(&path).unwrap();
+            panic!("{} differs from the golden image, see {}", name, path.display());
+        }
+    }
+
+    #[test]
+    fn test_render_golden() {
+        let mut grid = Grid::new(12);
+        grid.draw_scale = 8.0;
+        grid.apply_sdf(&sdf::circle(3.7).translate(Vector2::new(5.2, 5.6))
+            .union(sdf::rect(Vector2::new(1.5, 1.0)).translate(Vector2::new(9.0, 2.5))));
+        assert_golden(&render(&grid, &grid.contour()), include_bytes!("../golden/grid.ppm"), "grid.ppm");
+    }
+
+    #[test]
+    fn test_qef_clamped_to_face() {
+        // Nearly parallel crossings would meet far outside of the face.
+        let mut qef: Matrix3<f32> = zero();
+        for (n, p) in [(Vector2::new(1.0, 0.0), Vector2::new(0.5, 0.0)),
+                       (Vector2::new(0.999, 0.045), Vector2::new(0.4, 1.0))].iter() {
+            let n = n.normalize();
+            let plane = Vector3::new(n.x, n.y, -n.dot(p));
+            qef += plane * plane.transpose();
+        }
+        let min = Vector2::new(0.0, 0.0);
+        let max = Vector2::new(1.0, 1.0);
+        let v = solve_qef(&qef, Vector2::new(0.45, 0.5), min, max);
+        assert!(v.x >= 0.0 && v.x <= 1.0 && v.y >= 0.0 && v.y <= 1.0, "{}", v);
+    }
 }
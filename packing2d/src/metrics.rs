@@ -0,0 +1,206 @@
+// How closely the extracted contour follows the SDF it was extracted from.
+//
+//  * vertex error: |distance| at each face vertex on the contour.
+//  * segment error: |distance| sampled along each segment, between the vertices.
+//  * normal deviation: angle between each segment's normal (perpendicular to it, facing
+//    out) and the SDF's gradient at its midpoint, in degrees.
+//
+// Distances are in world units. compare() runs both vertex methods over a list of
+// resolutions, and the results can be written as CSV or a markdown table.
+
+use nalgebra::Vector2;
+
+use crate::sdf::Sdf;
+use crate::{Grid, VertexMethod};
+
+// Points sampled along each segment for the segment error.
+const SEGMENT_SAMPLES: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ContourMetrics {
+    pub method: VertexMethod,
+    pub cells: (usize, usize),
+    pub cell_size: Vector2<f32>,
+    pub segments: usize,
+    pub max_vertex_error: f32,
+    pub mean_vertex_error: f32,
+    pub max_segment_error: f32,
+    pub mean_segment_error: f32,
+    pub max_normal_deviation: f32,
+    pub mean_normal_deviation: f32,
+}
+
+// Running max and mean.
+#[derive(Default)]
+struct Stat {
+    max: f32,
+    sum: f32,
+    count: usize,
+}
+
+impl Stat {
+    fn add(&mut self, x: f32) {
+        self.max = self.max.max(x);
+        self.sum += x;
+        self.count += 1;
+    }
+
+    fn mean(&self) -> f32 {
+        if self.count > 0 { self.sum / self.count as f32 } else { 0.0 }
+    }
+}
+
+/// Measure the contour of `grid`, which should have had `sdf` applied to it.
+pub fn measure<S: Sdf + ?Sized>(grid: &Grid, sdf: &S) -> ContourMetrics {
+    let (mut vertex, mut segment, mut normal) = (Stat::default(), Stat::default(), Stat::default());
+    let mut segments = 0;
+
+    for polyline in grid.contour() {
+        let n = polyline.points.len();
+        for p in &polyline.points {
+            vertex.add(sdf.distance(*p).abs());
+        }
+
+        let count = if polyline.closed { n } else { n - 1 };
+        segments += count;
+        for i in 0..count {
+            let (a, b) = (polyline.points[i], polyline.points[(i + 1) % n]);
+            for s in 0..SEGMENT_SAMPLES {
+                let t = (s as f32 + 0.5) / SEGMENT_SAMPLES as f32;
+                segment.add(sdf.distance(a + (b - a) * t).abs());
+            }
+
+            // Perpendicular to the segment, on the side the contour's own normals face.
+            let along = b - a;
+            if along.norm() == 0.0 {
+                continue;
+            }
+            let mut perpendicular = Vector2::new(along.y, -along.x).normalize();
+            if perpendicular.dot(&(polyline.normals[i] + polyline.normals[(i + 1) % n])) < 0.0 {
+                perpendicular = -perpendicular;
+            }
+            let gradient = sdf.normal((a + b) * 0.5);
+            if gradient.norm() > 0.0 {
+                let cos = perpendicular.dot(&gradient.normalize()).max(-1.0).min(1.0);
+                normal.add(cos.acos().to_degrees());
+            }
+        }
+    }
+
+    ContourMetrics {
+        method: grid.vertex_method,
+        cells: (grid.width - 1, grid.height - 1),
+        cell_size: grid.spacing,
+        segments,
+        max_vertex_error: vertex.max,
+        mean_vertex_error: vertex.mean(),
+        max_segment_error: segment.max,
+        mean_segment_error: segment.mean(),
+        max_normal_deviation: normal.max,
+        mean_normal_deviation: normal.mean(),
+    }
+}
+
+/// Contour `sdf` over min..max with each vertex method, at each resolution
+/// (faces across, faces down).
+pub fn compare<S: Sdf + ?Sized>(sdf: &S, min: Vector2<f32>, max: Vector2<f32>,
+                                resolutions: &[(usize, usize)]) -> Vec<ContourMetrics> {
+    let mut rows = vec![];
+    for cells in resolutions {
+        for method in [VertexMethod::MassPoint, VertexMethod::Qef].iter() {
+            let mut grid = Grid::with_resolution(min, max, *cells);
+            grid.set_vertex_method(*method);
+            grid.apply_sdf(sdf);
+            rows.push(measure(&grid, sdf));
+        }
+    }
+    rows
+}
+
+const COLUMNS: [&str; 10] = ["method", "cells", "cell size", "segments",
+                             "max vertex error", "mean vertex error",
+                             "max segment error", "mean segment error",
+                             "max normal deviation", "mean normal deviation"];
+
+fn fields(m: &ContourMetrics) -> Vec<String> {
+    vec![
+        format!("{:?}", m.method),
+        format!("{}x{}", m.cells.0, m.cells.1),
+        format!("{:.4}x{:.4}", m.cell_size.x, m.cell_size.y),
+        format!("{}", m.segments),
+        format!("{:.5}", m.max_vertex_error),
+        format!("{:.5}", m.mean_vertex_error),
+        format!("{:.5}", m.max_segment_error),
+        format!("{:.5}", m.mean_segment_error),
+        format!("{:.3}", m.max_normal_deviation),
+        format!("{:.3}", m.mean_normal_deviation),
+    ]
+}
+
+pub fn to_csv(rows: &[ContourMetrics]) -> String {
+    let mut out = COLUMNS.iter().map(|c| c.replace(' ', "_")).collect::<Vec<_>>().join(",") + "\n";
+    for m in rows {
+        out += &(fields(m).join(",") + "\n");
+    }
+    out
+}
+
+pub fn to_markdown(rows: &[ContourMetrics]) -> String {
+    let mut out = format!("| {} |\n", COLUMNS.join(" | "));
+    out += &format!("|{}\n", "---|".repeat(COLUMNS.len()));
+    for m in rows {
+        out += &format!("| {} |\n", fields(m).join(" | "));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdf::*;
+
+    fn square() -> impl Sdf {
+        rect(Vector2::new(4.0, 3.0)).rotate(0.3).translate(Vector2::new(10.2, 9.7))
+    }
+
+    #[test]
+    fn test_qef_keeps_corners() {
+        let rows = compare(&square(), Vector2::new(0.0, 0.0), Vector2::new(20.0, 20.0), &[(16, 16)]);
+        let (mass_point, qef) = (&rows[0], &rows[1]);
+        assert_eq!((mass_point.method, qef.method), (VertexMethod::MassPoint, VertexMethod::Qef));
+        assert_eq!(mass_point.segments, qef.segments);
+        assert!(qef.max_vertex_error < mass_point.max_vertex_error * 0.5,
+                "qef {} vs mass point {}", qef.max_vertex_error, mass_point.max_vertex_error);
+    }
+
+    #[test]
+    fn test_finer_is_better() {
+        let shape = circle(6.3).translate(Vector2::new(10.1, 9.8));
+        let resolutions = [(8, 8), (16, 16), (32, 32), (64, 64)];
+        let rows = compare(&shape, Vector2::new(0.0, 0.0), Vector2::new(20.0, 20.0), &resolutions);
+        assert_eq!(rows.len(), 8);
+
+        for method in 0..2 {
+            let (coarse, fine) = (&rows[method], &rows[6 + method]);
+            assert!(fine.segments > coarse.segments * 4);
+            assert!(fine.max_segment_error < coarse.max_segment_error);
+            assert!(fine.mean_normal_deviation < coarse.mean_normal_deviation);
+            assert!(fine.mean_vertex_error <= fine.max_vertex_error);
+        }
+    }
+
+    #[test]
+    fn test_tables() {
+        let rows = compare(&square(), Vector2::new(0.0, 0.0), Vector2::new(20.0, 20.0), &[(8, 8), (12, 10)]);
+        let csv = to_csv(&rows);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("method,cells,cell_size,segments,"));
+        assert!(lines[4].starts_with("Qef,12x10,1.6667x2.0000,"));
+        assert!(lines.iter().all(|l| l.split(',').count() == COLUMNS.len()));
+
+        let markdown = to_markdown(&rows);
+        assert_eq!(markdown.lines().count(), 6);
+        assert!(markdown.lines().nth(2).unwrap().starts_with("| MassPoint | 8x8 |"));
+    }
+}
@@ -0,0 +1,314 @@
+// Packing (nesting) of irregular, SDF defined pieces inside an SDF container.
+//
+// Each piece is tried, biggest first, at every grid vertex and allowed rotation,
+// in reading order (top row first, then left to right), and placed at the first spot
+// where it fits.
+//
+// A placement is checked only at the grid vertices within half a cell diagonal of the
+// piece. Since any point of the piece is at most that far from one of those vertices,
+// and a true SDF changes by at most 1 per unit moved, requiring that much extra room at
+// the vertices guarantees the clearance holds everywhere, not just at the samples.
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use nalgebra::Vector2;
+
+use crate::sdf::Sdf;
+use crate::{Grid, Polyline};
+
+pub struct Piece {
+    // Shape of the piece, around its own origin.
+    pub sdf: Box<dyn Sdf>,
+    // Every point of the piece is within this distance of its origin. A piece with a
+    // NaN or infinite radius is never placed.
+    pub radius: f32,
+}
+
+impl Piece {
+    pub fn new<S: Sdf + 'static>(sdf: S, radius: f32) -> Piece {
+        Piece { sdf: Box::new(sdf), radius }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NestConfig {
+    // Rotations (in radians, counter-clockwise) each piece may be placed at.
+    pub rotations: Vec<f32>,
+    // Minimum gap between pieces, and between a piece and the container's edge.
+    pub clearance: f32,
+}
+
+impl NestConfig {
+    pub fn new() -> NestConfig {
+        NestConfig { rotations: vec![0.0], clearance: 0.0 }
+    }
+}
+
+// Where a piece was placed: rotate about its origin, then translate.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub translation: Vector2<f32>,
+    pub rotation: f32,
+}
+
+impl Transform {
+    // Map a world position into the piece's own space.
+    pub fn to_local(&self, p: Vector2<f32>) -> Vector2<f32> {
+        let d = p - self.translation;
+        let (sin, cos) = self.rotation.sin_cos();
+        Vector2::new(cos * d.x + sin * d.y, -sin * d.x + cos * d.y)
+    }
+
+    pub fn to_world(&self, p: Vector2<f32>) -> Vector2<f32> {
+        let (sin, cos) = self.rotation.sin_cos();
+        Vector2::new(cos * p.x - sin * p.y, sin * p.x + cos * p.y) + self.translation
+    }
+}
+
+struct Nester<'a> {
+    grid: &'a Grid,
+    // Distance to the nearest placed piece at each vertex. Only kept up to date near
+    // the pieces; further away it's left at f32::MAX, which is just as good for the check.
+    placed: Vec<f32>,
+    clearance: f32,
+}
+
+impl<'a> Nester<'a> {
+    // Indices of the vertices within `radius` of `center`.
+    fn vertices_near(&self, center: Vector2<f32>, radius: f32) -> Vec<usize> {
+        let grid = self.grid;
+        let (w, h) = (grid.width as i64, grid.height as i64);
+        let lo = center - grid.origin - Vector2::new(radius, radius);
+        let hi = center - grid.origin + Vector2::new(radius, radius);
+        let (i0, i1) = (((lo.x / grid.spacing.x).floor() as i64).max(0),
+                        ((hi.x / grid.spacing.x).ceil() as i64).min(w - 1));
+        let (j0, j1) = (((lo.y / grid.spacing.y).floor() as i64).max(0),
+                        ((hi.y / grid.spacing.y).ceil() as i64).min(h - 1));
+
+        let mut indices = vec![];
+        for j in j0..=j1 {
+            for i in i0..=i1 {
+                indices.push((i + j * w) as usize);
+            }
+        }
+        indices
+    }
+
+    fn fits(&self, piece: &Piece, transform: &Transform) -> bool {
+        let margin = self.grid.spacing.norm() / 2.0;
+        let room = self.clearance + margin;
+
+        // The piece must not hang off of the sampled region either.
+        if !piece.radius.is_finite() {
+            return false;
+        }
+        let origin = self.grid.origin;
+        let extent = self.grid.max();
+        let t = transform.translation;
+        if t.x - piece.radius < origin.x || t.y - piece.radius < origin.y
+            || t.x + piece.radius > extent.x || t.y + piece.radius > extent.y {
+            return false;
+        }
+
+        for v in self.vertices_near(transform.translation, piece.radius + margin) {
+            let pos = self.grid.verts[v].pos;
+            if piece.sdf.distance(transform.to_local(pos)) > margin {
+                continue;
+            }
+            if self.grid.verts[v].sdf > -room || self.placed[v] < room {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn place(&mut self, piece: &Piece, transform: &Transform) {
+        let reach = piece.radius + self.clearance + self.grid.spacing.norm() * 2.0;
+        for v in self.vertices_near(transform.translation, reach) {
+            let d = piece.sdf.distance(transform.to_local(self.grid.verts[v].pos));
+            self.placed[v] = self.placed[v].min(d);
+        }
+    }
+}
+
+/// Place `pieces` inside the shape sampled in `grid` (see Grid::apply_sdf).
+/// The grid's resolution is the resolution of the placements.
+/// Returns a transform for each piece, or None if it didn't fit.
+pub fn nest(grid: &Grid, pieces: &[Piece], config: &NestConfig) -> Vec<Option<Transform>> {
+    let mut nester = Nester {
+        grid,
+        placed: vec![std::f32::MAX; grid.verts.len()],
+        clearance: config.clearance,
+    };
+
+    let mut order: Vec<usize> = (0..pieces.len()).collect();
+    order.sort_by(|a, b| pieces[*b].radius.partial_cmp(&pieces[*a].radius).unwrap_or(Ordering::Equal));
+
+    let mut transforms = vec![None; pieces.len()];
+    for i in order {
+        let piece = &pieces[i];
+        'search: for v in &grid.verts {
+            for rotation in &config.rotations {
+                let transform = Transform { translation: v.pos, rotation: *rotation };
+                if nester.fits(piece, &transform) {
+                    nester.place(piece, &transform);
+                    transforms[i] = Some(transform);
+                    break 'search;
+                }
+            }
+        }
+    }
+    transforms
+}
+
+// Contour an SDF over the area covered by `scratch`, replacing what was sampled there.
+fn contour<S: Sdf + ?Sized>(scratch: &mut Grid, sdf: &S) -> Vec<Polyline> {
+    scratch.apply_sdf(sdf);
+    scratch.contour()
+}
+
+/// Write the container and the placed pieces to an SVG, `scale` pixels per unit.
+/// The shapes are contoured at the resolution of `grid`.
+pub fn write_svg(path: &Path, grid: &Grid, container: &dyn Sdf, pieces: &[Piece],
+                 transforms: &[Option<Transform>], scale: f32) -> io::Result<()> {
+    let origin = grid.origin;
+    let size = (grid.max() - origin) * scale;
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">",
+             size.x.ceil(), size.y.ceil())?;
+
+    let write_contour = |out: &mut BufWriter<File>, contour: &[Polyline], colour: &str| -> io::Result<()> {
+        for polyline in contour {
+            let points: Vec<String> = polyline.points.iter()
+                .map(|p| { let p = (p - origin) * scale; format!("{},{}", p.x, p.y) })
+                .collect();
+            writeln!(out, "<{} points=\"{}\" fill=\"none\" stroke=\"{}\"/>",
+                     if polyline.closed { "polygon" } else { "polyline" }, points.join(" "), colour)?;
+        }
+        Ok(())
+    };
+
+    let mut scratch = grid.with_same_layout();
+    write_contour(&mut out, &contour(&mut scratch, container), "black")?;
+
+    for (piece, transform) in pieces.iter().zip(transforms.iter()) {
+        if let Some(t) = transform {
+            let outline = contour(&mut scratch, &|p: Vector2<f32>| piece.sdf.distance(t.to_local(p)));
+            write_contour(&mut out, &outline, "red")?;
+
+            let (c, axis) = ((t.translation - origin) * scale,
+                             (t.to_world(Vector2::x()) - origin) * scale);
+            writeln!(out, "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"blue\"/>",
+                     c.x, c.y, axis.x, axis.y)?;
+        }
+    }
+
+    writeln!(out, "</svg>")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdf::*;
+
+    // Check the layout densely, between the grid vertices.
+    fn assert_valid(container: &dyn Sdf, pieces: &[Piece], transforms: &[Option<Transform>],
+                    clearance: f32) {
+        let step = 0.1;
+        for (i, (piece, t)) in pieces.iter().zip(transforms.iter()).enumerate() {
+            let t = t.expect("piece should have been placed");
+            let r = piece.radius;
+            let mut y = -r;
+            while y <= r {
+                let mut x = -r;
+                while x <= r {
+                    let local = Vector2::new(x, y);
+                    x += step;
+                    if piece.sdf.distance(local) > 0.0 {
+                        continue;
+                    }
+                    let p = t.to_world(local);
+                    assert!(container.distance(p) <= -clearance + 1e-3, "piece {} leaves container", i);
+                    for (j, (other, u)) in pieces.iter().zip(transforms.iter()).enumerate() {
+                        if i != j {
+                            let d = other.sdf.distance(u.unwrap().to_local(p));
+                            assert!(d >= clearance - 1e-3, "piece {} overlaps {}: {}", i, j, d);
+                        }
+                    }
+                }
+                y += step;
+            }
+        }
+    }
+
+    #[test]
+    fn test_nest_in_circle() {
+        let container = circle(9.0).translate(Vector2::new(10.0, 10.0));
+        let mut grid = Grid::new(21);
+        grid.apply_sdf(&container);
+
+        let mut pieces = vec![];
+        for _ in 0..2 {
+            pieces.push(Piece::new(rect(Vector2::new(2.0, 1.0)), 5f32.sqrt()));
+            pieces.push(Piece::new(circle(1.5), 1.5));
+            pieces.push(Piece::new(capsule(Vector2::new(-1.0, 0.0), Vector2::new(1.0, 0.0), 0.5), 1.5));
+        }
+        let mut config = NestConfig::new();
+        config.clearance = 0.5;
+        config.rotations = vec![0.0, std::f32::consts::FRAC_PI_2];
+
+        let transforms = nest(&grid, &pieces, &config);
+        assert_valid(&container, &pieces, &transforms, config.clearance);
+    }
+
+    #[test]
+    fn test_nest_needs_rotation() {
+        // A tall, thin slot only fits the plank standing up.
+        let container = rect(Vector2::new(2.0, 6.0)).translate(Vector2::new(5.0, 7.0));
+        let mut grid = Grid::new(15);
+        grid.apply_sdf(&container);
+
+        let pieces = [Piece::new(rect(Vector2::new(4.0, 0.5)), 17f32.sqrt())];
+        let mut config = NestConfig::new();
+        assert_eq!(nest(&grid, &pieces, &config), vec![None]);
+
+        config.rotations.push(std::f32::consts::FRAC_PI_2);
+        let transforms = nest(&grid, &pieces, &config);
+        assert_eq!(transforms[0].unwrap().rotation, std::f32::consts::FRAC_PI_2);
+        assert_valid(&container, &pieces, &transforms, 0.0);
+    }
+
+    #[test]
+    fn test_nest_bad_radius() {
+        let container = circle(9.0).translate(Vector2::new(10.0, 10.0));
+        let mut grid = Grid::new(21);
+        grid.apply_sdf(&container);
+
+        let pieces = [Piece::new(circle(1.0), std::f32::NAN), Piece::new(circle(1.0), 1.0),
+                      Piece::new(circle(1.0), std::f32::INFINITY)];
+        let transforms = nest(&grid, &pieces, &NestConfig::new());
+        assert_eq!((transforms[0], transforms[2]), (None, None));
+        assert!(transforms[1].is_some());
+    }
+
+    #[test]
+    fn test_write_svg() {
+        let container = circle(6.0).translate(Vector2::new(7.0, 7.0));
+        let mut grid = Grid::new(15);
+        grid.apply_sdf(&container);
+        let pieces = [Piece::new(circle(2.0), 2.0), Piece::new(circle(2.0), 2.0)];
+        let transforms = nest(&grid, &pieces, &NestConfig::new());
+
+        let path = std::env::temp_dir().join("packing2d_test_nesting.svg");
+        write_svg(&path, &grid, &container, &pieces, &transforms, 10.0).unwrap();
+        let svg = std::fs::read_to_string(&path).unwrap();
+        assert!(svg.contains("stroke=\"black\""));
+        assert!(svg.contains("stroke=\"red\""));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,501 @@
+// Rectangle bin packing.
+//
+// Two heuristics, as described in Jukka Jylänki's
+// "A Thousand Ways to Pack the Bin" (2010):
+//  * MaxRects: keeps a list of maximal free rectangles, places using best short side fit.
+//    Tighter, but slower.
+//  * Skyline: keeps the top edge of the packed rectangles, places bottom-left.
+//    Faster, wastes space under overhangs.
+
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, w: u32, h: u32) -> Rect {
+        Rect { x, y, w, h }
+    }
+
+    pub fn right(&self) -> u32 {
+        self.x + self.w
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.y + self.h
+    }
+
+    pub fn area(&self) -> u64 {
+        self.w as u64 * self.h as u64
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.right() && other.x < self.right()
+            && self.y < other.bottom() && other.y < self.bottom()
+    }
+
+    pub fn contains(&self, other: &Rect) -> bool {
+        self.x <= other.x && self.y <= other.y
+            && self.right() >= other.right() && self.bottom() >= other.bottom()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Heuristic {
+    MaxRects,
+    Skyline,
+}
+
+#[derive(Debug, Clone)]
+pub struct PackerConfig {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub heuristic: Heuristic,
+    // Allow rectangles to be turned 90 degrees if they fit better.
+    pub allow_rotation: bool,
+    // Empty pixels left between neighbouring rectangles.
+    pub padding: u32,
+    // Round each page's size up to a power of two. The maximum is rounded down to one
+    // first, so the rounded page still fits.
+    pub power_of_two: bool,
+}
+
+impl PackerConfig {
+    pub fn new(max_width: u32, max_height: u32) -> PackerConfig {
+        PackerConfig {
+            max_width,
+            max_height,
+            heuristic: Heuristic::MaxRects,
+            allow_rotation: false,
+            padding: 0,
+            power_of_two: false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Placement {
+    pub page: usize,
+    // Where the rectangle ended up. If rotated, w and h are swapped from the input size.
+    pub rect: Rect,
+    pub rotated: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Packing {
+    // One per input rectangle, in the order they were given.
+    pub placements: Vec<Placement>,
+    // Final (width, height) of each page.
+    pub pages: Vec<(u32, u32)>,
+}
+
+impl Packing {
+    // Fraction of the pages' area covered by rectangles.
+    pub fn efficiency(&self) -> f32 {
+        let used: u64 = self.placements.iter().map(|p| p.rect.area()).sum();
+        let total: u64 = self.pages.iter().map(|p| p.0 as u64 * p.1 as u64).sum();
+        if total == 0 {
+            return 1.0;
+        }
+        used as f32 / total as f32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PackError {
+    // Rectangle `index` wouldn't fit on an empty page.
+    TooLarge { index: usize, size: (u32, u32) },
+}
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackError::TooLarge { index, size } => {
+                write!(f, "rectangle {} ({}x{}) is larger than a page", index, size.0, size.1)
+            }
+        }
+    }
+}
+
+impl Error for PackError {}
+
+trait Bin {
+    // Find a place for a w x h rectangle, returning it and whether it was rotated.
+    fn insert(&mut self, w: u32, h: u32, allow_rotation: bool) -> Option<(Rect, bool)>;
+}
+
+struct MaxRectsBin {
+    free: Vec<Rect>,
+}
+
+impl MaxRectsBin {
+    fn new(width: u32, height: u32) -> MaxRectsBin {
+        MaxRectsBin { free: vec![Rect::new(0, 0, width, height)] }
+    }
+
+    // Best short side fit: the free rectangle with the least leftover on its shorter side.
+    fn find(&self, w: u32, h: u32) -> Option<(Rect, (u32, u32))> {
+        let mut best: Option<(Rect, (u32, u32))> = None;
+        for f in &self.free {
+            if f.w >= w && f.h >= h {
+                let leftover = (f.w - w, f.h - h);
+                let score = (leftover.0.min(leftover.1), leftover.0.max(leftover.1));
+                if best.map_or(true, |b| score < b.1) {
+                    best = Some((Rect::new(f.x, f.y, w, h), score));
+                }
+            }
+        }
+        best
+    }
+
+    fn place(&mut self, placed: &Rect) {
+        let mut split = Vec::with_capacity(self.free.len() + 4);
+        for f in self.free.drain(..) {
+            if !f.intersects(placed) {
+                split.push(f);
+                continue;
+            }
+            // Up to four maximal rectangles around the placed one.
+            if placed.x > f.x {
+                split.push(Rect::new(f.x, f.y, placed.x - f.x, f.h));
+            }
+            if placed.right() < f.right() {
+                split.push(Rect::new(placed.right(), f.y, f.right() - placed.right(), f.h));
+            }
+            if placed.y > f.y {
+                split.push(Rect::new(f.x, f.y, f.w, placed.y - f.y));
+            }
+            if placed.bottom() < f.bottom() {
+                split.push(Rect::new(f.x, placed.bottom(), f.w, f.bottom() - placed.bottom()));
+            }
+        }
+
+        // Drop any free rectangle that is inside another.
+        let mut i = 0;
+        while i < split.len() {
+            let contained = split.iter().enumerate().any(|(j, other)| {
+                j != i && other.contains(&split[i]) && (other != &split[i] || j < i)
+            });
+            if contained {
+                split.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        self.free = split;
+    }
+}
+
+impl Bin for MaxRectsBin {
+    fn insert(&mut self, w: u32, h: u32, allow_rotation: bool) -> Option<(Rect, bool)> {
+        let upright = self.find(w, h).map(|(r, score)| (r, score, false));
+        let turned = if allow_rotation && w != h {
+            self.find(h, w).map(|(r, score)| (r, score, true))
+        } else {
+            None
+        };
+
+        let best = match (upright, turned) {
+            (Some(a), Some(b)) => if b.1 < a.1 { b } else { a },
+            (a, b) => a.or(b)?,
+        };
+        self.place(&best.0);
+        Some((best.0, best.2))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Segment {
+    x: u32,
+    y: u32,
+    w: u32,
+}
+
+struct SkylineBin {
+    width: u32,
+    height: u32,
+    skyline: Vec<Segment>,
+}
+
+impl SkylineBin {
+    fn new(width: u32, height: u32) -> SkylineBin {
+        SkylineBin { width, height, skyline: vec![Segment { x: 0, y: 0, w: width }] }
+    }
+
+    // The height a w x h rectangle would rest at if its left edge is at segment i.
+    fn fits(&self, i: usize, w: u32, h: u32) -> Option<u32> {
+        let x = self.skyline[i].x;
+        if x + w > self.width {
+            return None;
+        }
+        let mut y = 0;
+        let mut remaining = w as i64;
+        let mut j = i;
+        while remaining > 0 {
+            y = y.max(self.skyline[j].y);
+            if y + h > self.height {
+                return None;
+            }
+            remaining -= self.skyline[j].w as i64;
+            j += 1;
+        }
+        Some(y)
+    }
+
+    // Bottom-left: lowest top edge, then the leftmost.
+    fn find(&self, w: u32, h: u32) -> Option<(Rect, (u32, u32))> {
+        let mut best: Option<(Rect, (u32, u32))> = None;
+        for i in 0..self.skyline.len() {
+            if let Some(y) = self.fits(i, w, h) {
+                let score = (y + h, self.skyline[i].x);
+                if best.map_or(true, |b| score < b.1) {
+                    best = Some((Rect::new(self.skyline[i].x, y, w, h), score));
+                }
+            }
+        }
+        best
+    }
+
+    fn place(&mut self, placed: &Rect) {
+        let mut skyline = Vec::with_capacity(self.skyline.len() + 2);
+        let mut inserted = false;
+        for s in &self.skyline {
+            let end = s.x + s.w;
+            if end <= placed.x || s.x >= placed.right() {
+                if !inserted && s.x >= placed.right() {
+                    skyline.push(Segment { x: placed.x, y: placed.bottom(), w: placed.w });
+                    inserted = true;
+                }
+                skyline.push(*s);
+                continue;
+            }
+            // Keep whatever part of the segment sticks out either side.
+            if s.x < placed.x {
+                skyline.push(Segment { x: s.x, y: s.y, w: placed.x - s.x });
+            }
+            if !inserted {
+                skyline.push(Segment { x: placed.x, y: placed.bottom(), w: placed.w });
+                inserted = true;
+            }
+            if end > placed.right() {
+                skyline.push(Segment { x: placed.right(), y: s.y, w: end - placed.right() });
+            }
+        }
+
+        // Merge neighbours at the same height.
+        let mut merged: Vec<Segment> = Vec::with_capacity(skyline.len());
+        for s in skyline {
+            match merged.last_mut() {
+                Some(last) if last.y == s.y => last.w += s.w,
+                _ => merged.push(s),
+            }
+        }
+        self.skyline = merged;
+    }
+}
+
+impl Bin for SkylineBin {
+    fn insert(&mut self, w: u32, h: u32, allow_rotation: bool) -> Option<(Rect, bool)> {
+        let upright = self.find(w, h).map(|(r, score)| (r, score, false));
+        let turned = if allow_rotation && w != h {
+            self.find(h, w).map(|(r, score)| (r, score, true))
+        } else {
+            None
+        };
+
+        let best = match (upright, turned) {
+            (Some(a), Some(b)) => if b.1 < a.1 { b } else { a },
+            (a, b) => a.or(b)?,
+        };
+        self.place(&best.0);
+        Some((best.0, best.2))
+    }
+}
+
+fn new_bin(config: &PackerConfig) -> Box<dyn Bin> {
+    // Every rectangle is padded on its right and bottom. Grow the page by the
+    // same amount, so there is no padding on the page's own right and bottom edge.
+    let width = config.max_width + config.padding;
+    let height = config.max_height + config.padding;
+    match config.heuristic {
+        Heuristic::MaxRects => Box::new(MaxRectsBin::new(width, height)),
+        Heuristic::Skyline => Box::new(SkylineBin::new(width, height)),
+    }
+}
+
+// The largest power of two no bigger than x, or 0.
+fn floor_power_of_two(x: u32) -> u32 {
+    if x == 0 { 0 } else { 1 << (31 - x.leading_zeros()) }
+}
+
+/// Pack rectangles of the given (width, height) into as few pages as possible.
+pub fn pack(sizes: &[(u32, u32)], config: &PackerConfig) -> Result<Packing, PackError> {
+    let rounded;
+    let config = if config.power_of_two {
+        rounded = PackerConfig {
+            max_width: floor_power_of_two(config.max_width),
+            max_height: floor_power_of_two(config.max_height),
+            ..config.clone()
+        };
+        &rounded
+    } else {
+        config
+    };
+
+    // Big rectangles first.
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|i| {
+        let (w, h) = sizes[*i];
+        (std::cmp::Reverse(w.max(h)), std::cmp::Reverse(w.min(h)))
+    });
+
+    let mut bins: Vec<Box<dyn Bin>> = vec![];
+    let mut placements = vec![None; sizes.len()];
+    for i in order {
+        let (w, h) = sizes[i];
+        let padded = (w + config.padding, h + config.padding);
+
+        let mut found = None;
+        for (page, bin) in bins.iter_mut().enumerate() {
+            if let Some(r) = bin.insert(padded.0, padded.1, config.allow_rotation) {
+                found = Some((page, r));
+                break;
+            }
+        }
+        if found.is_none() {
+            let mut bin = new_bin(config);
+            let r = bin.insert(padded.0, padded.1, config.allow_rotation)
+                .ok_or(PackError::TooLarge { index: i, size: (w, h) })?;
+            bins.push(bin);
+            found = Some((bins.len() - 1, r));
+        }
+
+        let (page, (rect, rotated)) = found.unwrap();
+        let (w, h) = if rotated { (h, w) } else { (w, h) };
+        placements[i] = Some(Placement { page, rect: Rect::new(rect.x, rect.y, w, h), rotated });
+    }
+    let placements: Vec<Placement> = placements.into_iter().map(|p| p.unwrap()).collect();
+
+    // Shrink each page to what was used.
+    let mut pages = vec![(0, 0); bins.len()];
+    for p in &placements {
+        let page = &mut pages[p.page];
+        page.0 = page.0.max(p.rect.right());
+        page.1 = page.1.max(p.rect.bottom());
+    }
+    if config.power_of_two {
+        for page in pages.iter_mut() {
+            page.0 = page.0.next_power_of_two().min(config.max_width);
+            page.1 = page.1.next_power_of_two().min(config.max_height);
+        }
+    }
+
+    Ok(Packing { placements, pages })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn assert_valid(sizes: &[(u32, u32)], packing: &Packing, config: &PackerConfig) {
+        assert_eq!(sizes.len(), packing.placements.len());
+        for (i, p) in packing.placements.iter().enumerate() {
+            let size = if p.rotated { (sizes[i].1, sizes[i].0) } else { sizes[i] };
+            assert_eq!((p.rect.w, p.rect.h), size);
+            assert!(p.rect.right() <= packing.pages[p.page].0);
+            assert!(p.rect.bottom() <= packing.pages[p.page].1);
+
+            let padded = Rect::new(p.rect.x, p.rect.y,
+                                   p.rect.w + config.padding, p.rect.h + config.padding);
+            for q in &packing.placements[i + 1..] {
+                if q.page == p.page {
+                    assert!(!padded.intersects(&q.rect), "{:?} overlaps {:?}", p, q);
+                    let q_padded = Rect::new(q.rect.x, q.rect.y,
+                                             q.rect.w + config.padding, q.rect.h + config.padding);
+                    assert!(!q_padded.intersects(&p.rect), "{:?} overlaps {:?}", q, p);
+                }
+            }
+        }
+    }
+
+    fn random_sizes(seed: u64, n: usize) -> Vec<(u32, u32)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n).map(|_| (rng.gen_range(1, 64), rng.gen_range(1, 64))).collect()
+    }
+
+    #[test]
+    fn test_perfect_fit() {
+        let sizes = [(64, 32), (32, 32), (32, 32), (64, 64)];
+        for heuristic in [Heuristic::MaxRects, Heuristic::Skyline].iter() {
+            let mut config = PackerConfig::new(128, 64);
+            config.heuristic = *heuristic;
+            let packing = pack(&sizes, &config).unwrap();
+            assert_valid(&sizes, &packing, &config);
+            assert_eq!(packing.pages, vec![(128, 64)]);
+            assert_eq!(packing.efficiency(), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_random_packings_are_valid() {
+        for (seed, heuristic) in [(1, Heuristic::MaxRects), (2, Heuristic::Skyline)].iter() {
+            for (rotation, padding, pot) in [(false, 0, false), (true, 2, true)].iter() {
+                let sizes = random_sizes(*seed, 200);
+                let config = PackerConfig {
+                    max_width: 256,
+                    max_height: 256,
+                    heuristic: *heuristic,
+                    allow_rotation: *rotation,
+                    padding: *padding,
+                    power_of_two: *pot,
+                };
+                let packing = pack(&sizes, &config).unwrap();
+                assert_valid(&sizes, &packing, &config);
+                assert!(packing.pages.len() > 1);
+                assert!(packing.efficiency() > 0.6, "{:?}: {}", heuristic, packing.efficiency());
+                if *pot {
+                    for page in &packing.pages {
+                        assert!(page.0.is_power_of_two() && page.1.is_power_of_two());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let sizes = [(10, 100)];
+        let mut config = PackerConfig::new(100, 10);
+        assert_eq!(pack(&sizes, &config).unwrap_err(),
+                   PackError::TooLarge { index: 0, size: (10, 100) });
+
+        config.allow_rotation = true;
+        let packing = pack(&sizes, &config).unwrap();
+        assert!(packing.placements[0].rotated);
+        assert_eq!(packing.placements[0].rect, Rect::new(0, 0, 100, 10));
+    }
+
+    #[test]
+    fn test_power_of_two_maximum() {
+        // 200x100 pages are really 128x64 once they must be powers of two.
+        let mut config = PackerConfig::new(200, 100);
+        config.power_of_two = true;
+        let sizes = random_sizes(3, 50);
+        let packing = pack(&sizes, &config).unwrap();
+        assert_valid(&sizes, &packing, &config);
+        for page in &packing.pages {
+            assert!(page.0.is_power_of_two() && page.1.is_power_of_two());
+            assert!(page.0 <= 128 && page.1 <= 64, "{:?}", page);
+        }
+        assert_eq!(pack(&[(150, 10)], &config).unwrap_err(),
+                   PackError::TooLarge { index: 0, size: (150, 10) });
+    }
+}
@@ -0,0 +1,329 @@
+// Scattering discs (rocks, asteroids, ...) inside the shape sampled in a Grid.
+//
+//  * poisson_disc: Bridson's "Fast Poisson Disk Sampling in Arbitrary Dimensions" (2007),
+//    with a radius per disc. Evenly spread, natural looking, not dense.
+//  * pack_circles: greedy maximal packing. Discs are dropped at random free spots until
+//    none fit; then the gaps are filled with the largest disc that fits each, down to
+//    the smallest allowed radius.
+//
+// Both are deterministic for a given seed.
+
+use std::collections::HashMap;
+
+use nalgebra::Vector2;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::Grid;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Disc {
+    pub center: Vector2<f32>,
+    pub radius: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RadiusDistribution {
+    Constant(f32),
+    Uniform(f32, f32),
+    // Uniform in log(radius): many small discs and a few big ones. With a lower bound of
+    // 0 or less, which has no log, it's plain Uniform.
+    LogUniform(f32, f32),
+}
+
+impl RadiusDistribution {
+    pub fn sample(&self, rng: &mut StdRng) -> f32 {
+        match *self {
+            RadiusDistribution::Constant(r) => r,
+            RadiusDistribution::Uniform(lo, hi) => if lo < hi { rng.gen_range(lo, hi) } else { lo },
+            RadiusDistribution::LogUniform(lo, hi) if lo <= 0.0 => RadiusDistribution::Uniform(lo, hi).sample(rng),
+            RadiusDistribution::LogUniform(lo, hi) => {
+                if lo < hi { rng.gen_range(lo.ln(), hi.ln()).exp() } else { lo }
+            }
+        }
+    }
+
+    pub fn range(&self) -> (f32, f32) {
+        match *self {
+            RadiusDistribution::Constant(r) => (r, r),
+            RadiusDistribution::Uniform(lo, hi) | RadiusDistribution::LogUniform(lo, hi) => (lo, hi),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScatterConfig {
+    pub radii: RadiusDistribution,
+    // Gap between neighbouring discs.
+    pub spacing: f32,
+    // Gap between a disc and the edge of the region.
+    pub boundary_margin: f32,
+    pub seed: u64,
+    // How many times to try before giving up on a spot (Bridson's k).
+    pub attempts: usize,
+}
+
+impl ScatterConfig {
+    pub fn new(radii: RadiusDistribution) -> ScatterConfig {
+        ScatterConfig {
+            radii,
+            spacing: 0.0,
+            boundary_margin: 0.0,
+            seed: 0,
+            attempts: 30,
+        }
+    }
+}
+
+// Bucket grid for finding discs near a point.
+struct DiscIndex {
+    cell: f32,
+    buckets: HashMap<(i32, i32), Vec<usize>>,
+    discs: Vec<Disc>,
+}
+
+impl DiscIndex {
+    fn new(max_radius: f32, spacing: f32) -> DiscIndex {
+        DiscIndex {
+            cell: (max_radius * 2.0 + spacing).max(std::f32::EPSILON),
+            buckets: HashMap::new(),
+            discs: vec![],
+        }
+    }
+
+    fn key(&self, p: Vector2<f32>) -> (i32, i32) {
+        ((p.x / self.cell).floor() as i32, (p.y / self.cell).floor() as i32)
+    }
+
+    fn insert(&mut self, disc: Disc) {
+        let key = self.key(disc.center);
+        self.buckets.entry(key).or_insert_with(Vec::new).push(self.discs.len());
+        self.discs.push(disc);
+    }
+
+    // Would a disc here be at least `spacing` away from all of the others?
+    fn is_free(&self, disc: &Disc, spacing: f32) -> bool {
+        let (ki, kj) = self.key(disc.center);
+        // Discs are at most a cell across, so only neighbouring buckets can be too close.
+        let reach = ((disc.radius + spacing) / self.cell).ceil() as i32 + 1;
+        for j in kj - reach..=kj + reach {
+            for i in ki - reach..=ki + reach {
+                if let Some(bucket) = self.buckets.get(&(i, j)) {
+                    for d in bucket {
+                        let other = &self.discs[*d];
+                        if (other.center - disc.center).norm() < other.radius + disc.radius + spacing {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+fn grid_bounds(grid: &Grid) -> (Vector2<f32>, Vector2<f32>) {
+    (grid.origin, grid.max())
+}
+
+fn in_region(grid: &Grid, disc: &Disc, margin: f32) -> bool {
+    let (min, max) = grid_bounds(grid);
+    let r = disc.radius + margin;
+    disc.center.x - r >= min.x && disc.center.y - r >= min.y
+        && disc.center.x + r <= max.x && disc.center.y + r <= max.y
+        && grid.sample(disc.center) <= -r
+}
+
+/// Poisson disc sampling of the inside of the shape sampled in `grid` (see Grid::apply_sdf).
+pub fn poisson_disc(grid: &Grid, config: &ScatterConfig) -> Vec<Disc> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let (_, max_radius) = config.radii.range();
+    let mut index = DiscIndex::new(max_radius, config.spacing);
+    let (min, max) = grid_bounds(grid);
+
+    // Seed with the first disc that lands inside.
+    for _ in 0..config.attempts * 100 {
+        let disc = Disc {
+            center: Vector2::new(rng.gen_range(min.x, max.x), rng.gen_range(min.y, max.y)),
+            radius: config.radii.sample(&mut rng),
+        };
+        if in_region(grid, &disc, config.boundary_margin) {
+            index.insert(disc);
+            break;
+        }
+    }
+
+    let mut active: Vec<usize> = (0..index.discs.len()).collect();
+    while !active.is_empty() {
+        let a = rng.gen_range(0, active.len());
+        let parent = index.discs[active[a]];
+
+        let mut found = false;
+        for _ in 0..config.attempts {
+            let radius = config.radii.sample(&mut rng);
+            let near = parent.radius + radius + config.spacing;
+            if near <= 0.0 {
+                // Nothing to keep apart: with no size and no spacing, every disc would land
+                // on its parent.
+                continue;
+            }
+            let distance = rng.gen_range(near, near * 2.0);
+            let angle = rng.gen_range(0.0, 2.0 * std::f32::consts::PI);
+            let disc = Disc {
+                center: parent.center + Vector2::new(angle.cos(), angle.sin()) * distance,
+                radius,
+            };
+            if in_region(grid, &disc, config.boundary_margin) && index.is_free(&disc, config.spacing) {
+                active.push(index.discs.len());
+                index.insert(disc);
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            active.swap_remove(a);
+        }
+    }
+    index.discs
+}
+
+/// Greedy maximal packing of the inside of the shape sampled in `grid`.
+/// Disc centres are placed on the grid vertices.
+pub fn pack_circles(grid: &Grid, config: &ScatterConfig) -> Vec<Disc> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let (min_radius, max_radius) = config.radii.range();
+    let (min, max) = grid_bounds(grid);
+
+    // Radius of the largest disc that could go at each vertex.
+    let mut room: Vec<f32> = grid.verts.iter().map(|v| {
+        let edge = (v.pos.x - min.x).min(v.pos.y - min.y).min(max.x - v.pos.x).min(max.y - v.pos.y);
+        (-v.sdf).min(edge) - config.boundary_margin
+    }).collect();
+
+    // Vertices already holding a disc. With no radius and no spacing (or less), a disc
+    // leaves room for another at its own centre.
+    let mut taken = vec![false; room.len()];
+    let mut discs = vec![];
+    let mut failures = 0;
+    loop {
+        let mut radius = config.radii.sample(&mut rng);
+        let candidates: Vec<usize> = (0..room.len()).filter(|v| !taken[*v] && room[*v] >= radius).collect();
+
+        let v = if !candidates.is_empty() {
+            candidates[rng.gen_range(0, candidates.len())]
+        } else {
+            failures += 1;
+            if failures < config.attempts {
+                continue;
+            }
+            // Fill the largest remaining gap instead.
+            let (v, largest) = room.iter().cloned().enumerate().filter(|(v, _)| !taken[*v])
+                .fold((0, std::f32::MIN), |a, b| if b.1 > a.1 { b } else { a });
+            if largest < min_radius {
+                break;
+            }
+            radius = largest.min(max_radius);
+            v
+        };
+
+        let disc = Disc { center: grid.verts[v].pos, radius };
+        for (r, vert) in room.iter_mut().zip(grid.verts.iter()) {
+            *r = r.min((vert.pos - disc.center).norm() - disc.radius - config.spacing);
+        }
+        taken[v] = true;
+        discs.push(disc);
+    }
+    discs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdf::*;
+
+    fn region() -> (Grid, impl Sdf) {
+        let shape = circle(12.0).translate(Vector2::new(15.0, 15.0))
+            .subtract(rect(Vector2::new(3.0, 8.0)).translate(Vector2::new(15.0, 10.0)));
+        let mut grid = Grid::new(31);
+        grid.apply_sdf(&shape);
+        (grid, shape)
+    }
+
+    fn assert_valid(discs: &[Disc], shape: &dyn Sdf, config: &ScatterConfig) {
+        let (lo, hi) = config.radii.range();
+        for (i, d) in discs.iter().enumerate() {
+            assert!(d.radius >= lo - 1e-5 && d.radius <= hi + 1e-5);
+            // The grid is sampled; allow for interpolation error.
+            assert!(shape.distance(d.center) <= -(d.radius + config.boundary_margin) + 0.1,
+                    "{:?} is outside", d);
+            for other in &discs[i + 1..] {
+                let gap = (d.center - other.center).norm() - d.radius - other.radius;
+                assert!(gap >= config.spacing - 1e-4, "{:?} overlaps {:?}", d, other);
+            }
+        }
+    }
+
+    #[test]
+    fn test_poisson_disc() {
+        let (grid, shape) = region();
+        let mut config = ScatterConfig::new(RadiusDistribution::Uniform(0.5, 1.5));
+        config.spacing = 0.25;
+        config.boundary_margin = 1.0;
+        config.seed = 33;
+
+        let discs = poisson_disc(&grid, &config);
+        assert!(discs.len() > 30, "only {} discs", discs.len());
+        assert_valid(&discs, &shape, &config);
+        assert_eq!(discs, poisson_disc(&grid, &config));
+
+        config.seed = 34;
+        assert_ne!(discs, poisson_disc(&grid, &config));
+    }
+
+    #[test]
+    fn test_pack_circles_is_maximal() {
+        let (grid, shape) = region();
+        let mut config = ScatterConfig::new(RadiusDistribution::LogUniform(0.75, 4.0));
+        config.boundary_margin = 0.5;
+        config.seed = 7;
+
+        let discs = pack_circles(&grid, &config);
+        assert_valid(&discs, &shape, &config);
+        assert_eq!(discs, pack_circles(&grid, &config));
+
+        // No vertex has room left for the smallest disc.
+        let (min_radius, _) = config.radii.range();
+        for v in &grid.verts {
+            let probe = Disc { center: v.pos, radius: min_radius };
+            let blocked = !in_region(&grid, &probe, config.boundary_margin)
+                || discs.iter().any(|d| (d.center - v.pos).norm() < d.radius + min_radius);
+            assert!(blocked, "room left at {}", v.pos);
+        }
+    }
+
+    #[test]
+    fn test_degenerate_radii() {
+        // Discs of no size used to be packed on the same vertex forever, and a log-uniform
+        // range from 0 took the log of 0.
+        let (grid, shape) = region();
+        let inside = grid.verts.iter().filter(|v| v.sdf <= 0.0).count();
+        let distributions = [RadiusDistribution::Constant(0.0), RadiusDistribution::Uniform(0.0, 1.0),
+                             RadiusDistribution::LogUniform(0.0, 1.0)];
+        for radii in distributions.iter() {
+            let config = ScatterConfig::new(*radii);
+            let discs = pack_circles(&grid, &config);
+            assert!(!discs.is_empty() && discs.len() <= inside, "{:?}: {} discs", radii, discs.len());
+            assert_valid(&discs, &shape, &config);
+        }
+        // Poisson sampling of discs with no size used to ask for a distance between 0 and 0.
+        let config = ScatterConfig::new(RadiusDistribution::Constant(0.0));
+        assert_eq!(poisson_disc(&grid, &config).len(), 1);
+
+        // Negative spacing lets discs overlap, but still puts at most one on each vertex.
+        let mut config = ScatterConfig::new(RadiusDistribution::Constant(1.0));
+        config.spacing = -3.0;
+        let discs = pack_circles(&grid, &config);
+        assert!(!discs.is_empty() && discs.len() <= inside, "{} discs", discs.len());
+        assert!(!poisson_disc(&grid, &config).is_empty());
+    }
+}
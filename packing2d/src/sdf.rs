@@ -0,0 +1,371 @@
+// Signed distance functions, and a small library for building them up
+// out of primitives, transforms and boolean operations.
+//
+// Like the rest of the grid, negative distances are inside the shape.
+//
+//     let scene = circle(1.0).translate(Vector2::new(5.0, 5.0))
+//         .smooth_union(rect(Vector2::new(2.0, 0.5)).rotate(0.3), 0.5);
+//     grid.apply_sdf(&scene);
+
+use nalgebra::Vector2;
+
+pub trait Sdf {
+    fn distance(&self, p: Vector2<f32>) -> f32;
+
+    fn normal(&self, p: Vector2<f32>) -> Vector2<f32> {
+        let epsilon = 0.01;
+        Vector2::new(self.distance(p + Vector2::x() * epsilon) - self.distance(p - Vector2::x() * epsilon),
+                     self.distance(p + Vector2::y() * epsilon) - self.distance(p - Vector2::y() * epsilon))
+            .normalize()
+    }
+
+    fn translate(self, offset: Vector2<f32>) -> Translate<Self> where Self: Sized {
+        Translate { sdf: self, offset }
+    }
+
+    // Counter-clockwise, in radians.
+    fn rotate(self, angle: f32) -> Rotate<Self> where Self: Sized {
+        Rotate { sdf: self, angle }
+    }
+
+    fn scale(self, factor: f32) -> Scale<Self> where Self: Sized {
+        Scale { sdf: self, factor }
+    }
+
+    // Grow the shape by `radius`, rounding off any corners.
+    fn round(self, radius: f32) -> Round<Self> where Self: Sized {
+        Round { sdf: self, radius }
+    }
+
+    // Hollow out the shape, leaving a shell `thickness` wide around the old surface.
+    fn onion(self, thickness: f32) -> Onion<Self> where Self: Sized {
+        Onion { sdf: self, thickness }
+    }
+
+    fn union<B: Sdf>(self, other: B) -> Union<Self, B> where Self: Sized {
+        Union { a: self, b: other, smooth: 0.0 }
+    }
+
+    fn intersect<B: Sdf>(self, other: B) -> Intersection<Self, B> where Self: Sized {
+        Intersection { a: self, b: other, smooth: 0.0 }
+    }
+
+    fn subtract<B: Sdf>(self, other: B) -> Difference<Self, B> where Self: Sized {
+        Difference { a: self, b: other, smooth: 0.0 }
+    }
+
+    // Blends the shapes together within distance `k` of where they meet.
+    fn smooth_union<B: Sdf>(self, other: B, k: f32) -> Union<Self, B> where Self: Sized {
+        Union { a: self, b: other, smooth: k }
+    }
+
+    fn smooth_intersect<B: Sdf>(self, other: B, k: f32) -> Intersection<Self, B> where Self: Sized {
+        Intersection { a: self, b: other, smooth: k }
+    }
+
+    fn smooth_subtract<B: Sdf>(self, other: B, k: f32) -> Difference<Self, B> where Self: Sized {
+        Difference { a: self, b: other, smooth: k }
+    }
+
+    fn boxed(self) -> Box<dyn Sdf> where Self: Sized + 'static {
+        Box::new(self)
+    }
+}
+
+// Any closure (or plain fn) is an SDF.
+impl<F: Fn(Vector2<f32>) -> f32> Sdf for F {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        self(p)
+    }
+}
+
+impl Sdf for Box<dyn Sdf> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        (**self).distance(p)
+    }
+
+    fn normal(&self, p: Vector2<f32>) -> Vector2<f32> {
+        (**self).normal(p)
+    }
+}
+
+// Polynomial smooth minimum (Inigo Quilez). Exactly min(a, b) when k == 0.
+pub fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).max(0.0).min(1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+pub fn smooth_max(a: f32, b: f32, k: f32) -> f32 {
+    -smooth_min(-a, -b, k)
+}
+
+//
+// Primitives. All are centred on the origin; use translate to move them.
+//
+
+pub struct Circle {
+    pub radius: f32,
+}
+
+pub fn circle(radius: f32) -> Circle {
+    Circle { radius }
+}
+
+impl Sdf for Circle {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        p.norm() - self.radius
+    }
+}
+
+pub struct Rect {
+    pub half_size: Vector2<f32>,
+}
+
+pub fn rect(half_size: Vector2<f32>) -> Rect {
+    Rect { half_size }
+}
+
+impl Sdf for Rect {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        let d = Vector2::new(p.x.abs() - self.half_size.x, p.y.abs() - self.half_size.y);
+        let outside = Vector2::new(d.x.max(0.0), d.y.max(0.0)).norm();
+        let inside = d.x.max(d.y).min(0.0);
+        outside + inside
+    }
+}
+
+// A line segment from a to b, thickened by radius.
+pub struct Capsule {
+    pub a: Vector2<f32>,
+    pub b: Vector2<f32>,
+    pub radius: f32,
+}
+
+pub fn capsule(a: Vector2<f32>, b: Vector2<f32>, radius: f32) -> Capsule {
+    Capsule { a, b, radius }
+}
+
+impl Sdf for Capsule {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        let pa = p - self.a;
+        let ba = self.b - self.a;
+        let h = (pa.dot(&ba) / ba.dot(&ba)).max(0.0).min(1.0);
+        (pa - ba * h).norm() - self.radius
+    }
+}
+
+// Everything on the far side of a line through the origin. `normal` points out of the shape.
+pub struct HalfPlane {
+    pub normal: Vector2<f32>,
+}
+
+pub fn half_plane(normal: Vector2<f32>) -> HalfPlane {
+    HalfPlane { normal: normal.normalize() }
+}
+
+impl Sdf for HalfPlane {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        p.dot(&self.normal)
+    }
+}
+
+//
+// Transforms
+//
+
+pub struct Translate<S> {
+    sdf: S,
+    offset: Vector2<f32>,
+}
+
+impl<S: Sdf> Sdf for Translate<S> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        self.sdf.distance(p - self.offset)
+    }
+}
+
+pub struct Rotate<S> {
+    sdf: S,
+    angle: f32,
+}
+
+impl<S: Sdf> Sdf for Rotate<S> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        // Rotate the sample point the other way.
+        let (sin, cos) = self.angle.sin_cos();
+        self.sdf.distance(Vector2::new(cos * p.x + sin * p.y, -sin * p.x + cos * p.y))
+    }
+}
+
+pub struct Scale<S> {
+    sdf: S,
+    factor: f32,
+}
+
+impl<S: Sdf> Sdf for Scale<S> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        self.sdf.distance(p / self.factor) * self.factor
+    }
+}
+
+pub struct Round<S> {
+    sdf: S,
+    radius: f32,
+}
+
+impl<S: Sdf> Sdf for Round<S> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        self.sdf.distance(p) - self.radius
+    }
+}
+
+pub struct Onion<S> {
+    sdf: S,
+    thickness: f32,
+}
+
+impl<S: Sdf> Sdf for Onion<S> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        self.sdf.distance(p).abs() - self.thickness / 2.0
+    }
+}
+
+//
+// Boolean operations
+//
+
+pub struct Union<A, B> {
+    a: A,
+    b: B,
+    smooth: f32,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        smooth_min(self.a.distance(p), self.b.distance(p), self.smooth)
+    }
+}
+
+pub struct Intersection<A, B> {
+    a: A,
+    b: B,
+    smooth: f32,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Intersection<A, B> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        smooth_max(self.a.distance(p), self.b.distance(p), self.smooth)
+    }
+}
+
+// a, with b cut out of it.
+pub struct Difference<A, B> {
+    a: A,
+    b: B,
+    smooth: f32,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Difference<A, B> {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        smooth_max(self.a.distance(p), -self.b.distance(p), self.smooth)
+    }
+}
+
+// The union of any number of shapes, eg. a scene built at runtime.
+pub struct Scene {
+    pub shapes: Vec<Box<dyn Sdf>>,
+    pub smooth: f32,
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene { shapes: vec![], smooth: 0.0 }
+    }
+
+    pub fn add<S: Sdf + 'static>(&mut self, shape: S) {
+        self.shapes.push(Box::new(shape));
+    }
+}
+
+impl Sdf for Scene {
+    fn distance(&self, p: Vector2<f32>) -> f32 {
+        self.shapes.iter()
+            .map(|s| s.distance(p))
+            .fold(std::f32::MAX, |a, b| smooth_min(a, b, self.smooth))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "expected {}, got {}", b, a);
+    }
+
+    #[test]
+    fn test_primitives() {
+        assert_near(circle(2.0).distance(Vector2::new(3.0, 0.0)), 1.0);
+        assert_near(circle(2.0).distance(Vector2::new(0.0, 0.0)), -2.0);
+
+        let r = rect(Vector2::new(2.0, 1.0));
+        assert_near(r.distance(Vector2::new(0.0, 0.0)), -1.0);
+        assert_near(r.distance(Vector2::new(5.0, 0.0)), 3.0);
+        assert_near(r.distance(Vector2::new(5.0, 5.0)), 5.0);
+
+        let c = capsule(Vector2::new(0.0, 0.0), Vector2::new(4.0, 0.0), 1.0);
+        assert_near(c.distance(Vector2::new(2.0, 3.0)), 2.0);
+        assert_near(c.distance(Vector2::new(7.0, 0.0)), 2.0);
+
+        assert_near(half_plane(Vector2::new(0.0, 2.0)).distance(Vector2::new(9.0, -3.0)), -3.0);
+    }
+
+    #[test]
+    fn test_transforms() {
+        let moved = circle(1.0).translate(Vector2::new(5.0, 5.0));
+        assert_near(moved.distance(Vector2::new(5.0, 5.0)), -1.0);
+
+        let turned = rect(Vector2::new(2.0, 0.5)).rotate(std::f32::consts::FRAC_PI_2);
+        assert_near(turned.distance(Vector2::new(0.0, 2.0)), 0.0);
+        assert_near(turned.distance(Vector2::new(2.0, 0.0)), 1.5);
+
+        assert_near(circle(1.0).scale(3.0).distance(Vector2::new(4.0, 0.0)), 1.0);
+        assert_near(circle(1.0).round(0.5).distance(Vector2::new(2.0, 0.0)), 0.5);
+        assert_near(circle(2.0).onion(0.5).distance(Vector2::new(0.0, 0.0)), 1.75);
+    }
+
+    #[test]
+    fn test_booleans() {
+        let a = circle(1.0);
+        let b = circle(1.0).translate(Vector2::new(1.5, 0.0));
+        let p = Vector2::new(0.75, 0.0);
+        assert_near(a.union(b).distance(p), -0.25);
+
+        let a = circle(1.0);
+        let b = circle(1.0).translate(Vector2::new(1.5, 0.0));
+        assert_near(a.subtract(b).distance(p), 0.25);
+
+        // Smoothing only ever adds material to a union.
+        let a = circle(1.0);
+        let b = circle(1.0).translate(Vector2::new(1.5, 0.0));
+        let smooth = a.smooth_union(b, 0.5);
+        assert!(smooth.distance(Vector2::new(0.75, 0.7)) < -0.01);
+        assert_near(smooth.distance(Vector2::new(-1.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn test_closures_and_trait_objects() {
+        let center = Vector2::new(2.0, 3.0);
+        let radius = 1.5;
+        let captured = move |p: Vector2<f32>| (p - center).norm() - radius;
+
+        let mut scene = Scene::new();
+        scene.add(captured);
+        scene.add(rect(Vector2::new(1.0, 1.0)).boxed());
+        assert_near(scene.distance(Vector2::new(2.0, 3.0)), -1.5);
+        assert_near(scene.distance(Vector2::new(0.0, 0.0)), -1.0);
+    }
+}
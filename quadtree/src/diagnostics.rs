@@ -0,0 +1,152 @@
+use crate::{HermiteGrid, Index, QuadTree};
+
+// Something inconsistent found in a HermiteGrid or a QuadTree built from it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GridDiagnostic {
+    // An interior edge that doesn't join exactly two dual vertices.
+    UnpairedEdge { edge: (Index, Index), dual_verts: usize },
+    // The contour leaves the grid through an edge on its boundary.
+    BoundaryCrossing { edge: (Index, Index) },
+    // An edge with hermite data, but both vertices have the same value.
+    SameValueEdge { edge: (Index, Index) },
+    // vertex_index_to_xy and vertex_index don't agree.
+    IndexRoundTrip { index: Index, xy: (u32, u32) },
+}
+
+impl HermiteGrid {
+    fn is_boundary_edge(&self, edge: &(Index, Index)) -> bool {
+        let a = self.vertex_index_to_xy(&edge.0);
+        let b = self.vertex_index_to_xy(&edge.1);
+        (a.0 == b.0 && (a.0 == 0 || a.0 == self.width - 1))
+            || (a.1 == b.1 && (a.1 == 0 || a.1 == self.height - 1))
+    }
+
+    /// Check the grid's indexing and hermite data for consistency.
+    pub fn validate(&self) -> Vec<GridDiagnostic> {
+        let mut diagnostics = vec![];
+        for index in 0..self.verts.len() {
+            let xy = self.vertex_index_to_xy(&index);
+            let position = self.vertex_position(&index);
+            if self.vertex_index(xy.0, xy.1) != index
+                || position.x != xy.0 as f32
+                || position.y != xy.1 as f32
+            {
+                diagnostics.push(GridDiagnostic::IndexRoundTrip { index, xy });
+            }
+        }
+
+        for key in self.edges.keys() {
+            if self.verts[key.0].value == self.verts[key.1].value {
+                diagnostics.push(GridDiagnostic::SameValueEdge { edge: *key });
+            }
+        }
+        diagnostics
+    }
+}
+
+impl QuadTree {
+    /// Check the grid, and that every crossed edge joins up with the
+    /// contour in the faces either side of it.
+    /// Only meaningful after `build`.
+    pub fn validate(&self) -> Vec<GridDiagnostic> {
+        let mut diagnostics = self.grid.validate();
+        for (key, e) in self.grid.edges.iter() {
+            if self.grid.is_boundary_edge(key) {
+                diagnostics.push(GridDiagnostic::BoundaryCrossing { edge: *key });
+            } else if e.dual_verts.len() != 2 {
+                diagnostics.push(GridDiagnostic::UnpairedEdge {
+                    edge: *key,
+                    dual_verts: e.dual_verts.len(),
+                });
+            }
+        }
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::*;
+    use nalgebra::Vector2;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const SIZE: u32 = 16;
+
+    fn random_circles(rng: &mut StdRng, margin: f32) -> Vec<Circle> {
+        (0..rng.gen_range(1, 5))
+            .map(|_| {
+                let radius = rng.gen_range(0.5, 4.0);
+                let lo = margin + radius;
+                let hi = SIZE as f32 - margin - radius;
+                Circle::new(
+                    Vector2::new(rng.gen_range(lo, hi), rng.gen_range(lo, hi)),
+                    radius,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_index_round_trip() {
+        let grid = HermiteGrid::new(7, 5);
+        assert_eq!(grid.vertex_index_to_xy(&grid.vertex_index(3, 4)), (3, 4));
+        assert!(grid.validate().is_empty());
+    }
+
+    #[test]
+    fn test_random_interior_scenes_are_valid() {
+        let mut rng = StdRng::seed_from_u64(28);
+        for _ in 0..200 {
+            let mut qt = QuadTree::new(SIZE, SIZE);
+            for c in random_circles(&mut rng, 1.0).iter() {
+                qt.grid.add_contour(c);
+            }
+            qt.build();
+            assert_eq!(qt.validate(), vec![]);
+            assert_eq!(qt.get_contour().len(), qt.grid.edges.len());
+        }
+    }
+
+    #[test]
+    fn test_random_scenes_only_cross_boundary() {
+        let mut rng = StdRng::seed_from_u64(2028);
+        for _ in 0..200 {
+            let mut qt = QuadTree::new(SIZE, SIZE);
+            for c in random_circles(&mut rng, -3.0).iter() {
+                qt.grid.add_contour(c);
+            }
+            qt.build();
+
+            let diagnostics = qt.validate();
+            let crossings = diagnostics
+                .iter()
+                .filter(|d| match d {
+                    GridDiagnostic::BoundaryCrossing { .. } => true,
+                    _ => false,
+                })
+                .count();
+            assert_eq!(crossings, diagnostics.len(), "{:?}", diagnostics);
+            assert_eq!(
+                qt.get_contour().len() + crossings,
+                qt.grid.edges.len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_same_value_edge() {
+        let mut grid = HermiteGrid::new(5, 5);
+        grid.add_contour(&Circle::new(Vector2::new(1.5, 1.5), 1.0));
+        assert!(grid.validate().is_empty());
+
+        // Both of these vertices are outside the circle.
+        let edge = grid.edges.values().next().unwrap().clone();
+        grid.edges.insert((0, 1), edge);
+        assert_eq!(
+            grid.validate(),
+            vec![GridDiagnostic::SameValueEdge { edge: (0, 1) }]
+        );
+    }
+}
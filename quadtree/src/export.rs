@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use sdl2::pixels::Color;
+
+use crate::isoline::*;
+use crate::raster::{DrawTarget, Raster};
+use crate::QuadTree;
+
+pub type Lines = Vec<((f32, f32), (f32, f32))>;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FrameFormat {
+    Svg,
+    Ppm,
+    Png,
+}
+
+impl FrameFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            FrameFormat::Svg => "svg",
+            FrameFormat::Ppm => "ppm",
+            FrameFormat::Png => "png",
+        }
+    }
+}
+
+/// Write contour lines as white strokes on a black SVG.
+/// `scale` is the number of pixels per grid cell.
+pub fn write_svg(path: &Path, size: (u32, u32), scale: f32, lines: &Lines) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">",
+        size.0, size.1
+    )?;
+    writeln!(out, "<rect width=\"100%\" height=\"100%\" fill=\"black\"/>")?;
+    for l in lines.iter() {
+        writeln!(
+            out,
+            "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"white\"/>",
+            (l.0).0 * scale,
+            (l.0).1 * scale,
+            (l.1).0 * scale,
+            (l.1).1 * scale
+        )?;
+    }
+    writeln!(out, "</svg>")?;
+    out.flush()
+}
+
+/// Draw contour lines as white pixels on a black raster.
+/// `scale` is the number of pixels per grid cell.
+pub fn rasterise(size: (u32, u32), scale: f32, lines: &Lines) -> Raster {
+    let mut raster = Raster::new(size.0, size.1);
+    raster.set_draw_color(Color::RGB(255, 255, 255));
+    for l in lines.iter() {
+        raster
+            .draw_line(
+                (((l.0).0 * scale) as i32, ((l.0).1 * scale) as i32),
+                (((l.1).0 * scale) as i32, ((l.1).1 * scale) as i32),
+            )
+            .expect("bad draw");
+    }
+    raster
+}
+
+/// Contour an animation and write each frame to `dir` as `frame_0000.<ext>`.
+/// The QuadTree is re-contoured in place between frames.
+/// Returns the paths of the written frames.
+pub fn export_frames(
+    qt: &mut QuadTree,
+    iso: &dyn AnimatedIsoLine,
+    nframes: u32,
+    fps: f32,
+    dir: &Path,
+    format: FrameFormat,
+    scale: f32,
+) -> io::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let size = (
+        ((qt.grid.width - 1) as f32 * scale).ceil() as u32 + 1,
+        ((qt.grid.height - 1) as f32 * scale).ceil() as u32 + 1,
+    );
+
+    let mut paths = vec![];
+    for frame in 0..nframes {
+        qt.update(&Frame::new(iso, frame as f32 / fps));
+        let lines = qt.get_contour();
+
+        let path = dir.join(format!("frame_{:04}.{}", frame, format.extension()));
+        match format {
+            FrameFormat::Svg => write_svg(&path, size, scale, &lines)?,
+            FrameFormat::Ppm => rasterise(size, scale, &lines).write_ppm(&path)?,
+            FrameFormat::Png => rasterise(size, scale, &lines).write_png(&path)?,
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector2;
+
+    fn pulse(p: Vector2<f32>, t: f32) -> f32 {
+        let radius = 2.0 + t;
+        let delta = p - Vector2::new(4.0, 4.0);
+        radius * radius - delta.dot(&delta)
+    }
+
+    #[test]
+    fn test_export_frames() {
+        let dir = std::env::temp_dir().join("quadtree_test_export_frames");
+        let mut qt = QuadTree::new(8, 8);
+        let paths = export_frames(&mut qt, &pulse, 3, 2.0, &dir, FrameFormat::Ppm, 4.0).unwrap();
+        assert_eq!(paths.len(), 3);
+
+        let data = std::fs::read(&paths[2]).unwrap();
+        assert!(data.starts_with(b"P6\n33 33\n255\n"));
+        assert!(data.iter().skip(13).any(|b| *b == 255));
+
+        let svg = export_frames(&mut qt, &pulse, 1, 1.0, &dir, FrameFormat::Svg, 4.0).unwrap();
+        let text = std::fs::read_to_string(&svg[0]).unwrap();
+        assert!(text.contains("<line"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -19,6 +19,60 @@ pub trait IsoLine {
     }
 }
 
+// An implicit surface that changes over time.
+pub trait AnimatedIsoLine {
+    // A signed distance function (SDF) sample of this surface at `time` (in seconds).
+    // If sample returns a positive value (>0), this point is inside the surface.
+    fn sample(&self, point: Vector2<f32>, time: f32) -> f32;
+    fn normal(&self, point: Vector2<f32>, time: f32) -> Vector2<f32> {
+        let epsilon = 0.01;
+        Vector2::new(
+            self.sample(point + (Vector2::x() * epsilon), time)
+                - self.sample(point - Vector2::x() * epsilon, time),
+            self.sample(point + (Vector2::y() * epsilon), time)
+                - self.sample(point - Vector2::y() * epsilon, time),
+        )
+        .normalize()
+    }
+
+    // A snapshot of this surface at `time`, to use wherever an IsoLine is expected.
+    fn at(&self, time: f32) -> Frame<'_, Self>
+    where
+        Self: Sized,
+    {
+        Frame::new(self, time)
+    }
+}
+
+// Any closure of (point, time) is an animated surface.
+impl<F: Fn(Vector2<f32>, f32) -> f32> AnimatedIsoLine for F {
+    fn sample(&self, point: Vector2<f32>, time: f32) -> f32 {
+        self(point, time)
+    }
+}
+
+// An AnimatedIsoLine frozen at a single point in time.
+pub struct Frame<'a, T: AnimatedIsoLine + ?Sized> {
+    iso: &'a T,
+    time: f32,
+}
+
+impl<'a, T: AnimatedIsoLine + ?Sized> Frame<'a, T> {
+    pub fn new(iso: &'a T, time: f32) -> Frame<'a, T> {
+        Frame { iso, time }
+    }
+}
+
+impl<'a, T: AnimatedIsoLine + ?Sized> IsoLine for Frame<'a, T> {
+    fn sample(&self, point: Vector2<f32>) -> f32 {
+        self.iso.sample(point, self.time)
+    }
+
+    fn normal(&self, point: Vector2<f32>) -> Vector2<f32> {
+        self.iso.normal(point, self.time)
+    }
+}
+
 impl IsoLine for Circle {
     fn sample(&self, point: Vector2<f32>) -> f32 {
         let delta = point - self.center;
@@ -1,15 +1,19 @@
+mod diagnostics;
+mod export;
 mod geom;
 mod isoline;
+mod pointcloud;
+mod raster;
 
 use isoline::*;
 use geom::*;
+use pointcloud::*;
+use raster::{DrawTarget, Raster};
 
 use arrayvec::ArrayVec;
 use nalgebra::Vector2;
 use sdl2::event::Event;
 use sdl2::pixels::Color;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
 use std::boxed::Box;
 use std::collections::HashMap;
 
@@ -67,7 +71,7 @@ impl HermiteGrid {
 
     pub fn vertex_index_to_xy(&self, v: &Index) -> (u32, u32) {
         let x = v % self.width as usize;
-        let y = v - x;
+        let y = v / self.width as usize;
         (x as u32, y as u32)
     }
 
@@ -101,37 +105,110 @@ impl HermiteGrid {
         }
     }
 
+    // Keep the hermite data of an edge in sync with the values of its vertices.
+    // `crossed` is true if `iso` itself changes sign along the edge.
+    fn update_edge(&mut self, v1: Index, v2: Index, iso: &dyn IsoLine, crossed: bool) {
+        if self.verts[v1].value == self.verts[v2].value {
+            // A previous contour crossed here, but has since been covered.
+            self.edges.remove(&(v1, v2));
+        } else if crossed || !self.edges.contains_key(&(v1, v2)) {
+            self.edges.insert((v1, v2), self.make_edge(v1, v2, iso));
+        }
+    }
+
     /// Apply a union operation to the Grid
     pub fn add_contour(&mut self, iso: &dyn IsoLine) {
+        let mut inside = vec![false; self.verts.len()];
         for j in 0..self.height {
             for i in 0..self.width {
                 let index = self.vertex_index(i, j);
                 let position = Vector2::new(i as f32, j as f32);
 
                 // TODO: support multiple values, not just binary.
-                self.verts[index].value |= iso.sample(position) > 0.0;
+                inside[index] = iso.sample(position) > 0.0;
+                self.verts[index].value |= inside[index];
 
                 // Add hermite data to grid.
                 if i > 0 {
                     let left_index = self.vertex_index(i - 1, j);
-                    if self.verts[left_index].value != self.verts[index].value {
-                        self.edges
-                            .insert((left_index, index),
-                                    self.make_edge(left_index, index, iso));
-                    }
+                    let crossed = inside[left_index] != inside[index];
+                    self.update_edge(left_index, index, iso, crossed);
                 }
 
                 if j > 0 {
                     let up_index = self.vertex_index(i, j - 1);
-                    if self.verts[up_index].value != self.verts[index].value {
-                        self.edges
-                            .insert((up_index, index), 
-                                    self.make_edge(up_index, index, iso));
+                    let crossed = inside[up_index] != inside[index];
+                    self.update_edge(up_index, index, iso, crossed);
+                }
+            }
+        }
+    }
+
+    // Check that the (already computed) crossing of an edge still brackets the surface.
+    fn edge_still_crosses(&self, e: &Edge, iso: &dyn IsoLine) -> bool {
+        let v1 = self.vertex_position(&e.verts[0]);
+        let v2 = self.vertex_position(&e.verts[1]);
+        let vector = (v2 - v1).normalize();
+        let offset = (e.position - v1).dot(&vector);
+        // Same precision as the bisection in find_edge_intersection.
+        let epsilon = 1.0 / 32.0;
+        let before = v1 + vector * f32::max(offset - epsilon, 0.0);
+        let after = v1 + vector * f32::min(offset + epsilon, 1.0);
+        (iso.sample(before) > 0.0) == self.verts[e.verts[0]].value
+            && (iso.sample(after) > 0.0) == self.verts[e.verts[1]].value
+    }
+
+    /// Replace the contents of the Grid with a new contour.
+    /// Crossings are kept for edges where they haven't moved, so re-contouring a
+    /// slowly changing surface only bisects the edges that changed. Normals are
+    /// always taken afresh: a turning surface can keep its crossing but not its normal.
+    pub fn set_contour(&mut self, iso: &dyn IsoLine) {
+        for j in 0..self.height {
+            for i in 0..self.width {
+                let index = self.vertex_index(i, j);
+                let position = Vector2::new(i as f32, j as f32);
+                self.verts[index].value = iso.sample(position) > 0.0;
+            }
+        }
+
+        let verts = &self.verts;
+        self.edges
+            .retain(|key, _| verts[key.0].value != verts[key.1].value);
+
+        for j in 0..self.height {
+            for i in 0..self.width {
+                let index = self.vertex_index(i, j);
+                let mut neighbours = ArrayVec::<[Index; 2]>::new();
+                if i > 0 {
+                    neighbours.push(self.vertex_index(i - 1, j));
+                }
+                if j > 0 {
+                    neighbours.push(self.vertex_index(i, j - 1));
+                }
+
+                for n in neighbours {
+                    if self.verts[n].value == self.verts[index].value {
+                        continue;
+                    }
+                    match self.edges.get(&(n, index)) {
+                        Some(e) if self.edge_still_crosses(e, iso) => {
+                            let e = self.edges.get_mut(&(n, index)).unwrap();
+                            e.normal = iso.normal(e.position);
+                        }
+                        _ => {
+                            self.edges.insert((n, index), self.make_edge(n, index, iso));
+                        }
                     }
                 }
             }
         }
     }
+
+    /// Apply a union operation to the Grid, using the closed contour
+    /// reconstructed from oriented point samples.
+    pub fn add_points(&mut self, points: &[OrientedPoint]) {
+        self.add_contour(&PointCloud::new(points.to_vec()));
+    }
 }
 
 pub struct QuadTree {
@@ -214,15 +291,31 @@ impl QuadTree {
         let min = (0, 0);
         let max = (self.grid.width - 1, self.grid.height - 1);
 
-        self.root = Box::new(self.build_face([min, (max.0, min.1), (min.0, max.1), max]));
+        for e in self.grid.edges.values_mut() {
+            e.dual_verts.clear();
+        }
+
+        // Reuse the faces from the last build, if there are any.
+        let mut children = std::mem::replace(&mut *self.root.children, [None, None, None, None]);
+        let dual_vertex = self.build_children(&mut children, [min, (max.0, min.1), (min.0, max.1), max]);
+        self.root.dual_vertex = dual_vertex;
+        *self.root.children = children;
+    }
+
+    /// Re-contour the tree with a new surface, reusing the existing faces and
+    /// any hermite data that is still valid.
+    pub fn update(&mut self, iso: &dyn IsoLine) {
+        self.grid.set_contour(iso);
+        self.build();
     }
 
     pub fn get_contour(&self) -> Vec<((f32, f32), (f32, f32))> {
         let mut v = vec![];
         for e in self.grid.edges.values() {
-            if e.dual_verts.len() < 2 { 
-                println!("bad edge; or edge crossed boundary");
-                continue; 
+            // Edges on the boundary of the grid only have one face.
+            // See QuadTree::validate for diagnosing these.
+            if e.dual_verts.len() < 2 {
+                continue;
             }
             v.push(((e.dual_verts[0].x, e.dual_verts[0].y), 
                     (e.dual_verts[1].x, e.dual_verts[1].y)));
@@ -230,7 +323,43 @@ impl QuadTree {
         v
     }
 
-    fn build_face(&mut self, corners: [(u32, u32); 4]) -> Face {
+    fn build_face(&mut self, corners: [(u32, u32); 4], mut face: Option<Face>) -> Face {
+        let verts = [
+            self.grid.vertex_index(corners[0].0, corners[0].1),
+            self.grid.vertex_index(corners[1].0, corners[1].1),
+            self.grid.vertex_index(corners[2].0, corners[2].1),
+            self.grid.vertex_index(corners[3].0, corners[3].1),
+        ];
+
+        let mut children = face
+            .as_mut()
+            .map_or([None, None, None, None], |f| {
+                std::mem::replace(&mut *f.children, [None, None, None, None])
+            });
+        let dual_vertex = self.build_children(&mut children, corners);
+
+        match face {
+            Some(mut f) => {
+                f.verts = verts;
+                f.dual_vertex = dual_vertex;
+                *f.children = children;
+                f
+            }
+            None => Face {
+                verts,
+                dual_vertex,
+                children: Box::new(children),
+            },
+        }
+    }
+
+    /// Fill in the children of a face, reusing any faces left over from
+    /// a previous build. Returns the dual vertex of the face, if any.
+    fn build_children(
+        &mut self,
+        children: &mut [Option<Face>; 4],
+        corners: [(u32, u32); 4],
+    ) -> Option<Vector2<f32>> {
         assert_eq!(corners[3].0 - corners[0].0, corners[3].1 - corners[0].1);
         assert_eq!(corners[0].1, corners[1].1);
         assert_eq!(corners[2].1, corners[3].1);
@@ -244,7 +373,6 @@ impl QuadTree {
             self.grid.vertex_index(corners[3].0, corners[3].1),
         ];
 
-        let mut children: [Option<Face>; 4] = [None, None, None, None];
         let mut dual_vertex = None;
 
         // if we are not yet at the finest granularity.
@@ -274,7 +402,7 @@ impl QuadTree {
             .iter()
             .enumerate()
             {
-                let child = self.build_face(*corner_set);
+                let child = self.build_face(*corner_set, children[i].take());
                 // Only keep children that are heterogeneous.
                 if self.face_homogeneous_value(&child).is_none() {
                     children[i] = Some(child);
@@ -304,21 +432,17 @@ impl QuadTree {
             }
         }
 
-        Face {
-            verts,
-            dual_vertex,
-            children: Box::new(children),
-        }
+        dual_vertex
     }
 }
 
-fn draw_points(canvas: &mut Canvas<Window>, points: &Vec<(f32, f32)>) {
+fn draw_points(canvas: &mut impl DrawTarget, points: &Vec<(f32, f32)>) {
     for p in points.iter() {
         canvas.draw_point(((p.0 * 20.0) as i32, (p.1 * 20.0) as i32)).expect("bad draw");
     }
 }
 
-fn draw_lines(canvas: &mut Canvas<Window>, lines: &Vec<((f32, f32), (f32, f32))>) {
+fn draw_lines(canvas: &mut impl DrawTarget, lines: &Vec<((f32, f32), (f32, f32))>) {
     for l in lines.iter() {
         canvas.draw_line(
             (((l.0).0 * 20.0) as i32, ((l.0).1 * 20.0) as i32),
@@ -327,7 +451,60 @@ fn draw_lines(canvas: &mut Canvas<Window>, lines: &Vec<((f32, f32), (f32, f32))>
     }
 }
 
+// White contour lines on black, as the window shows them.
+fn draw_frame(canvas: &mut impl DrawTarget, lines: &Vec<((f32, f32), (f32, f32))>) {
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.clear();
+    canvas.set_draw_color(Color::RGB(255, 255, 255));
+    draw_lines(canvas, lines);
+}
+
+// The contour the window shows.
+fn demo_contour() -> Vec<((f32, f32), (f32, f32))> {
+    let mut qt = QuadTree::new(4, 4);
+    let circle = Circle::new(Vector2::new(1.5, 1.5), 1.0);
+    qt.grid.add_contour(&circle);
+    qt.build();
+    qt.get_contour()
+}
+
+// Two blobs drifting into each other and apart again.
+fn lava_blobs(p: Vector2<f32>, t: f32) -> f32 {
+    let offset = 2.0 * t.sin();
+    let a = Circle::new(Vector2::new(6.0 - offset, 8.0), 2.5);
+    let b = Circle::new(Vector2::new(10.0 + offset, 8.0), 2.0);
+    f32::max(a.sample(p), b.sample(p))
+}
+
 fn main() {
+    // `quadtree --export <dir>` renders an animation to SVG frames, without a window.
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == "--export" {
+        let mut qt = QuadTree::new(16, 16);
+        let paths = export::export_frames(
+            &mut qt,
+            &lava_blobs,
+            60,
+            30.0,
+            std::path::Path::new(&args[2]),
+            export::FrameFormat::Svg,
+            20.0,
+        )
+        .expect("failed to export frames");
+        println!("wrote {} frames", paths.len());
+        return;
+    }
+
+    // `quadtree --render <output.ppm|png>` draws what the window would show.
+    if args.len() == 3 && args[1] == "--render" {
+        let mut raster = Raster::new(61, 61);
+        draw_frame(&mut raster, &demo_contour());
+        let path = std::path::Path::new(&args[2]);
+        raster.write(path).expect("failed to write image");
+        println!("wrote {}", path.display());
+        return;
+    }
+
     let sdl = sdl2::init().unwrap();
     let video_subsystem = sdl.video().unwrap();
     let window = video_subsystem
@@ -337,11 +514,7 @@ fn main() {
     let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl.event_pump().unwrap();
 
-    let mut qt = QuadTree::new(4, 4);
-    let circle = Circle::new(Vector2::new(1.5, 1.5), 1.0);
-    qt.grid.add_contour(&circle);
-    qt.build();
-    let lines = qt.get_contour();
+    let lines = demo_contour();
 
     'main: loop {
         for event in event_pump.poll_iter() {
@@ -351,10 +524,7 @@ fn main() {
             }
         }
 
-        canvas.set_draw_color(Color::RGB(0, 0, 0));
-        canvas.clear();
-        canvas.set_draw_color(Color::RGB(255, 255, 255));
-        draw_lines(&mut canvas, &lines);
+        draw_frame(&mut canvas, &lines);
         canvas.present();
     }
 }
@@ -401,4 +571,75 @@ mod tests {
         qt.grid.add_contour(&circle);
         qt.build();
     }
+
+    #[test]
+    fn test_update_matches_fresh_build() {
+        let mut qt = QuadTree::new(16, 16);
+        for t in [0.0, 0.1, 0.2, 1.5].iter() {
+            qt.update(&lava_blobs.at(*t));
+
+            let mut fresh = QuadTree::new(16, 16);
+            fresh.grid.add_contour(&lava_blobs.at(*t));
+            fresh.build();
+
+            assert_eq!(qt.grid.edges.len(), fresh.grid.edges.len());
+            for (key, e) in fresh.grid.edges.iter() {
+                let reused = qt.grid.edges.get(key).expect("missing edge");
+                assert!((e.position - reused.position).norm() <= 1.0 / 16.0);
+            }
+            assert_eq!(qt.get_contour().len(), fresh.get_contour().len());
+        }
+    }
+
+    #[test]
+    fn test_update_reuses_unchanged_edges() {
+        let mut qt = QuadTree::new(4, 4);
+        let circle = Circle::new(Vector2::new(1.5, 1.5), 1.0);
+        qt.update(&circle);
+        let before = qt.grid.edges.clone();
+
+        // Nudge the circle less than the bisection precision.
+        qt.update(&Circle::new(Vector2::new(1.505, 1.5), 1.0));
+        for (key, e) in before.iter() {
+            assert_eq!(e.position, qt.grid.edges[key].position);
+        }
+
+        // A line turning about a crossing keeps it, but the normal there turns too.
+        let line = |p: Vector2<f32>, t: f32| (p - Vector2::new(1.5, 1.0)).dot(&Vector2::new(t.cos(), t.sin()));
+        qt.update(&line.at(0.0));
+        let key = (qt.grid.vertex_index(1, 1), qt.grid.vertex_index(2, 1));
+        let position = qt.grid.edges[&key].position;
+        qt.update(&line.at(0.3));
+        assert_eq!(qt.grid.edges[&key].position, position);
+        for e in qt.grid.edges.values() {
+            assert!((e.normal - line.at(0.3).normal(e.position)).norm() < 1e-3, "stale normal at {}", e.position);
+        }
+    }
+
+    // Compare against an image in golden/. On a mismatch the new image is left in the
+    // temp directory, to look at and copy over the golden one if the change is intended.
+    fn assert_golden(raster: &Raster, golden: &[u8], name: &str) {
+        let matches = Raster::from_ppm(golden).map_or(false, |expected| {
+            (expected.width, expected.height, &expected.pixels)
+                == (raster.width, raster.height, &raster.pixels)
+        });
+        if !matches {
+            let path = std::env::temp_dir().join(name);
+            raster.write_ppm(&path).unwrap();
+            panic!("{} differs from the golden image, see {}", name, path.display());
+        }
+    }
+
+    #[test]
+    fn test_render_golden() {
+        let mut raster = Raster::new(61, 61);
+        draw_frame(&mut raster, &demo_contour());
+        assert_golden(&raster, include_bytes!("../golden/circle.ppm"), "circle.ppm");
+
+        // A frame of the animation, through export.
+        let mut qt = QuadTree::new(16, 16);
+        qt.update(&Frame::new(&lava_blobs, 0.5));
+        let raster = export::rasterise((61, 61), 4.0, &qt.get_contour());
+        assert_golden(&raster, include_bytes!("../golden/lava_blobs.ppm"), "lava_blobs.ppm");
+    }
 }
@@ -0,0 +1,154 @@
+use nalgebra::Vector2;
+
+use crate::isoline::*;
+
+// A sample on a closed contour, with the contour's outward facing normal.
+#[derive(Debug, Copy, Clone)]
+pub struct OrientedPoint {
+    pub position: Vector2<f32>,
+    pub normal: Vector2<f32>,
+}
+
+impl OrientedPoint {
+    pub fn new(position: Vector2<f32>, normal: Vector2<f32>) -> OrientedPoint {
+        OrientedPoint {
+            position,
+            normal: normal.normalize(),
+        }
+    }
+}
+
+// An implicit surface reconstructed from scattered oriented samples.
+//
+// Inside/outside is decided with the generalized winding number (Jacobson et al. 2013).
+// Each sample acts as a small dipole, weighted by the length of contour it stands in for.
+// The sum is ~1 inside a closed contour and ~0 outside, even if the samples are noisy
+// or have small gaps.
+pub struct PointCloud {
+    points: Vec<OrientedPoint>,
+    weights: Vec<f32>, // length of contour each sample represents.
+}
+
+impl PointCloud {
+    pub fn new(points: Vec<OrientedPoint>) -> PointCloud {
+        // Estimate the sample spacing from the two nearest neighbours of each sample.
+        let weights = points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let mut nearest = [std::f32::MAX, std::f32::MAX];
+                for (j, q) in points.iter().enumerate() {
+                    if i == j {
+                        continue;
+                    }
+                    let d = (q.position - p.position).norm();
+                    if d < nearest[0] {
+                        nearest[1] = nearest[0];
+                        nearest[0] = d;
+                    } else if d < nearest[1] {
+                        nearest[1] = d;
+                    }
+                }
+                match points.len() {
+                    0 | 1 => 0.0,
+                    2 => nearest[0],
+                    _ => (nearest[0] + nearest[1]) / 2.0,
+                }
+            })
+            .collect();
+
+        PointCloud { points, weights }
+    }
+
+    /// Generalized winding number of the samples around `point`.
+    pub fn winding_number(&self, point: Vector2<f32>) -> f32 {
+        let mut w = 0.0;
+        for (p, weight) in self.points.iter().zip(self.weights.iter()) {
+            let delta = p.position - point;
+            let distsq = delta.dot(&delta);
+            if distsq < std::f32::EPSILON {
+                continue; // point is on the sample; it doesn't contribute a direction.
+            }
+            w += weight * p.normal.dot(&delta) / distsq;
+        }
+        w / (2.0 * std::f32::consts::PI)
+    }
+}
+
+impl IsoLine for PointCloud {
+    fn sample(&self, point: Vector2<f32>) -> f32 {
+        self.winding_number(point) - 0.5
+    }
+
+    // Blend the normals of nearby samples, so the edge normals follow what was drawn
+    // rather than the (noisy) gradient of the winding number.
+    fn normal(&self, point: Vector2<f32>) -> Vector2<f32> {
+        let mut normal = Vector2::zeros();
+        for p in self.points.iter() {
+            let delta = p.position - point;
+            let distsq = delta.dot(&delta);
+            if distsq < std::f32::EPSILON {
+                return p.normal;
+            }
+            normal += p.normal / (distsq * distsq);
+        }
+        normal.normalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::*;
+
+    fn circle_points(center: Vector2<f32>, radius: f32, n: usize) -> Vec<OrientedPoint> {
+        (0..n)
+            .map(|i| {
+                let theta = i as f32 / n as f32 * 2.0 * std::f32::consts::PI;
+                let normal = Vector2::new(theta.cos(), theta.sin());
+                OrientedPoint::new(center + normal * radius, normal)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_winding_number() {
+        let cloud = PointCloud::new(circle_points(Vector2::new(1.5, 1.5), 1.0, 64));
+        assert!((cloud.winding_number(Vector2::new(1.5, 1.5)) - 1.0).abs() < 0.01);
+        assert!((cloud.winding_number(Vector2::new(1.0, 1.2)) - 1.0).abs() < 0.05);
+        assert!(cloud.winding_number(Vector2::new(3.0, 3.0)).abs() < 0.05);
+        assert!(cloud.winding_number(Vector2::new(40.0, 1.5)).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_points_match_analytic_circle() {
+        let center = Vector2::new(1.5, 1.5);
+        let mut analytic = crate::HermiteGrid::new(5, 5);
+        analytic.add_contour(&Circle::new(center, 1.0));
+
+        let mut grid = crate::HermiteGrid::new(5, 5);
+        grid.add_points(&circle_points(center, 1.0, 64));
+
+        for (a, b) in analytic.verts.iter().zip(grid.verts.iter()) {
+            assert_eq!(a.value, b.value);
+        }
+        assert_eq!(analytic.edges.len(), grid.edges.len());
+        for (key, e) in analytic.edges.iter() {
+            let other = grid.edges.get(key).expect("missing edge");
+            assert!((e.position - other.position).norm() < 0.1);
+            assert!((e.normal - other.normal).norm() < 0.1);
+        }
+    }
+
+    #[test]
+    fn test_quadtree_from_points() {
+        let mut qt = crate::QuadTree::new(8, 8);
+        qt.grid.add_points(&circle_points(Vector2::new(4.0, 4.0), 2.5, 40));
+        qt.build();
+
+        // A closed contour pairs up every crossed edge.
+        let contour = qt.get_contour();
+        assert!(!contour.is_empty());
+        assert_eq!(contour.len(), qt.grid.edges.len());
+    }
+}
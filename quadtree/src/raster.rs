@@ -0,0 +1,227 @@
+// A small software rasteriser, so the debug views can be drawn without a window:
+// on a headless box, or in tests against golden images.
+//
+// Anything drawn through DrawTarget goes to either an SDL canvas or a Raster.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+// The parts of the SDL canvas API the debug views use.
+pub trait DrawTarget {
+    fn set_draw_color(&mut self, colour: Color);
+    fn clear(&mut self);
+    fn draw_point(&mut self, p: (i32, i32)) -> Result<(), String>;
+    fn draw_line(&mut self, a: (i32, i32), b: (i32, i32)) -> Result<(), String>;
+    fn fill_rect(&mut self, rect: Rect) -> Result<(), String>;
+}
+
+impl DrawTarget for Canvas<Window> {
+    fn set_draw_color(&mut self, colour: Color) {
+        Canvas::set_draw_color(self, colour)
+    }
+
+    fn clear(&mut self) {
+        Canvas::clear(self)
+    }
+
+    fn draw_point(&mut self, p: (i32, i32)) -> Result<(), String> {
+        Canvas::draw_point(self, p)
+    }
+
+    fn draw_line(&mut self, a: (i32, i32), b: (i32, i32)) -> Result<(), String> {
+        Canvas::draw_line(self, a, b)
+    }
+
+    fn fill_rect(&mut self, rect: Rect) -> Result<(), String> {
+        Canvas::fill_rect(self, rect)
+    }
+}
+
+// An 8 bit RGB image. Drawing outside of it is clipped.
+#[derive(Debug, Clone)]
+pub struct Raster {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+    colour: Color,
+}
+
+impl Raster {
+    pub fn new(width: u32, height: u32) -> Raster {
+        Raster {
+            width,
+            height,
+            pixels: vec![0; (width * height * 3) as usize],
+            colour: Color::RGB(255, 255, 255),
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let i = ((x + y * self.width) * 3) as usize;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2]]
+    }
+
+    fn plot(&mut self, x: i32, y: i32) {
+        if x >= 0 && y >= 0 && (x as u32) < self.width && (y as u32) < self.height {
+            let i = ((x as u32 + y as u32 * self.width) * 3) as usize;
+            self.pixels[i..i + 3].copy_from_slice(&[self.colour.r, self.colour.g, self.colour.b]);
+        }
+    }
+
+    /// Binary PPM (P6).
+    pub fn write_ppm(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.pixels)?;
+        out.flush()
+    }
+
+    pub fn write_png(&self, path: &Path) -> io::Result<()> {
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(File::create(path)?), self.width, self.height);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+
+    /// Write a .png, or a .ppm for any other extension.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        if path
+            .extension()
+            .map_or(false, |e| e.eq_ignore_ascii_case("png"))
+        {
+            self.write_png(path)
+        } else {
+            self.write_ppm(path)
+        }
+    }
+
+    /// Parse a binary PPM as written by write_ppm.
+    pub fn from_ppm(data: &[u8]) -> Option<Raster> {
+        let mut fields = vec![];
+        let mut pos = 0;
+        while fields.len() < 4 {
+            while pos < data.len() && data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            let start = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            fields.push(std::str::from_utf8(data.get(start..pos)?).ok()?);
+        }
+        pos += 1;
+
+        let (width, height) = (
+            fields[1].parse::<u32>().ok()?,
+            fields[2].parse::<u32>().ok()?,
+        );
+        if fields[0] != "P6" || fields[3] != "255" {
+            return None;
+        }
+        let mut raster = Raster::new(width, height);
+        let size = raster.pixels.len();
+        raster.pixels.copy_from_slice(data.get(pos..pos + size)?);
+        Some(raster)
+    }
+}
+
+impl DrawTarget for Raster {
+    fn set_draw_color(&mut self, colour: Color) {
+        self.colour = colour;
+    }
+
+    fn clear(&mut self) {
+        let rgb = [self.colour.r, self.colour.g, self.colour.b];
+        for px in self.pixels.chunks_mut(3) {
+            px.copy_from_slice(&rgb);
+        }
+    }
+
+    fn draw_point(&mut self, p: (i32, i32)) -> Result<(), String> {
+        self.plot(p.0, p.1);
+        Ok(())
+    }
+
+    // Bresenham's line algorithm, end points included.
+    fn draw_line(&mut self, a: (i32, i32), b: (i32, i32)) -> Result<(), String> {
+        let (mut x0, mut y0) = a;
+        let (x1, y1) = b;
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        loop {
+            self.plot(x0, y0);
+            if x0 == x1 && y0 == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x0 += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y0 += sy;
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_rect(&mut self, rect: Rect) -> Result<(), String> {
+        for y in rect.y()..rect.y() + rect.height() as i32 {
+            for x in rect.x()..rect.x() + rect.width() as i32 {
+                self.plot(x, y);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw() {
+        let mut raster = Raster::new(8, 6);
+        raster.set_draw_color(Color::RGB(0, 0, 255));
+        raster.clear();
+        assert_eq!(raster.pixel(7, 5), [0, 0, 255]);
+
+        raster.set_draw_color(Color::RGB(255, 0, 0));
+        raster.draw_line((0, 0), (7, 3)).unwrap();
+        assert_eq!(raster.pixel(0, 0), [255, 0, 0]);
+        assert_eq!(raster.pixel(7, 3), [255, 0, 0]);
+        assert_eq!(raster.pixel(0, 3), [0, 0, 255]);
+
+        // Clipped at the edges.
+        raster.set_draw_color(Color::RGB(0, 255, 0));
+        raster.fill_rect(Rect::new(6, 4, 5, 5)).unwrap();
+        raster.draw_point((-1, 2)).unwrap();
+        assert_eq!(raster.pixel(6, 4), [0, 255, 0]);
+        assert_eq!(raster.pixel(7, 5), [0, 255, 0]);
+        assert_eq!(raster.pixel(5, 4), [0, 0, 255]);
+
+        let path = std::env::temp_dir().join("quadtree_test_raster.ppm");
+        raster.write(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let read = Raster::from_ppm(&data).unwrap();
+        assert_eq!(
+            (read.width, read.height, read.pixels),
+            (8, 6, raster.pixels)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}